@@ -0,0 +1,149 @@
+//! Who may find and enter a room.
+//!
+//! A [`RoomAccess`] is part of every room's saved [`RoomAcl`](crate::moderation::RoomAcl).
+//! Public rooms show up in `RoomList`; unlisted and private ones only to
+//! their members. Anyone who knows an unlisted room's name may join it,
+//! while a private room admits only its staff and holders of an invite.
+//! Any room can additionally require a join password; an invite stands in
+//! for it.
+
+use std::collections::HashMap;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::error::ChatError;
+use crate::ids::random_b64;
+use crate::moderation::deadline;
+use crate::protocol::Visibility;
+
+/// Prefix of every invite token, so clients can tell one from a password.
+pub const INVITE_PREFIX: &str = "inv_";
+
+/// Room passwords are shared secrets checked on every join, so they are
+/// stored as a salted HMAC rather than with the (slow) account hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Secret {
+    salt: String,
+    mac: String,
+}
+
+impl Secret {
+    fn new(password: &str) -> Self {
+        let salt = random_b64(16);
+        let mac = B64.encode(keyed(&salt, password).finalize().into_bytes());
+        Self { salt, mac }
+    }
+
+    fn matches(&self, password: &str) -> bool {
+        let Ok(expected) = B64.decode(&self.mac) else { return false };
+        // constant-time comparison
+        keyed(&self.salt, password).verify_slice(&expected).is_ok()
+    }
+}
+
+fn keyed(salt: &str, password: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("hmac accepts any key length");
+    mac.update(password.as_bytes());
+    mac
+}
+
+/// Visibility, password and invites of one room.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomAccess {
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<Secret>,
+    /// token → expiry in ms since epoch; `None` lasts until revoked
+    #[serde(default)]
+    invites: HashMap<String, Option<u64>>,
+}
+
+impl RoomAccess {
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// Require `password` to join, or drop the requirement with `None`.
+    pub fn set_password(&mut self, password: Option<&str>) {
+        self.password = password.map(Secret::new);
+    }
+
+    /// A new invite token valid for `ttl` seconds (until revoked when `None`),
+    /// and its expiry.
+    pub fn mint_invite(&mut self, ttl: Option<u64>, now: u64) -> (String, Option<u64>) {
+        self.invites.retain(|_, until| until.is_none_or(|t| t > now));
+        let token = format!("{INVITE_PREFIX}{}", random_b64(18));
+        let expires = ttl.map(|secs| deadline(now, secs));
+        self.invites.insert(token.clone(), expires);
+        (token, expires)
+    }
+
+    pub fn revoke_invite(&mut self, token: &str) -> bool {
+        self.invites.remove(token).is_some()
+    }
+
+    /// Admit a non-staff join to `room` presenting `password` and/or `invite`.
+    pub fn admit(&self, room: &str, password: Option<&str>, invite: Option<&str>, now: u64) -> Result<(), ChatError> {
+        if let Some(token) = invite {
+            return match self.invites.get(token) {
+                Some(until) if until.is_none_or(|t| t > now) => Ok(()),
+                _ => Err(ChatError::Forbidden(format!("invalid or expired invite for {room}"))),
+            };
+        }
+        if self.visibility == Visibility::Private {
+            return Err(ChatError::Forbidden(format!("{room} is private; an invite is required")));
+        }
+        match (&self.password, password) {
+            (None, _) => Ok(()),
+            (Some(secret), Some(password)) if secret.matches(password) => Ok(()),
+            (Some(_), Some(_)) => Err(ChatError::Unauthorized(format!("wrong password for {room}"))),
+            (Some(_), None) => Err(ChatError::Unauthorized(format!("{room} needs a password"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_gate() {
+        let mut access = RoomAccess::default();
+        assert!(access.admit("r", None, None, 0).is_ok());
+        access.set_password(Some("hunter2"));
+        assert!(access.has_password());
+        assert!(matches!(access.admit("r", None, None, 0), Err(ChatError::Unauthorized(_))));
+        assert!(access.admit("r", Some("nope"), None, 0).is_err());
+        assert!(access.admit("r", Some("hunter2"), None, 0).is_ok());
+        // the secret survives a save and reload
+        let saved: RoomAccess = serde_json::from_str(&serde_json::to_string(&access).unwrap()).unwrap();
+        assert!(saved.admit("r", Some("hunter2"), None, 0).is_ok());
+        access.set_password(None);
+        assert!(access.admit("r", None, None, 0).is_ok());
+    }
+
+    #[test]
+    fn private_rooms_need_invites() {
+        let mut access = RoomAccess { visibility: Visibility::Private, ..RoomAccess::default() };
+        access.set_password(Some("pw"));
+        assert!(matches!(access.admit("r", Some("pw"), None, 0), Err(ChatError::Forbidden(_))));
+
+        let (token, expires) = access.mint_invite(Some(60), 1_000);
+        assert!(token.starts_with(INVITE_PREFIX));
+        assert_eq!(expires, Some(61_000));
+        // an invite stands in for the password
+        assert!(access.admit("r", None, Some(&token), 2_000).is_ok());
+        assert!(access.admit("r", None, Some(&token), 61_000).is_err());
+        assert!(access.admit("r", None, Some("inv_bogus"), 0).is_err());
+
+        assert_eq!(access.mint_invite(Some(u64::MAX), 1_000).1, Some(u64::MAX));
+        let (token, _) = access.mint_invite(None, 0);
+        assert!(access.revoke_invite(&token));
+        assert!(access.admit("r", None, Some(&token), 0).is_err());
+    }
+}
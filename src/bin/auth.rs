@@ -0,0 +1,24 @@
+use my_chat::server::auth::{hash_password, TokenAuth};
+
+const USAGE: &str = "usage: auth hash <password> | auth token <user> [ttl_secs]  (token needs AUTH_TOKEN_SECRET)";
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["hash", password] => println!("{}", hash_password(password)?),
+        ["token", user, rest @ ..] => {
+            let secret = std::env::var("AUTH_TOKEN_SECRET")
+                .map_err(|_| anyhow::anyhow!("AUTH_TOKEN_SECRET is not set"))?;
+            let ttl = match rest {
+                [] => 0,
+                [ttl] => ttl.parse()?,
+                _ => anyhow::bail!(USAGE),
+            };
+            println!("{}", TokenAuth::new(secret.as_bytes()).mint(user, ttl));
+        }
+        _ => anyhow::bail!(USAGE),
+    }
+    Ok(())
+}
@@ -2,7 +2,7 @@ use my_chat::client::ui::start_cli_client;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let ws_url_opt = std::env::args().skip(1).next();
+    let ws_url_opt = std::env::args().nth(1);
 
     match &ws_url_opt {
         Some(url) => println!("Connecting to server {url} ..."),
@@ -2,6 +2,7 @@ use std::net::SocketAddr;
 
 use my_chat::config::Config;
 use my_chat::hub::ChatHub;
+use my_chat::metrics;
 use my_chat::server::listener::start_ws_listener;
 
 #[tokio::main]
@@ -9,6 +10,32 @@ async fn main() -> anyhow::Result<()> {
     let cfg = Config::from_env();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&cfg.log_level)).init();
     let hub_tx = ChatHub::spawn();
-    let addr: SocketAddr = cfg.server_addr.parse()?;
-    start_ws_listener(&addr.to_string(), hub_tx).await
+    let _: SocketAddr = cfg.server_addr.parse()?;
+    if let Some(addr) = &cfg.metrics_addr {
+        tokio::spawn(metrics::serve(addr.parse()?));
+    }
+    start_ws_listener(&cfg, hub_tx, shutdown_signal()).await
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
@@ -0,0 +1,62 @@
+//! Opening the client's WebSocket, over plain TCP or TLS.
+//!
+//! Environment knobs:
+//!
+//! * `CHAT_TOKEN` — sent as an `Authorization: Bearer` upgrade header.
+//! * `CHAT_CA_FILE` — PEM bundle trusted for `wss://` instead of the web PKI roots.
+//! * `CHAT_CLIENT_CERT` / `CHAT_CLIENT_KEY` — certificate presented to servers that require mTLS.
+
+use std::env;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+use crate::tls::client_config;
+
+/// Byte stream under the WebSocket, plain or encrypted.
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+pub type ClientStream = WebSocketStream<Box<dyn Io>>;
+
+/// Connect to a `ws://` or `wss://` URL.
+pub async fn connect(url: &str) -> anyhow::Result<ClientStream> {
+    let mut request = url.into_client_request()?;
+    // a token in the environment is sent with the upgrade request
+    if let Ok(token) = env::var("CHAT_TOKEN") {
+        request
+            .headers_mut()
+            .insert("Authorization", HeaderValue::from_str(&format!("Bearer {token}"))?);
+    }
+
+    let uri = request.uri();
+    let host = uri.host().ok_or_else(|| anyhow::anyhow!("no host in {url}"))?.to_string();
+    let secure = match uri.scheme_str() {
+        Some("wss") => true,
+        Some("ws") => false,
+        other => anyhow::bail!("unsupported scheme {other:?}, expected ws or wss"),
+    };
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+    let tcp = TcpStream::connect((host.as_str(), port)).await?;
+    let stream: Box<dyn Io> = if secure {
+        let ca = env::var("CHAT_CA_FILE").ok();
+        let cert = env::var("CHAT_CLIENT_CERT").ok();
+        let key = env::var("CHAT_CLIENT_KEY").ok();
+        let identity = cert.as_deref().zip(key.as_deref());
+        let config = client_config(ca.as_deref(), identity)?;
+        // IPv6 literals come bracketed in URLs
+        let name = ServerName::try_from(host.trim_matches(|c| c == '[' || c == ']').to_string())?;
+        Box::new(TlsConnector::from(config).connect(name, tcp).await?)
+    } else {
+        Box::new(tcp)
+    };
+
+    let (ws, _) = client_async(request, stream).await?;
+    Ok(ws)
+}
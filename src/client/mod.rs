@@ -1 +1,2 @@
-pub mod ui;
\ No newline at end of file
+pub mod connect;
+pub mod ui;
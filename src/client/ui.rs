@@ -1,9 +1,10 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::{self, Stdout};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use chrono::{Local, TimeZone};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode,KeyEvent,KeyEventKind,MouseEvent, MouseEventKind},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode,KeyEvent,KeyEventKind, MouseEventKind},
     execute, terminal,
 };
 use futures_util::{Sink, SinkExt, StreamExt};
@@ -11,9 +12,9 @@ use tokio::{
     select,
     sync::mpsc,
     task,
-    time::{sleep},
+    time::{interval, sleep, timeout},
 };
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::Message;
 use tui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
@@ -23,55 +24,209 @@ use tui::{
     Terminal,
 };
 
-use crate::protocol::{ClientRequest, ServerEvent};
+use crate::access::INVITE_PREFIX;
+use crate::client::connect::connect;
+use crate::protocol::{
+    ClientRequest, Credentials, LeaveReason, PresenceState, Reaction, Replay, Role, ServerEvent, TypingState,
+    Visibility, PROTOCOL_VERSION,
+};
 
 const WS_DEFAULT: &str = "ws://127.0.0.1:9000";
+/// features this client understands, announced in `Hello`
+const CLIENT_CAPABILITIES: &[&str] = &["request_ids", "message_ids", "history_paging", "auth"];
+/// messages fetched per scroll-back request
+const HISTORY_PAGE: usize = 20;
+/// how often `Typing` is repeated while composing; well inside the server's expiry
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+/// how often we ping the server; its pongs show the connection is alive
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Updates from the reader task to the UI loop.
+enum UiUpdate {
+    Line(String),
+    /// an older history page to prepend
+    Older { lines: Vec<String>, next_before: Option<u64> },
+    /// newest live message of a room, the target of `/react` and `/reply`
+    Latest { room: String, latest: Latest },
+    /// a live reply, shown in the stream and in its thread if open
+    Reply { thread: String, line: String },
+    /// a page of one thread
+    Thread { thread: String, lines: Vec<String> },
+    /// `name` started or stopped typing in `room`
+    Typing { room: String, name: String, active: bool },
+    /// `name` has read `room` up to message `seq`
+    Receipt { room: String, name: String, seq: u64 },
+    /// unread counts from the server, per joined room
+    Unread(BTreeMap<String, u64>),
+}
+
+#[derive(Clone)]
+struct Latest {
+    id: String,
+    seq: u64,
+    /// its author
+    name: String,
+    /// the thread it starts or belongs to
+    thread: String,
+}
+
+/// An open thread, shown instead of the room's stream.
+struct ThreadView {
+    room: String,
+    root: String,
+    lines: Vec<String>,
+}
+
+/// What commands act on: the newest message per room and the open thread.
+#[derive(Default)]
+struct Focus {
+    /// room → its newest message
+    latest: HashMap<String, Latest>,
+    thread: Option<ThreadView>,
+    /// room → our name there
+    names: HashMap<String, String>,
+    /// room → messages that arrived while we were elsewhere
+    unread: BTreeMap<String, u64>,
+    /// room → reader → newest `seq` they read, from receipts
+    read_by: HashMap<String, HashMap<String, u64>>,
+    /// send read receipts to the rooms we read
+    receipts: bool,
+}
+
+/// Lazy scroll-back state for the current room.
+#[derive(Default)]
+struct HistoryState {
+    /// cursor for the next older page; `None` once exhausted
+    cursor: Option<u64>,
+    /// first page has arrived
+    loaded: bool,
+    /// a request is in flight
+    fetching: bool,
+}
 
 pub async fn start_cli_client(ws_addr: Option<String>) -> anyhow::Result<()> {
     let ws_addr = ws_addr.unwrap_or_else(|| WS_DEFAULT.to_string());
-    let (ws_stream, _) = connect_async(&ws_addr).await?;
+    let ws_stream = connect(&ws_addr).await?;
     let (mut ws_sink, mut ws_stream) = ws_stream.split();
 
+    // negotiate before anything else so the server sends us v2 events
+    let hello = ClientRequest::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CLIENT_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    };
+    ws_sink.send(Message::Text(serde_json::to_string(&hello)?)).await?;
+
     // UI channel
-    let (ui_tx, mut ui_rx) = mpsc::unbounded_channel::<String>();
+    let (ui_tx, mut ui_rx) = mpsc::unbounded_channel::<UiUpdate>();
 
     // Spawn reader task
     {
         let ui_tx = ui_tx.clone();
         task::spawn(async move {
-            while let Some(Ok(msg)) = ws_stream.next().await {
+            // message ids already shown; replays after a reconnect overlap
+            let mut seen: HashSet<String> = HashSet::new();
+            // (room, name) → presence last shown; joining implies online
+            let mut presence: HashMap<(String, String), (PresenceState, Option<String>)> = HashMap::new();
+            let mut gone = "connection closed".to_string();
+            loop {
+                // our keepalive pings draw a pong well within this
+                let msg = match timeout(KEEPALIVE * 2, ws_stream.next()).await {
+                    Ok(Some(Ok(msg))) => msg,
+                    Ok(_) => break,
+                    Err(_) => {
+                        gone = "server stopped answering".into();
+                        break;
+                    }
+                };
+                if let Message::Close(Some(frame)) = &msg {
+                    gone = format!("server closed the connection: {}", frame.reason);
+                }
                 if !msg.is_text() {
                     continue;
                 }
                 let evt: ServerEvent = match serde_json::from_str(msg.to_text().unwrap()) {
                     Ok(ev) => ev,
                     Err(e) => {
-                        let _ = ui_tx.send(format!("⚠️  bad event: {e}"));
+                        let _ = ui_tx.send(UiUpdate::Line(format!("⚠️  bad event: {e}")));
                         continue;
                     }
                 };
 
-                match evt {
-                    ServerEvent::NewMessage { name, text, ts, .. } => {
-                        if let Some(dt) = Local.timestamp_millis_opt(ts as i64).single() {
-                            let _ =
-                                ui_tx.send(format!("[{}] {}: {}", dt.format("%H:%M:%S"), name, text));
+                let update = match evt {
+                    ServerEvent::PresenceChanged { ref room, ref name, state, ref status, .. } => {
+                        let key = (room.clone(), name.clone());
+                        let shown = presence.get(&key).cloned().unwrap_or((PresenceState::Online, None));
+                        if shown == (state, status.clone()) {
+                            continue; // only the connection count changed
+                        }
+                        if state == PresenceState::Offline {
+                            presence.remove(&key);
+                        } else {
+                            presence.insert(key, (state, status.clone()));
+                        }
+                        match format_event(evt) {
+                            Some(line) => UiUpdate::Line(line),
+                            None => continue,
                         }
                     }
-                    ServerEvent::UserJoined { name, room } => {
-                        let _ = ui_tx.send(format!("🔔 {name} joined {room}"));
+                    ServerEvent::UserTyping { room, name, state } => {
+                        UiUpdate::Typing { room, name, active: state == TypingState::Started }
                     }
-                    ServerEvent::UserLeft { name, room } => {
-                        let _ = ui_tx.send(format!("🔕 {name} left {room}"));
+                    ServerEvent::ReadReceipt { room, name, seq, .. } => UiUpdate::Receipt { room, name, seq },
+                    ServerEvent::RoomList { rooms, unread } => {
+                        let _ = ui_tx.send(UiUpdate::Unread(unread.clone()));
+                        match format_event(ServerEvent::RoomList { rooms, unread }) {
+                            Some(line) => UiUpdate::Line(line),
+                            None => continue,
+                        }
                     }
-                    ServerEvent::RoomList { rooms } => {
-                        let _ = ui_tx.send(format!("📄 rooms: {:?}", rooms));
+                    // a conversation is shown in full where it is asked for
+                    ServerEvent::DirectHistoryPage { messages, .. } => {
+                        for line in messages.into_iter().filter_map(format_event) {
+                            let _ = ui_tx.send(UiUpdate::Line(line));
+                        }
+                        continue;
                     }
-                    ServerEvent::MemberList { room, members } => {
-                        let _ = ui_tx.send(format!("👥 members in {room}: {:?}", members));
+                    // a thread page may repeat lines from the stream; show it whole
+                    ServerEvent::HistoryPage { messages, thread: Some(thread), .. } => UiUpdate::Thread {
+                        thread,
+                        lines: messages.into_iter().filter_map(format_event).collect(),
+                    },
+                    ServerEvent::HistoryPage { room, messages, next_before, .. } => {
+                        // the newest message of a page counts as read once shown
+                        if let Some(latest) = messages.iter().rev().find_map(latest_of) {
+                            let _ = ui_tx.send(UiUpdate::Latest { room, latest });
+                        }
+                        UiUpdate::Older {
+                            lines: messages
+                                .into_iter()
+                                .filter(|ev| first_sight(&mut seen, ev))
+                                .filter_map(format_event)
+                                .collect(),
+                            next_before,
+                        }
                     }
-                }
+                    other if !first_sight(&mut seen, &other) => continue,
+                    other => {
+                        let mut reply_in = None;
+                        if let ServerEvent::NewMessage { room, name, thread_id, .. } = &other
+                            && let Some(latest) = latest_of(&other)
+                        {
+                            // a message ends its author's typing
+                            let _ = ui_tx.send(UiUpdate::Typing { room: room.clone(), name: name.clone(), active: false });
+                            let _ = ui_tx.send(UiUpdate::Latest { room: room.clone(), latest });
+                            reply_in = thread_id.clone();
+                        }
+                        match (format_event(other), reply_in) {
+                            (Some(line), Some(thread)) => UiUpdate::Reply { thread, line },
+                            (Some(line), None) => UiUpdate::Line(line),
+                            (None, _) => continue,
+                        }
+                    }
+                };
+                let _ = ui_tx.send(update);
             }
+            let _ = ui_tx.send(UiUpdate::Line(format!("🔌 {gone}")));
         });
     }
 
@@ -80,7 +235,16 @@ pub async fn start_cli_client(ws_addr: Option<String>) -> anyhow::Result<()> {
     let mut terminal = init_terminal()?;
     let mut input = String::new();
     let mut messages: Vec<String> = Vec::new();
+    // `room` is where typed text goes; `joined` is every room we are in
     let mut room: Option<String> = None;
+    let mut joined: Vec<String> = Vec::new();
+    let mut history = HistoryState::default();
+    let mut focus = Focus::default();
+    // room → who is typing there
+    let mut typing: HashMap<String, BTreeSet<String>> = HashMap::new();
+    // when we last said we are typing
+    let mut typing_sent: Option<Instant> = None;
+    let mut keepalive = interval(KEEPALIVE);
 
     let mut scroll_index: usize = 0; // 用于控制当前显示的起始消息
     let mut total_messages: usize = 0; // 用于存储总的消息条数
@@ -92,33 +256,109 @@ pub async fn start_cli_client(ws_addr: Option<String>) -> anyhow::Result<()> {
             let chunks = Layout::default()
                 .direction(Direction::Vertical)
                 .margin(1)
-                .constraints([Constraint::Percentage(85), Constraint::Percentage(15)].as_ref())
+                .constraints([Constraint::Percentage(85), Constraint::Length(1), Constraint::Percentage(15)].as_ref())
                 .split(f.size());
 
-            let visible_messages = messages.iter()
-                .skip(scroll_index) // 从当前显示位置开始显示
-                .take(20)  // 最多显示 10 条消息
-                .collect::<Vec<_>>(); // 取出当前应该显示的消息
+            let (visible_messages, title) = match &focus.thread {
+                // an open thread follows its newest replies
+                Some(view) => (
+                    view.lines.iter().skip(view.lines.len().saturating_sub(20)).collect::<Vec<_>>(),
+                    format!("Thread in {} (/back to return)", view.room),
+                ),
+                None => (
+                    messages.iter()
+                        .skip(scroll_index) // 从当前显示位置开始显示
+                        .take(20)  // 最多显示 10 条消息
+                        .collect::<Vec<_>>(), // 取出当前应该显示的消息
+                    match unread_badge(&focus.unread) {
+                        Some(badge) => format!("Messages · unread: {badge}"),
+                        None => "Messages".to_string(),
+                    },
+                ),
+            };
 
             let items: Vec<ListItem> =
                 visible_messages.iter().map(|m| ListItem::new(Spans::from(m.as_str()))).collect();
             let list = List::new(items)
-                .block(Block::default().borders(Borders::ALL).title("Messages"));
+                .block(Block::default().borders(Borders::ALL).title(title));
             f.render_widget(list, chunks[0]);
 
+            let status = Paragraph::new(status_line(&typing, room.as_ref(), &focus))
+                .style(Style::default().fg(Color::DarkGray));
+            f.render_widget(status, chunks[1]);
+
             let inp = Paragraph::new(input.as_ref())
                 .style(Style::default().fg(Color::Yellow))
                 .block(Block::default().borders(Borders::ALL).title("Input"));
-            f.render_widget(inp, chunks[1]);
+            f.render_widget(inp, chunks[2]);
         })?;
 
         select! {
-            Some(line) = ui_rx.recv() => {
-                messages.push(line);
-                total_messages += 1;
-            }
+            Some(update) = ui_rx.recv() => match update {
+                UiUpdate::Line(line) => {
+                    messages.push(line);
+                    total_messages += 1;
+                }
+                UiUpdate::Older { lines, next_before } => {
+                    let n = lines.len();
+                    messages.splice(0..0, lines);
+                    total_messages += n;
+                    // keep the current view in place when paging back
+                    if history.loaded {
+                        scroll_index += n;
+                    }
+                    history = HistoryState { cursor: next_before, loaded: true, fetching: false };
+                }
+                UiUpdate::Latest { room: r, latest } => {
+                    // an older history page does not replace the newest message
+                    if focus.latest.get(&r).is_some_and(|l| l.seq >= latest.seq) {
+                        continue;
+                    }
+                    if focus.names.get(&r) != Some(&latest.name) {
+                        if room.as_ref() == Some(&r) {
+                            let req = ClientRequest::MarkRead { room: r.clone(), up_to_id: latest.id.clone(), receipt: focus.receipts };
+                            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+                        } else {
+                            *focus.unread.entry(r.clone()).or_default() += 1;
+                        }
+                    }
+                    focus.latest.insert(r, latest);
+                }
+                UiUpdate::Reply { thread, line } => {
+                    if let Some(view) = focus.thread.as_mut().filter(|v| v.root == thread) {
+                        view.lines.push(line.clone());
+                    }
+                    messages.push(line);
+                    total_messages += 1;
+                }
+                UiUpdate::Thread { thread, lines } => {
+                    if let Some(view) = focus.thread.as_mut().filter(|v| v.root == thread) {
+                        view.lines = lines;
+                    }
+                }
+                UiUpdate::Receipt { room, name, seq } => {
+                    focus.read_by.entry(room).or_default().insert(name, seq);
+                }
+                UiUpdate::Unread(counts) => {
+                    // the current room is being read as it arrives
+                    focus.unread = counts.into_iter().filter(|(r, n)| *n > 0 && room.as_ref() != Some(r)).collect();
+                }
+                UiUpdate::Typing { room, name, active } => {
+                    let names = typing.entry(room).or_default();
+                    if active {
+                        names.insert(name);
+                    } else {
+                        names.remove(&name);
+                    }
+                }
+            },
             
 
+            _ = keepalive.tick() => {
+                // a dead connection shows up in the reader
+                let _ = ws_sink.send(Message::Ping(Vec::new())).await;
+            }
+
             _ = sleep(Duration::from_millis(10)) => {
                 while event::poll(Duration::from_millis(0))? {
                     if let Ok(evt) = event::read(){
@@ -126,63 +366,86 @@ pub async fn start_cli_client(ws_addr: Option<String>) -> anyhow::Result<()> {
                             Event::Mouse(mouse_event) => {
                                 match mouse_event.kind {
                                     MouseEventKind::ScrollUp => {
-                                        if scroll_index > 0 {
-                                            scroll_index -= 1; // 向上滚动，减少显示的起始位置
+                                        if scroll_index == 0 {
+                                            fetch_older(&mut ws_sink, &room, &mut history).await?;
                                         }
+                                        scroll_index = scroll_index.saturating_sub(1); // 向上滚动，减少显示的起始位置
                                     }
-                                    MouseEventKind::ScrollDown => {
-                                        if scroll_index + 20 < total_messages {
-                                            scroll_index += 1; // 向下滚动，增加显示的起始位置
-                                        }
+                                    MouseEventKind::ScrollDown if scroll_index + 20 < total_messages => {
+                                        scroll_index += 1; // 向下滚动，增加显示的起始位置
                                     }
                                     _ => {}
                                 }
                             }
-                            Event::Key(KeyEvent { code, kind, .. }) => {
-                                if kind == KeyEventKind::Press {
-                                    match code {
-                                        KeyCode::Char(c) => input.push(c),
-                                        KeyCode::Backspace => { input.pop(); }
-                                        KeyCode::Enter => {
-                                            let cmd = input.trim().to_string();
-                                            input.clear();
-                                            if cmd.starts_with('/') {
-                                                handle_command(&cmd, &mut ws_sink, &mut room, &mut messages).await?;
-                                                if cmd == "/leave" {
-                                                    messages.clear(); // free history memory
-                                                    disable_tui()?;
-                                                    return Ok(());
-                                                }
-                                            } else if let Some(r) = &room {
-                                                let req = ClientRequest::Message { room: r.clone(), text: cmd };
-                                                ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
-                                            } else {
-                                                messages.push("❗ join a room first".into());
-                                            }
+                            Event::Key(KeyEvent { code, kind: KeyEventKind::Press, .. }) => {
+                                match code {
+                                    KeyCode::Char(c) => {
+                                        input.push(c);
+                                        let composing = !input.starts_with('/');
+                                        signal_typing(&mut ws_sink, target_room(&room, &focus), &mut typing_sent, composing).await?;
+                                    }
+                                    KeyCode::Backspace => {
+                                        input.pop();
+                                        if input.is_empty() {
+                                            signal_typing(&mut ws_sink, target_room(&room, &focus), &mut typing_sent, false).await?;
+                                        }
+                                    }
+                                    KeyCode::Enter => {
+                                        let cmd = input.trim().to_string();
+                                        input.clear();
+                                        if cmd.starts_with('/') || cmd.is_empty() {
+                                            signal_typing(&mut ws_sink, target_room(&room, &focus), &mut typing_sent, false).await?;
+                                        } else {
+                                            // the server ends it when the message arrives
+                                            typing_sent = None;
                                         }
-                                        KeyCode::Esc => {
-                                            if let Some(r) = &room {
-                                                let leave = ClientRequest::Leave { room: r.clone() };
-                                                ws_sink
-                                                    .send(Message::Text(serde_json::to_string(&leave)?))
-                                                    .await?;
+                                        if cmd.starts_with('/') {
+                                            handle_command(&cmd, &mut ws_sink, &mut room, &mut joined, &mut messages, &mut history, &mut focus).await?;
+                                            if cmd == "/leave" && joined.is_empty() {
+                                                messages.clear(); // free history memory
+                                                disable_tui()?;
+                                                return Ok(());
                                             }
-                                            disable_tui()?;
-                                            return Ok(());
+                                        } else if let Some(view) = &focus.thread {
+                                            // in a thread, plain text answers it
+                                            let req = ClientRequest::Message {
+                                                room: view.room.clone(),
+                                                text: cmd,
+                                                reply_to: Some(view.root.clone()),
+                                            };
+                                            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+                                        } else if let Some(r) = &room {
+                                            let req = ClientRequest::Message { room: r.clone(), text: cmd, reply_to: None };
+                                            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+                                        } else {
+                                            messages.push("❗ join a room first".into());
                                         }
-                                        KeyCode::Up=> {
-                                            scroll_index = 0;
+                                    }
+                                    KeyCode::Esc => {
+                                        for r in joined.drain(..) {
+                                            let leave = ClientRequest::Leave { room: r };
+                                            ws_sink
+                                                .send(Message::Text(serde_json::to_string(&leave)?))
+                                                .await?;
                                         }
-                                        KeyCode::Down => {
-                                            // Move scroll_index to show the latest 10 messages
-                                            if total_messages >= 20 {
-                                                scroll_index = total_messages - 20;
-                                            } else {
-                                                scroll_index = 0; // Show all if there are fewer than 10 messages
-                                            }
+                                        disable_tui()?;
+                                        return Ok(());
+                                    }
+                                    KeyCode::Up=> {
+                                        if scroll_index == 0 {
+                                            fetch_older(&mut ws_sink, &room, &mut history).await?;
+                                        }
+                                        scroll_index = 0;
+                                    }
+                                    KeyCode::Down => {
+                                        // Move scroll_index to show the latest 10 messages
+                                        if total_messages >= 20 {
+                                            scroll_index = total_messages - 20;
+                                        } else {
+                                            scroll_index = 0; // Show all if there are fewer than 10 messages
                                         }
-                                        _ => {}
                                     }
+                                    _ => {}
                                 }
                             }
                             _ => {}
@@ -199,7 +462,10 @@ async fn handle_command<S>(
     cmd: &str,
     ws_sink: &mut S,
     room: &mut Option<String>,
+    joined: &mut Vec<String>,
     messages: &mut Vec<String>,
+    history: &mut HistoryState,
+    focus: &mut Focus,
 ) -> anyhow::Result<()>
 where
     S: Sink<Message> + Unpin + Send,
@@ -207,23 +473,211 @@ where
 {
     let parts: Vec<&str> = cmd.split_whitespace().collect();
     match parts.as_slice() {
-        ["/join", room_name, name] => {
+        ["/join", room_name, _, ..] if joined.iter().any(|r| r == room_name) => {
+            // already in there; just make it the active room
+            *room = Some(room_name.to_string());
+            *history = HistoryState::default();
+            messages.push(format!("💬 now talking in {room_name}"));
+            mark_read(ws_sink, room_name, focus).await?;
+        }
+        ["/join", room_name, name, key @ ..] if key.len() <= 1 => {
+            // a third word is an invite token if it looks like one, else the password
+            let key = key.first().map(|k| k.to_string());
+            let (invite, password) = match key {
+                Some(k) if k.starts_with(INVITE_PREFIX) => (Some(k), None),
+                other => (None, other),
+            };
+            // skip the bulk replay and page history in lazily instead
             let req = ClientRequest::Join {
                 room: room_name.to_string(),
                 name: name.to_string(),
+                replay: Some(Replay::None),
+                password,
+                invite,
+            };
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+            let req = ClientRequest::History {
+                room: room_name.to_string(),
+                before: None,
+                limit: HISTORY_PAGE,
+                thread: None,
             };
             ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
             *room = Some(room_name.to_string());
+            joined.push(room_name.to_string());
+            focus.names.insert(room_name.to_string(), name.to_string());
+            *history = HistoryState { fetching: true, ..HistoryState::default() };
         }
         ["/leave"] => {
             if let Some(r) = room.take() {
+                joined.retain(|j| *j != r);
+                focus.thread.take_if(|view| view.room == r);
+                focus.names.remove(&r);
+                focus.unread.remove(&r);
+                focus.read_by.remove(&r);
                 ws_sink
                     .send(Message::Text(
                         serde_json::to_string(&ClientRequest::Leave { room: r })?,
                     ))
                     .await?;
+                // fall back to another room we are still in
+                *room = joined.last().cloned();
+                *history = HistoryState::default();
+                if let Some(r) = room {
+                    messages.push(format!("💬 now talking in {r}"));
+                    mark_read(ws_sink, r, focus).await?;
+                }
+            }
+        }
+        ["/nick", name] => {
+            if let Some(r) = room {
+                let req = ClientRequest::Rename { room: r.clone(), name: name.to_string() };
+                ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+                focus.names.insert(r.clone(), name.to_string());
+            } else {
+                messages.push("❗ not in any room".into());
             }
         }
+        ["/login", user, password] => {
+            let req = ClientRequest::Auth(Credentials::Password {
+                user: user.to_string(),
+                password: password.to_string(),
+            });
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+        }
+        ["/token", token] => {
+            let req = ClientRequest::Auth(Credentials::Bearer { token: token.to_string() });
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+        }
+        ["/kick", name, reason @ ..] => {
+            let reason = (!reason.is_empty()).then(|| reason.join(" "));
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::Kick { room, name: name.to_string(), reason })
+                .await?;
+        }
+        ["/ban", name, rest @ ..] => {
+            // an optional leading number is the duration in seconds
+            let (duration, reason) = match rest.split_first() {
+                Some((secs, reason)) if secs.parse::<u64>().is_ok() => (secs.parse().ok(), reason),
+                _ => (None, rest),
+            };
+            let reason = (!reason.is_empty()).then(|| reason.join(" "));
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::Ban {
+                room,
+                name: name.to_string(),
+                duration,
+                reason,
+            })
+            .await?;
+        }
+        ["/unban", name] => {
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::Unban { room, name: name.to_string() }).await?;
+        }
+        ["/mute", name, rest @ ..] if rest.len() <= 1 => {
+            let duration = rest.first().and_then(|s| s.parse().ok());
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::Mute { room, name: name.to_string(), duration })
+                .await?;
+        }
+        ["/unmute", name] => {
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::Unmute { room, name: name.to_string() }).await?;
+        }
+        ["/role", name, role] => {
+            let role = match *role {
+                "owner" => Role::Owner,
+                "moderator" | "mod" => Role::Moderator,
+                "member" => Role::Member,
+                _ => {
+                    messages.push("❗ role is one of owner | moderator | member".into());
+                    return Ok(());
+                }
+            };
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::SetRole { room, name: name.to_string(), role })
+                .await?;
+        }
+        ["/visibility", visibility] => {
+            let visibility = match *visibility {
+                "public" => Visibility::Public,
+                "unlisted" => Visibility::Unlisted,
+                "private" => Visibility::Private,
+                _ => {
+                    messages.push("❗ visibility is one of public | unlisted | private".into());
+                    return Ok(());
+                }
+            };
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::SetVisibility { room, visibility }).await?;
+        }
+        ["/password", rest @ ..] if rest.len() <= 1 => {
+            // no argument clears the password
+            let password = rest.first().map(|p| p.to_string());
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::SetPassword { room, password }).await?;
+        }
+        ["/invite", rest @ ..] if rest.len() <= 1 => {
+            let ttl = rest.first().and_then(|s| s.parse().ok());
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::CreateInvite { room, ttl }).await?;
+        }
+        ["/revoke", token] => {
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::RevokeInvite { room, token: token.to_string() })
+                .await?;
+        }
+        [verb @ ("/react" | "/unreact"), emoji] => {
+            // reactions go on the newest message of the current room
+            let Some(Latest { id: message_id, .. }) = room.as_ref().and_then(|r| focus.latest.get(r)).cloned() else {
+                messages.push("❗ no message to react to".into());
+                return Ok(());
+            };
+            let emoji = emoji.to_string();
+            send_in_room(ws_sink, room, messages, |room| match *verb {
+                "/react" => ClientRequest::React { room, message_id, emoji },
+                _ => ClientRequest::Unreact { room, message_id, emoji },
+            })
+            .await?;
+        }
+        ["/reply", text @ ..] if !text.is_empty() => {
+            let Some(Latest { id, .. }) = room.as_ref().and_then(|r| focus.latest.get(r)).cloned() else {
+                messages.push("❗ no message to reply to".into());
+                return Ok(());
+            };
+            let text = text.join(" ");
+            send_in_room(ws_sink, room, messages, |room| ClientRequest::Message { room, text, reply_to: Some(id) })
+                .await?;
+        }
+        ["/thread"] => {
+            // open the thread of the newest message in the current room
+            let (Some(r), Some(latest)) = (room.as_ref(), room.as_ref().and_then(|r| focus.latest.get(r))) else {
+                messages.push("❗ no thread to open".into());
+                return Ok(());
+            };
+            let req = ClientRequest::History {
+                room: r.clone(),
+                before: None,
+                limit: HISTORY_PAGE,
+                thread: Some(latest.thread.clone()),
+            };
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+            focus.thread = Some(ThreadView { room: r.clone(), root: latest.thread.clone(), lines: Vec::new() });
+        }
+        ["/back"] => focus.thread = None,
+        ["/msg", to, text @ ..] if !text.is_empty() => {
+            let req = ClientRequest::DirectMessage { to: to.to_string(), text: text.join(" ") };
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+        }
+        [verb @ ("/away" | "/status"), status @ ..] => {
+            // `/status` with no text clears it and marks us back
+            let status = (!status.is_empty()).then(|| status.join(" "));
+            let req = ClientRequest::SetStatus { away: *verb == "/away", status };
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+        }
+        ["/receipts", setting @ ("on" | "off")] => {
+            focus.receipts = *setting == "on";
+            messages.push(format!("👀 read receipts {setting}"));
+        }
+        ["/whois", name] => {
+            let req = ClientRequest::WhoIs { name: name.to_string() };
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+        }
+        ["/dms", with] => {
+            let req = ClientRequest::DirectHistory { with: with.to_string(), before: None, limit: HISTORY_PAGE };
+            ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+        }
         ["/rooms"] => {
             ws_sink
                 .send(Message::Text(serde_json::to_string(&ClientRequest::RoomList)?))
@@ -242,13 +696,291 @@ where
         }
         _ => {
             messages.push(
-                "❗ usage: /join <room> <name> | /leave | /rooms | /members".into(),
+                "❗ usage: /join <room> <name> [password|invite] | /leave | /rooms | /members | /msg <name> <text> | /dms <name> | /react <emoji> | /unreact <emoji> | /reply <text> | /thread | /back | /away [status] | /status [status] | /whois <name> | /receipts <on|off> | /nick <name> | /login <user> <password> | /token <token> | /kick <name> [reason] | /ban <name> [secs] [reason] | /unban <name> | /mute <name> [secs] | /unmute <name> | /role <name> <role> | /visibility <public|unlisted|private> | /password [secret] | /invite [secs] | /revoke <token>".into(),
             );
         }
     }
     Ok(())
 }
 
+/// Where typed text goes: the open thread's room, else the current room.
+fn target_room<'a>(room: &'a Option<String>, focus: &'a Focus) -> Option<&'a String> {
+    focus.thread.as_ref().map(|view| &view.room).or(room.as_ref())
+}
+
+/// Tell `room` whether we are composing. `Started` is repeated every
+/// `TYPING_REFRESH` while typing, `Stopped` sent once when we give up.
+async fn signal_typing<S>(
+    ws_sink: &mut S,
+    room: Option<&String>,
+    sent: &mut Option<Instant>,
+    active: bool,
+) -> anyhow::Result<()>
+where
+    S: Sink<Message> + Unpin + Send,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let Some(room) = room else { return Ok(()) };
+    let state = match (active, *sent) {
+        (true, Some(at)) if at.elapsed() < TYPING_REFRESH => return Ok(()),
+        (true, _) => {
+            *sent = Some(Instant::now());
+            TypingState::Started
+        }
+        (false, None) => return Ok(()),
+        (false, Some(_)) => {
+            *sent = None;
+            TypingState::Stopped
+        }
+    };
+    let req = ClientRequest::Typing { room: room.clone(), state };
+    ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+    Ok(())
+}
+
+/// "bob is typing…" for the current room, leaving ourselves out.
+fn status_line(typing: &HashMap<String, BTreeSet<String>>, room: Option<&String>, focus: &Focus) -> String {
+    let Some(room) = focus.thread.as_ref().map(|view| &view.room).or(room) else { return String::new() };
+    let me = focus.names.get(room);
+    let names: Vec<&str> =
+        typing.get(room).into_iter().flatten().filter(|n| Some(*n) != me).map(String::as_str).collect();
+    match names.as_slice() {
+        [] => {}
+        [one] => return format!("✍️  {one} is typing…"),
+        many => return format!("✍️  {} are typing…", many.join(", ")),
+    }
+    // nobody typing: who has read the newest message
+    let Some(latest) = focus.latest.get(room) else { return String::new() };
+    let mut readers: Vec<&str> = focus
+        .read_by
+        .get(room)
+        .into_iter()
+        .flatten()
+        .filter(|(name, seq)| **seq >= latest.seq && Some(*name) != me && **name != latest.name)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    readers.sort();
+    if readers.is_empty() { String::new() } else { format!("👀 seen by {}", readers.join(", ")) }
+}
+
+/// "go 3 · rust 1", or `None` when everything is read.
+fn unread_badge(unread: &BTreeMap<String, u64>) -> Option<String> {
+    let parts: Vec<String> = unread.iter().filter(|(_, n)| **n > 0).map(|(room, n)| format!("{room} {n}")).collect();
+    (!parts.is_empty()).then(|| parts.join(" · "))
+}
+
+/// Clear `room`'s badge and tell the server its newest message was read.
+async fn mark_read<S>(ws_sink: &mut S, room: &str, focus: &mut Focus) -> anyhow::Result<()>
+where
+    S: Sink<Message> + Unpin + Send,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    if focus.unread.remove(room).is_none() {
+        return Ok(());
+    }
+    if let Some(latest) = focus.latest.get(room) {
+        let req = ClientRequest::MarkRead { room: room.to_string(), up_to_id: latest.id.clone(), receipt: focus.receipts };
+        ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+    }
+    Ok(())
+}
+
+/// The `/react` and `/reply` target a live message makes, if it can be one.
+fn latest_of(evt: &ServerEvent) -> Option<Latest> {
+    match evt {
+        ServerEvent::NewMessage { id, seq, name, deleted: false, thread_id, .. } if !id.is_empty() => Some(Latest {
+            id: id.clone(),
+            seq: *seq,
+            name: name.clone(),
+            thread: thread_id.clone().unwrap_or_else(|| id.clone()),
+        }),
+        _ => None,
+    }
+}
+
+/// Send the request built for the current room, or complain if there is none.
+async fn send_in_room<S>(
+    ws_sink: &mut S,
+    room: &Option<String>,
+    messages: &mut Vec<String>,
+    build: impl FnOnce(String) -> ClientRequest,
+) -> anyhow::Result<()>
+where
+    S: Sink<Message> + Unpin + Send,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    match room {
+        Some(r) => ws_sink.send(Message::Text(serde_json::to_string(&build(r.clone()))?)).await?,
+        None => messages.push("❗ not in any room".into()),
+    }
+    Ok(())
+}
+
+/// Request the next older history page if there is one and none is pending.
+async fn fetch_older<S>(
+    ws_sink: &mut S,
+    room: &Option<String>,
+    history: &mut HistoryState,
+) -> anyhow::Result<()>
+where
+    S: Sink<Message> + Unpin + Send,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let (Some(r), Some(before)) = (room, history.cursor) else {
+        return Ok(());
+    };
+    if history.fetching {
+        return Ok(());
+    }
+    let req = ClientRequest::History { room: r.clone(), before: Some(before), limit: HISTORY_PAGE, thread: None };
+    ws_sink.send(Message::Text(serde_json::to_string(&req)?)).await?;
+    history.fetching = true;
+    Ok(())
+}
+
+/// False if this message id was already displayed.
+fn first_sight(seen: &mut HashSet<String>, evt: &ServerEvent) -> bool {
+    match evt {
+        ServerEvent::NewMessage { id, .. } if !id.is_empty() => seen.insert(id.clone()),
+        ServerEvent::DirectMessage { id, .. } => seen.insert(id.clone()),
+        _ => true,
+    }
+}
+
+fn presence_icon(state: PresenceState) -> &'static str {
+    match state {
+        PresenceState::Online => "🟢",
+        PresenceState::Away => "🌙",
+        PresenceState::Idle => "💤",
+        PresenceState::Offline => "⚫",
+    }
+}
+
+fn presence_word(state: PresenceState) -> &'static str {
+    match state {
+        PresenceState::Online => "online",
+        PresenceState::Away => "away",
+        PresenceState::Idle => "idle",
+        PresenceState::Offline => "offline",
+    }
+}
+
+/// "👍 2 · 🎉 1"
+fn tally(reactions: &[Reaction]) -> String {
+    reactions.iter().map(|r| format!("{} {}", r.emoji, r.count)).collect::<Vec<_>>().join(" · ")
+}
+
+/// Render a server event as one line in the message pane.
+fn format_event(evt: ServerEvent) -> Option<String> {
+    match evt {
+        ServerEvent::NewMessage { room, name, deleted: true, ts, .. } => {
+            let dt = Local.timestamp_millis_opt(ts as i64).single()?;
+            Some(format!("[{}] #{} {}: 🗑️  (deleted)", dt.format("%H:%M:%S"), room, name))
+        }
+        ServerEvent::NewMessage { room, name, text, ts, edited, reactions, thread_id, replies, .. } => {
+            let dt = Local.timestamp_millis_opt(ts as i64).single()?;
+            let arrow = if thread_id.is_some() { "↳ " } else { "" };
+            let mark = if edited.is_some() { " (edited)" } else { "" };
+            let tally = if reactions.is_empty() { String::new() } else { format!("  [{}]", tally(&reactions)) };
+            let thread = if replies > 0 { format!("  💬 {replies} replies") } else { String::new() };
+            Some(format!("[{}] #{} {}{}: {}{}{}{}", dt.format("%H:%M:%S"), room, arrow, name, text, mark, tally, thread))
+        }
+        ServerEvent::ReactionUpdated { room, reactions, .. } if reactions.is_empty() => {
+            Some(format!("💬 reactions cleared on a message in {room}"))
+        }
+        ServerEvent::ReactionUpdated { room, reactions, .. } => {
+            Some(format!("💬 {} on a message in {room}", tally(&reactions)))
+        }
+        ServerEvent::MessageEdited { room, by, text, .. } => Some(format!("✏️  {by} edited a message in {room}: {text}")),
+        ServerEvent::MessageDeleted { room, by, .. } => Some(format!("🗑️  {by} deleted a message in {room}")),
+        ServerEvent::DirectMessage { from, to, text, ts, .. } => {
+            let dt = Local.timestamp_millis_opt(ts as i64).single()?;
+            Some(format!("[{}] ✉️  {from} → {to}: {text}", dt.format("%H:%M:%S")))
+        }
+        ServerEvent::UserJoined { name, room } => Some(format!("🔔 {name} joined {room}")),
+        ServerEvent::UserLeft { name, room, reason } => Some(match reason {
+            Some(LeaveReason::Disconnected) => format!("🔕 {name} left {room} (disconnected)"),
+            Some(LeaveReason::TimedOut) => format!("🔕 {name} left {room} (timed out)"),
+            Some(LeaveReason::Left) | None => format!("🔕 {name} left {room}"),
+        }),
+        ServerEvent::UserRenamed { room, old, new } => {
+            Some(format!("✏️  {old} is now {new} in {room}"))
+        }
+        ServerEvent::RoomList { rooms, unread } => Some(match unread_badge(&unread) {
+            Some(badge) => format!("📄 rooms: {:?} · unread: {badge}", rooms),
+            None => format!("📄 rooms: {:?}", rooms),
+        }),
+        ServerEvent::MemberList { room, members } => {
+            Some(format!("👥 members in {room}: {:?}", members))
+        }
+        ServerEvent::Error { message, .. } => Some(format!("❗ {message}")),
+        ServerEvent::Welcome { version, .. } => Some(format!("✅ connected (protocol v{version})")),
+        ServerEvent::Authenticated { name } => Some(format!("🔑 signed in as {name}")),
+        ServerEvent::ServerShutdown { reason, reconnect_after } => Some(match reconnect_after {
+            Some(secs) => format!("🛑 {reason}; reconnect in {secs}s"),
+            None => format!("🛑 {reason}"),
+        }),
+        ServerEvent::Gap { room, missed } => Some(format!("⚠️  missed {missed} messages in {room}")),
+        ServerEvent::UserKicked { room, name, by, reason } => {
+            Some(format!("👢 {name} was kicked from {room} by {by}{}", because(reason)))
+        }
+        ServerEvent::UserBanned { room, name, by, until, reason } => Some(format!(
+            "⛔ {name} was banned from {room} by {by}{}{}",
+            until_time(until),
+            because(reason)
+        )),
+        ServerEvent::UserUnbanned { room, name, by } => Some(format!("✅ {by} lifted the ban on {name} in {room}")),
+        ServerEvent::UserMuted { room, name, by, until } => {
+            Some(format!("🔇 {name} was muted in {room} by {by}{}", until_time(until)))
+        }
+        ServerEvent::UserUnmuted { room, name, by } => Some(format!("🔊 {by} unmuted {name} in {room}")),
+        ServerEvent::RoleChanged { room, name, role, .. } => Some(format!("⭐ {name} is now {role:?} of {room}")),
+        ServerEvent::RoomUpdated { room, visibility, password, by } => Some(format!(
+            "⚙️  {by} made {room} {visibility:?}{}",
+            if password { ", password required" } else { "" }
+        )),
+        ServerEvent::InviteCreated { room, token, expires } => {
+            Some(format!("🎟️  invite to {room}: {token}{}", until_time(expires)))
+        }
+        ServerEvent::PresenceChanged { room, name, state, status, .. } => {
+            let status = status.map_or(String::new(), |s| format!(": {s}"));
+            Some(format!("{} {name} is {} in {room}{status}", presence_icon(state), presence_word(state)))
+        }
+        ServerEvent::UserInfo { name, state, status, connections, last_active, rooms } => {
+            let status = status.map_or(String::new(), |s| format!(" \"{s}\""));
+            let seen = last_active
+                .and_then(|ts| Local.timestamp_millis_opt(ts as i64).single())
+                .map_or(String::new(), |dt| format!(", last active {}", dt.format("%H:%M:%S")));
+            Some(format!(
+                "{} {name} is {}{status} ({connections} connections{seen}) rooms: {:?}",
+                presence_icon(state),
+                presence_word(state),
+                rooms
+            ))
+        }
+        ServerEvent::Resumed { rooms, .. } => Some(format!("🔁 session resumed in {rooms:?}")),
+        // shown in the status line instead
+        ServerEvent::UserTyping { .. } | ServerEvent::ReadReceipt { .. } => None,
+        ServerEvent::HistoryPage { .. }
+        | ServerEvent::DirectHistoryPage { .. }
+        | ServerEvent::SessionResumable { .. }
+        | ServerEvent::Ack { .. } => None,
+    }
+}
+
+/// `" (reason)"`, or nothing.
+fn because(reason: Option<String>) -> String {
+    reason.map(|r| format!(" ({r})")).unwrap_or_default()
+}
+
+/// `" until HH:MM:SS"` for a ms timestamp, or nothing for an open-ended one.
+fn until_time(until: Option<u64>) -> String {
+    until
+        .and_then(|ms| Local.timestamp_millis_opt(ms as i64).single())
+        .map(|dt| format!(" until {}", dt.format("%H:%M:%S")))
+        .unwrap_or_default()
+}
+
 /// Terminal helpers
 fn enable_tui() -> io::Result<()> {
     terminal::enable_raw_mode()?;
@@ -1,5 +1,56 @@
+use std::collections::HashMap;
 use std::env;
 
+/// What happens to a client that falls behind a room's broadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// send `Gap` and keep going; the client re-fetches from history
+    Notify,
+    /// send `Gap`, and close the connection once `lag_threshold` is reached
+    Disconnect,
+}
+
+impl LagPolicy {
+    fn parse(v: &str) -> Option<Self> {
+        match v.to_ascii_lowercase().as_str() {
+            "notify" => Some(LagPolicy::Notify),
+            "disconnect" => Some(LagPolicy::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// Token bucket settings: `per_sec` messages refilled per second, up to `burst`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// `"<per_sec>/<burst>"`, e.g. `"5/10"`; `"0"` means unlimited (`None`).
+    fn parse(v: &str) -> Option<Option<Self>> {
+        let v = v.trim();
+        if v == "0" {
+            return Some(None);
+        }
+        let (rate, burst) = v.split_once('/')?;
+        let per_sec = rate.trim().parse::<f64>().ok().filter(|r| *r > 0.0)?;
+        let burst = burst.trim().parse::<u32>().ok().filter(|b| *b > 0)?;
+        Some(Some(Self { per_sec, burst }))
+    }
+}
+
+/// `"room=<limit>,room=<limit>"`; entries that fail to parse are skipped.
+fn parse_room_limits(v: &str) -> HashMap<String, Option<RateLimit>> {
+    v.split(',')
+        .filter_map(|entry| {
+            let (room, limit) = entry.split_once('=')?;
+            Some((room.trim().to_string(), RateLimit::parse(limit)?))
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     /// WebSocket listen address, e.g. "0.0.0.0:9000"
@@ -10,6 +61,72 @@ pub struct Config {
     pub history_limit: usize,
     /// Seconds before an empty room is garbage‑collected
     pub room_ttl_secs: u64,
+    /// Seconds a typing indicator lasts without a refresh
+    pub typing_ttl_secs: u64,
+    /// Seconds without activity before a user shows as idle (0 = never)
+    pub idle_secs: u64,
+    /// Seconds a dropped session stays resumable (0 = no resume)
+    pub resume_grace_secs: u64,
+    /// Seconds between WebSocket pings to each client (0 = never ping)
+    pub ping_interval_secs: u64,
+    /// Seconds a client has to answer a ping before it is dropped
+    pub pong_timeout_secs: u64,
+    /// Seconds without a chat request from a client before it is dropped (0 = never)
+    pub idle_timeout_secs: u64,
+    /// Directory for on-disk history segments; `None` keeps history in memory
+    pub history_dir: Option<String>,
+    /// Drop history older than this many seconds (0 = keep forever)
+    pub history_max_age_secs: u64,
+    /// Max bytes of history kept per room (0 = unlimited)
+    pub history_max_bytes: u64,
+    /// Size at which a history segment file is rolled
+    pub segment_bytes: u64,
+    /// `name:argon2-hash` user file for password auth
+    pub auth_users_file: Option<String>,
+    /// HMAC secret for signed bearer tokens
+    pub auth_token_secret: Option<String>,
+    /// Reject `Join` until the connection has authenticated
+    pub auth_required: bool,
+    /// PEM certificate chain; with `tls_key` the listener serves `wss://`
+    pub tls_cert: Option<String>,
+    /// PEM private key for `tls_cert`
+    pub tls_key: Option<String>,
+    /// PEM CA bundle; when set, clients must present a certificate it signed
+    pub tls_client_ca: Option<String>,
+    /// Seconds allowed for a graceful shutdown before exiting anyway
+    pub shutdown_timeout_secs: u64,
+    /// Reconnect delay suggested to clients in `ServerShutdown` (0 = none)
+    pub shutdown_reconnect_secs: u64,
+    /// Handling of clients lagging behind a room broadcast
+    pub lag_policy: LagPolicy,
+    /// Missed messages per room subscription before `Disconnect` kicks in
+    pub lag_threshold: u64,
+    /// `GET /metrics` listen address; `None` disables the endpoint
+    pub metrics_addr: Option<String>,
+    /// Chat message rate per connection and user; `None` = unlimited
+    pub rate_limit: Option<RateLimit>,
+    /// Per-room overrides of `rate_limit`
+    pub room_rate_limits: HashMap<String, Option<RateLimit>>,
+    /// Chat message rate per source IP across all rooms; `None` = unlimited
+    pub ip_rate_limit: Option<RateLimit>,
+    /// Rate-limit rejections in a row before a sender is muted (0 = never)
+    pub mute_after: u32,
+    /// Seconds an auto-mute lasts
+    pub mute_secs: u64,
+    /// Largest chat message text accepted, in UTF-8 bytes
+    pub max_message_bytes: usize,
+    /// Largest WebSocket message or frame read from a client
+    pub max_frame_bytes: usize,
+    /// Most messages one history page returns; larger requests are cut down
+    pub max_history_page: usize,
+    /// Longest room name, in characters
+    pub max_room_len: usize,
+    /// Longest nickname, in characters
+    pub max_name_len: usize,
+    /// Characters allowed in room names besides letters and digits
+    pub room_name_chars: String,
+    /// Characters allowed in nicknames besides letters and digits
+    pub nick_chars: String,
 }
 
 impl Default for Config {
@@ -19,6 +136,39 @@ impl Default for Config {
             log_level: "info".into(),
             history_limit: 100,
             room_ttl_secs: 300, // 5 minutes
+            typing_ttl_secs: 6,
+            idle_secs: 300,
+            resume_grace_secs: 30,
+            ping_interval_secs: 20,
+            pong_timeout_secs: 10,
+            idle_timeout_secs: 0,
+            history_dir: None,
+            history_max_age_secs: 0,
+            history_max_bytes: 0,
+            segment_bytes: 4 * 1024 * 1024,
+            auth_users_file: None,
+            auth_token_secret: None,
+            auth_required: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            shutdown_timeout_secs: 10,
+            shutdown_reconnect_secs: 5,
+            lag_policy: LagPolicy::Notify,
+            lag_threshold: 1024,
+            metrics_addr: None,
+            rate_limit: Some(RateLimit { per_sec: 5.0, burst: 10 }),
+            room_rate_limits: HashMap::new(),
+            ip_rate_limit: Some(RateLimit { per_sec: 20.0, burst: 40 }),
+            mute_after: 10,
+            mute_secs: 60,
+            max_message_bytes: 4096,
+            max_frame_bytes: 64 * 1024,
+            max_history_page: 200,
+            max_room_len: 32,
+            max_name_len: 32,
+            room_name_chars: "-_.".into(),
+            nick_chars: "-_.".into(),
         }
     }
 }
@@ -32,6 +182,39 @@ impl Config {
     /// | `LOG_LEVEL`      | str   | "info" | log verbosity                  |
     /// | `HISTORY_LIMIT`  | usize | 100     | per‑room history size          |
     /// | `ROOM_TTL_SECS`  | u64   | 300     | seconds to keep empty rooms    |
+    /// | `TYPING_TTL_SECS` | u64  | 6       | typing indicator expiry        |
+    /// | `IDLE_SECS`      | u64   | 300     | inactivity before idle (0 = never) |
+    /// | `RESUME_GRACE_SECS` | u64 | 30     | resume window after a drop (0 = off) |
+    /// | `PING_INTERVAL_SECS` | u64 | 20    | server ping period (0 = off)   |
+    /// | `PONG_TIMEOUT_SECS` | u64 | 10     | deadline for a ping's pong     |
+    /// | `IDLE_TIMEOUT_SECS` | u64 | 0      | drop silent clients (0 = never) |
+    /// | `HISTORY_DIR`    | str   | unset   | segment log dir (unset = memory) |
+    /// | `HISTORY_MAX_AGE_SECS` | u64 | 0   | history age limit (0 = none)   |
+    /// | `HISTORY_MAX_BYTES` | u64 | 0      | history bytes limit (0 = none) |
+    /// | `SEGMENT_BYTES`  | u64   | 4 MiB   | segment file roll size         |
+    /// | `AUTH_USERS_FILE` | str  | unset   | argon2 user file               |
+    /// | `AUTH_TOKEN_SECRET` | str | unset  | HS256 bearer token secret      |
+    /// | `AUTH_REQUIRED`  | bool  | false   | require auth before `Join`     |
+    /// | `TLS_CERT`       | str   | unset   | PEM cert chain (enables wss)   |
+    /// | `TLS_KEY`        | str   | unset   | PEM private key                |
+    /// | `TLS_CLIENT_CA`  | str   | unset   | CA for client certs (mTLS)     |
+    /// | `SHUTDOWN_TIMEOUT_SECS` | u64 | 10 | graceful shutdown deadline    |
+    /// | `SHUTDOWN_RECONNECT_SECS` | u64 | 5 | reconnect hint (0 = none)    |
+    /// | `LAG_POLICY`     | str   | notify  | `notify` or `disconnect`       |
+    /// | `LAG_THRESHOLD`  | u64   | 1024    | missed msgs before disconnect  |
+    /// | `METRICS_ADDR`   | str   | unset   | Prometheus endpoint address    |
+    /// | `RATE_LIMIT`     | str   | "5/10"  | msgs/sec / burst (0 = off)     |
+    /// | `RATE_LIMIT_ROOMS` | str | unset   | `room=5/10,...` overrides      |
+    /// | `RATE_LIMIT_IP`  | str   | "20/40" | per-IP msgs/sec / burst        |
+    /// | `MUTE_AFTER`     | u32   | 10      | rejections before auto-mute    |
+    /// | `MUTE_SECS`      | u64   | 60      | auto-mute duration             |
+    /// | `MAX_MESSAGE_BYTES` | usize | 4096 | chat message text limit        |
+    /// | `MAX_FRAME_BYTES` | usize | 64 KiB | WebSocket frame/message limit  |
+    /// | `MAX_HISTORY_PAGE` | usize | 200   | messages per history page      |
+    /// | `MAX_ROOM_LEN`   | usize | 32      | room name length (chars)       |
+    /// | `MAX_NAME_LEN`   | usize | 32      | nickname length (chars)        |
+    /// | `ROOM_NAME_CHARS` | str  | "-_."   | room name symbols besides alnum |
+    /// | `NICK_CHARS`     | str   | "-_."   | nickname symbols besides alnum |
     pub fn from_env() -> Self {
         let def = Self::default();
         Self {
@@ -45,10 +228,145 @@ impl Config {
                 .ok()
                 .and_then(|v| v.parse::<u64>().ok())
                 .unwrap_or(def.room_ttl_secs),
+            typing_ttl_secs: env::var("TYPING_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.typing_ttl_secs),
+            idle_secs: env::var("IDLE_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.idle_secs),
+            resume_grace_secs: env::var("RESUME_GRACE_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.resume_grace_secs),
+            ping_interval_secs: env::var("PING_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.ping_interval_secs),
+            pong_timeout_secs: env::var("PONG_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.pong_timeout_secs),
+            idle_timeout_secs: env::var("IDLE_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.idle_timeout_secs),
+            history_dir: env::var("HISTORY_DIR")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or(def.history_dir),
+            history_max_age_secs: env::var("HISTORY_MAX_AGE_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.history_max_age_secs),
+            history_max_bytes: env::var("HISTORY_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.history_max_bytes),
+            segment_bytes: env::var("SEGMENT_BYTES")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.segment_bytes),
+            auth_users_file: env::var("AUTH_USERS_FILE")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or(def.auth_users_file),
+            auth_token_secret: env::var("AUTH_TOKEN_SECRET")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or(def.auth_token_secret),
+            auth_required: env::var("AUTH_REQUIRED")
+                .ok()
+                .and_then(|v| parse_bool(&v))
+                .unwrap_or(def.auth_required),
+            tls_cert: env::var("TLS_CERT")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or(def.tls_cert),
+            tls_key: env::var("TLS_KEY")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or(def.tls_key),
+            tls_client_ca: env::var("TLS_CLIENT_CA")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or(def.tls_client_ca),
+            shutdown_timeout_secs: env::var("SHUTDOWN_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.shutdown_timeout_secs),
+            shutdown_reconnect_secs: env::var("SHUTDOWN_RECONNECT_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.shutdown_reconnect_secs),
+            lag_policy: env::var("LAG_POLICY")
+                .ok()
+                .and_then(|v| LagPolicy::parse(&v))
+                .unwrap_or(def.lag_policy),
+            lag_threshold: env::var("LAG_THRESHOLD")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.lag_threshold),
+            metrics_addr: env::var("METRICS_ADDR")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .or(def.metrics_addr),
+            rate_limit: env::var("RATE_LIMIT")
+                .ok()
+                .and_then(|v| RateLimit::parse(&v))
+                .unwrap_or(def.rate_limit),
+            room_rate_limits: env::var("RATE_LIMIT_ROOMS")
+                .ok()
+                .map(|v| parse_room_limits(&v))
+                .unwrap_or(def.room_rate_limits),
+            ip_rate_limit: env::var("RATE_LIMIT_IP")
+                .ok()
+                .and_then(|v| RateLimit::parse(&v))
+                .unwrap_or(def.ip_rate_limit),
+            mute_after: env::var("MUTE_AFTER")
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(def.mute_after),
+            mute_secs: env::var("MUTE_SECS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or(def.mute_secs),
+            max_message_bytes: env::var("MAX_MESSAGE_BYTES")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(def.max_message_bytes),
+            max_frame_bytes: env::var("MAX_FRAME_BYTES")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(def.max_frame_bytes),
+            max_history_page: env::var("MAX_HISTORY_PAGE")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(def.max_history_page),
+            max_room_len: env::var("MAX_ROOM_LEN")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(def.max_room_len),
+            max_name_len: env::var("MAX_NAME_LEN")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(def.max_name_len),
+            room_name_chars: env::var("ROOM_NAME_CHARS").unwrap_or(def.room_name_chars),
+            nick_chars: env::var("NICK_CHARS").unwrap_or(def.nick_chars),
         }
     }
 }
 
+/// Accepts `1/0`, `true/false`, `yes/no`, `on/off`.
+fn parse_bool(v: &str) -> Option<bool> {
+    match v.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,6 +378,39 @@ mod tests {
         assert_eq!(cfg.log_level, "info");
         assert_eq!(cfg.history_limit, 100);
         assert_eq!(cfg.room_ttl_secs, 300);
+        assert_eq!(cfg.typing_ttl_secs, 6);
+        assert_eq!(cfg.idle_secs, 300);
+        assert_eq!(cfg.resume_grace_secs, 30);
+        assert_eq!(cfg.ping_interval_secs, 20);
+        assert_eq!(cfg.pong_timeout_secs, 10);
+        assert_eq!(cfg.idle_timeout_secs, 0);
+        assert_eq!(cfg.history_dir, None);
+        assert_eq!(cfg.history_max_age_secs, 0);
+        assert_eq!(cfg.history_max_bytes, 0);
+        assert_eq!(cfg.segment_bytes, 4 * 1024 * 1024);
+        assert_eq!(cfg.auth_users_file, None);
+        assert_eq!(cfg.auth_token_secret, None);
+        assert!(!cfg.auth_required);
+        assert_eq!(cfg.tls_cert, None);
+        assert_eq!(cfg.tls_key, None);
+        assert_eq!(cfg.tls_client_ca, None);
+        assert_eq!(cfg.shutdown_timeout_secs, 10);
+        assert_eq!(cfg.shutdown_reconnect_secs, 5);
+        assert_eq!(cfg.lag_policy, LagPolicy::Notify);
+        assert_eq!(cfg.lag_threshold, 1024);
+        assert_eq!(cfg.metrics_addr, None);
+        assert_eq!(cfg.rate_limit, Some(RateLimit { per_sec: 5.0, burst: 10 }));
+        assert!(cfg.room_rate_limits.is_empty());
+        assert_eq!(cfg.ip_rate_limit, Some(RateLimit { per_sec: 20.0, burst: 40 }));
+        assert_eq!(cfg.mute_after, 10);
+        assert_eq!(cfg.mute_secs, 60);
+        assert_eq!(cfg.max_message_bytes, 4096);
+        assert_eq!(cfg.max_frame_bytes, 64 * 1024);
+        assert_eq!(cfg.max_history_page, 200);
+        assert_eq!(cfg.max_room_len, 32);
+        assert_eq!(cfg.max_name_len, 32);
+        assert_eq!(cfg.room_name_chars, "-_.");
+        assert_eq!(cfg.nick_chars, "-_.");
     }
 
     #[test]
@@ -69,6 +420,39 @@ mod tests {
             ("LOG_LEVEL", "debug"),
             ("HISTORY_LIMIT", "256"),
             ("ROOM_TTL_SECS", "600"),
+            ("TYPING_TTL_SECS", "3"),
+            ("IDLE_SECS", "0"),
+            ("RESUME_GRACE_SECS", "5"),
+            ("PING_INTERVAL_SECS", "0"),
+            ("PONG_TIMEOUT_SECS", "3"),
+            ("IDLE_TIMEOUT_SECS", "900"),
+            ("HISTORY_DIR", "/var/lib/chat"),
+            ("HISTORY_MAX_AGE_SECS", "86400"),
+            ("HISTORY_MAX_BYTES", "1048576"),
+            ("SEGMENT_BYTES", "65536"),
+            ("AUTH_USERS_FILE", "/etc/chat/users"),
+            ("AUTH_TOKEN_SECRET", "hunter2"),
+            ("AUTH_REQUIRED", "yes"),
+            ("TLS_CERT", "/etc/chat/cert.pem"),
+            ("TLS_KEY", "/etc/chat/key.pem"),
+            ("TLS_CLIENT_CA", "/etc/chat/ca.pem"),
+            ("SHUTDOWN_TIMEOUT_SECS", "30"),
+            ("SHUTDOWN_RECONNECT_SECS", "0"),
+            ("LAG_POLICY", "Disconnect"),
+            ("LAG_THRESHOLD", "64"),
+            ("METRICS_ADDR", "127.0.0.1:9090"),
+            ("RATE_LIMIT", "0.5/3"),
+            ("RATE_LIMIT_ROOMS", "lobby=1/2, bots=0,bad=x"),
+            ("RATE_LIMIT_IP", "0"),
+            ("MUTE_AFTER", "3"),
+            ("MUTE_SECS", "120"),
+            ("MAX_MESSAGE_BYTES", "1000"),
+            ("MAX_FRAME_BYTES", "2048"),
+            ("MAX_HISTORY_PAGE", "50"),
+            ("MAX_ROOM_LEN", "16"),
+            ("MAX_NAME_LEN", "12"),
+            ("ROOM_NAME_CHARS", "-#"),
+            ("NICK_CHARS", ""),
         ]);
 
         let cfg = Config::from_env();
@@ -76,6 +460,41 @@ mod tests {
         assert_eq!(cfg.log_level, "debug");
         assert_eq!(cfg.history_limit, 256);
         assert_eq!(cfg.room_ttl_secs, 600);
+        assert_eq!(cfg.typing_ttl_secs, 3);
+        assert_eq!(cfg.idle_secs, 0);
+        assert_eq!(cfg.resume_grace_secs, 5);
+        assert_eq!(cfg.ping_interval_secs, 0);
+        assert_eq!(cfg.pong_timeout_secs, 3);
+        assert_eq!(cfg.idle_timeout_secs, 900);
+        assert_eq!(cfg.history_dir.as_deref(), Some("/var/lib/chat"));
+        assert_eq!(cfg.history_max_age_secs, 86400);
+        assert_eq!(cfg.history_max_bytes, 1048576);
+        assert_eq!(cfg.segment_bytes, 65536);
+        assert_eq!(cfg.auth_users_file.as_deref(), Some("/etc/chat/users"));
+        assert_eq!(cfg.auth_token_secret.as_deref(), Some("hunter2"));
+        assert!(cfg.auth_required);
+        assert_eq!(cfg.tls_cert.as_deref(), Some("/etc/chat/cert.pem"));
+        assert_eq!(cfg.tls_key.as_deref(), Some("/etc/chat/key.pem"));
+        assert_eq!(cfg.tls_client_ca.as_deref(), Some("/etc/chat/ca.pem"));
+        assert_eq!(cfg.shutdown_timeout_secs, 30);
+        assert_eq!(cfg.shutdown_reconnect_secs, 0);
+        assert_eq!(cfg.lag_policy, LagPolicy::Disconnect);
+        assert_eq!(cfg.lag_threshold, 64);
+        assert_eq!(cfg.metrics_addr.as_deref(), Some("127.0.0.1:9090"));
+        assert_eq!(cfg.rate_limit, Some(RateLimit { per_sec: 0.5, burst: 3 }));
+        assert_eq!(cfg.room_rate_limits.len(), 2);
+        assert_eq!(cfg.room_rate_limits["lobby"], Some(RateLimit { per_sec: 1.0, burst: 2 }));
+        assert_eq!(cfg.room_rate_limits["bots"], None);
+        assert_eq!(cfg.ip_rate_limit, None);
+        assert_eq!(cfg.mute_after, 3);
+        assert_eq!(cfg.mute_secs, 120);
+        assert_eq!(cfg.max_message_bytes, 1000);
+        assert_eq!(cfg.max_frame_bytes, 2048);
+        assert_eq!(cfg.max_history_page, 50);
+        assert_eq!(cfg.max_room_len, 16);
+        assert_eq!(cfg.max_name_len, 12);
+        assert_eq!(cfg.room_name_chars, "-#");
+        assert_eq!(cfg.nick_chars, "");
     }
 
     /// Simple RAII env guard for tests
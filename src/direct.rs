@@ -0,0 +1,275 @@
+//! One-to-one messages between users, outside any room.
+//!
+//! A [`Directory`] of the connections of every user runs on its own task
+//! (see [`spawn_directory`]), so conversation logs are written off the hub
+//! task; the hub forwards [`DirectCmd`]s to it. A direct message is stamped, appended to the conversation's own log and
+//! pushed to each connection of the recipient, and echoed to the sender's.
+//! A recipient with no connection gets it on the next one; the queue is
+//! bounded and in memory only, beyond it `DirectHistory` still has them.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::error::ChatError;
+use crate::ids::unique_id;
+use crate::protocol::ServerEvent;
+use crate::storage::{HistoryQuery, MemoryStorage, Retention, RoomLog, Storage};
+
+/// Messages kept per offline user; the oldest are dropped first.
+const MAX_PENDING: usize = 256;
+/// Conversation logs held open at once.
+const MAX_OPEN_LOGS: usize = 256;
+/// Capacity of a connection's direct-message feed; fits a full queue.
+pub const FEED_CAPACITY: usize = MAX_PENDING;
+
+/// Commands sent from Hub → directory task
+pub enum DirectCmd {
+    /// Deliver `user`'s direct messages to connection `session` via `feed`.
+    Register {
+        user: String,
+        session: String,
+        feed: mpsc::Sender<Bytes>,
+    },
+    Unregister {
+        user: String,
+        session: String,
+    },
+    Send {
+        from: String,
+        to: String,
+        text: String,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    History {
+        user: String,
+        with: String,
+        query: HistoryQuery,
+        resp: oneshot::Sender<Vec<(u64, Bytes)>>,
+    },
+    /// Flush every conversation and exit.
+    Shutdown,
+}
+
+/// Spawn the directory task; returns its sender + JoinHandle
+pub fn spawn_directory(cfg: &Config, storage: Arc<dyn Storage>) -> (mpsc::Sender<DirectCmd>, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::channel::<DirectCmd>(64);
+    let mut directory = Directory::new(cfg, storage);
+    let handle = tokio::spawn(async move {
+        while let Some(cmd) = rx.recv().await {
+            match cmd {
+                DirectCmd::Register { user, session, feed } => directory.register(&user, &session, feed),
+                DirectCmd::Unregister { user, session } => directory.unregister(&user, &session),
+                DirectCmd::Send { from, to, text, resp } => {
+                    let _ = resp.send(directory.send(&from, &to, text));
+                }
+                DirectCmd::History { user, with, query, resp } => {
+                    let _ = resp.send(directory.history(&user, &with, &query));
+                }
+                DirectCmd::Shutdown => break,
+            }
+        }
+        directory.flush();
+    });
+    (tx, handle)
+}
+
+#[derive(Default)]
+struct Inbox {
+    /// session id → that connection's feed
+    sessions: HashMap<String, mpsc::Sender<Bytes>>,
+    /// frames waiting for the user's next connection
+    pending: VecDeque<Bytes>,
+}
+
+/// Connected users and their conversations; owned by the directory task.
+pub struct Directory {
+    storage: Arc<dyn Storage>,
+    retention: Retention,
+    users: HashMap<String, Inbox>,
+    /// keyed by the two users, sorted
+    logs: HashMap<(String, String), Box<dyn RoomLog>>,
+}
+
+impl Directory {
+    pub fn new(cfg: &Config, storage: Arc<dyn Storage>) -> Self {
+        Self { storage, retention: Retention::from_config(cfg), users: HashMap::new(), logs: HashMap::new() }
+    }
+
+    /// Route `user`'s messages to connection `session` too, starting with
+    /// whatever queued up while they were away.
+    pub fn register(&mut self, user: &str, session: &str, feed: mpsc::Sender<Bytes>) {
+        let inbox = self.users.entry(user.to_string()).or_default();
+        for frame in inbox.pending.drain(..) {
+            let _ = feed.try_send(frame);
+        }
+        inbox.sessions.insert(session.to_string(), feed);
+    }
+
+    pub fn unregister(&mut self, user: &str, session: &str) {
+        if let Some(inbox) = self.users.get_mut(user) {
+            inbox.sessions.remove(session);
+            if inbox.sessions.is_empty() && inbox.pending.is_empty() {
+                self.users.remove(user);
+            }
+        }
+    }
+
+    /// Record `text` from `from` to `to` and deliver it to both sides.
+    pub fn send(&mut self, from: &str, to: &str, text: String) -> Result<(), ChatError> {
+        if from == to {
+            return Err(ChatError::BadRequest("cannot message yourself".into()));
+        }
+        let log = self.log(from, to);
+        let ts = chrono::Utc::now().timestamp_millis() as u64;
+        let event = ServerEvent::DirectMessage {
+            id: unique_id(),
+            seq: log.next_index(),
+            from: from.to_string(),
+            to: to.to_string(),
+            text,
+            ts,
+        };
+        let frame = Bytes::from(serde_json::to_vec(&event)?);
+        log.append(ts, frame.clone())?;
+        self.deliver(to, frame.clone(), true);
+        self.deliver(from, frame, false);
+        Ok(())
+    }
+
+    /// `(index, frame)` pairs of the conversation between `a` and `b`.
+    pub fn history(&mut self, a: &str, b: &str, query: &HistoryQuery) -> Vec<(u64, Bytes)> {
+        self.log(a, b).query(query)
+    }
+
+    pub fn flush(&mut self) {
+        for ((a, b), log) in self.logs.iter_mut() {
+            if let Err(e) = log.flush() {
+                tracing::error!(a=%a, b=%b, error=%e, "failed to flush direct messages");
+            }
+        }
+    }
+
+    /// Push `frame` to every connection of `user`; queue it if they have
+    /// none and `queue` is set.
+    fn deliver(&mut self, user: &str, frame: Bytes, queue: bool) {
+        if !queue && !self.users.contains_key(user) {
+            return;
+        }
+        let inbox = self.users.entry(user.to_string()).or_default();
+        inbox.sessions.retain(|_, feed| !feed.is_closed());
+        if inbox.sessions.is_empty() {
+            if queue {
+                if inbox.pending.len() >= MAX_PENDING {
+                    inbox.pending.pop_front();
+                }
+                inbox.pending.push_back(frame);
+            }
+            return;
+        }
+        for feed in inbox.sessions.values() {
+            // never wait on a slow reader; history has the message
+            if feed.try_send(frame.clone()).is_err() {
+                tracing::warn!(user=%user, "direct message feed full, frame dropped");
+            }
+        }
+    }
+
+    fn log(&mut self, a: &str, b: &str) -> &mut Box<dyn RoomLog> {
+        let key = if a <= b { (a.to_string(), b.to_string()) } else { (b.to_string(), a.to_string()) };
+        if !self.logs.contains_key(&key) {
+            if self.logs.len() >= MAX_OPEN_LOGS {
+                // close any other conversation; it reopens from storage
+                let other = self.logs.keys().next().cloned();
+                if let Some(mut log) = other.and_then(|k| self.logs.remove(&k)) {
+                    let _ = log.flush();
+                }
+            }
+            let log = self.storage.open_direct(&key.0, &key.1).unwrap_or_else(|e| {
+                tracing::error!(a=%key.0, b=%key.1, error=%e, "direct message store unavailable, using memory");
+                MemoryStorage::new(self.retention).open_direct(&key.0, &key.1).expect("memory store")
+            });
+            self.logs.insert(key.clone(), log);
+        }
+        self.logs.get_mut(&key).expect("log just opened")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory() -> Directory {
+        let cfg = Config::default();
+        Directory::new(&cfg, Arc::new(MemoryStorage::new(Retention::from_config(&cfg))))
+    }
+
+    fn text(frame: Bytes) -> String {
+        match serde_json::from_slice(&frame).unwrap() {
+            ServerEvent::DirectMessage { text, .. } => text,
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_to_every_connection_and_echoes() {
+        let mut dir = directory();
+        let (bob1, mut bob1_rx) = mpsc::channel(FEED_CAPACITY);
+        let (bob2, mut bob2_rx) = mpsc::channel(FEED_CAPACITY);
+        let (alice, mut alice_rx) = mpsc::channel(FEED_CAPACITY);
+        dir.register("bob", "s1", bob1);
+        dir.register("bob", "s2", bob2);
+        dir.register("alice", "s3", alice);
+
+        dir.send("alice", "bob", "hi".into()).unwrap();
+        assert_eq!(text(bob1_rx.recv().await.unwrap()), "hi");
+        assert_eq!(text(bob2_rx.recv().await.unwrap()), "hi");
+        assert_eq!(text(alice_rx.recv().await.unwrap()), "hi");
+        assert!(dir.send("bob", "bob", "me".into()).is_err());
+    }
+
+    #[tokio::test]
+    async fn queues_for_offline_users() {
+        let mut dir = directory();
+        dir.send("alice", "bob", "one".into()).unwrap();
+        dir.send("alice", "bob", "two".into()).unwrap();
+
+        let (bob, mut bob_rx) = mpsc::channel(FEED_CAPACITY);
+        dir.register("bob", "s1", bob);
+        assert_eq!(text(bob_rx.recv().await.unwrap()), "one");
+        assert_eq!(text(bob_rx.recv().await.unwrap()), "two");
+
+        // the conversation keeps its history either way round
+        let page = dir.history("bob", "alice", &HistoryQuery::latest(10));
+        assert_eq!(page.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1]);
+
+        dir.unregister("bob", "s1");
+        dir.send("alice", "bob", "three".into()).unwrap();
+        assert!(bob_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn task_serves_commands_until_shutdown() {
+        let cfg = Config::default();
+        let (tx, task) = spawn_directory(&cfg, Arc::new(MemoryStorage::new(Retention::from_config(&cfg))));
+        let (bob, mut bob_rx) = mpsc::channel(FEED_CAPACITY);
+        tx.send(DirectCmd::Register { user: "bob".into(), session: "s1".into(), feed: bob }).await.unwrap();
+
+        let (resp, rx) = oneshot::channel();
+        tx.send(DirectCmd::Send { from: "alice".into(), to: "bob".into(), text: "hi".into(), resp }).await.unwrap();
+        rx.await.unwrap().unwrap();
+        assert_eq!(text(bob_rx.recv().await.unwrap()), "hi");
+
+        let (resp, rx) = oneshot::channel();
+        let query = HistoryQuery::latest(10);
+        tx.send(DirectCmd::History { user: "bob".into(), with: "alice".into(), query, resp }).await.unwrap();
+        assert_eq!(rx.await.unwrap().len(), 1);
+
+        tx.send(DirectCmd::Shutdown).await.unwrap();
+        task.await.unwrap();
+    }
+}
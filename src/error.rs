@@ -3,20 +3,88 @@ use std::io;
 use serde_json;
 use tungstenite;
 
+use crate::protocol::ErrorCode;
+
 #[derive(Debug)]
 pub enum ChatError {
     Io(io::Error),
     Serde(serde_json::Error),
-    Tungstenite(tungstenite::Error),
+    Tungstenite(Box<tungstenite::Error>),
+    /// the room does not exist
+    UnknownRoom(String),
+    /// the room exists but this connection has not joined it
+    NotMember(String),
+    /// another member of the room already uses this name
+    NameTaken(String),
+    /// the request is well-formed JSON but not valid in this state
+    BadRequest(String),
+    /// the client speaks a protocol version we no longer serve
+    UnsupportedVersion(u32),
+    /// authentication failed or is required first
+    Unauthorized(String),
+    /// over the message rate; retry after this many seconds
+    RateLimited(u64),
+    /// auto-muted for flooding, for this many more seconds
+    Muted(u64),
+    /// a room name, nickname or message failed validation
+    Invalid(String),
+    /// the sender's room role does not allow this, or it is muted
+    Forbidden(String),
+    /// banned from a room; `retry_after` is `None` for permanent bans
+    Banned { room: String, retry_after: Option<u64> },
     Custom(String),
 }
 
+impl ChatError {
+    /// Wire error code reported to clients.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ChatError::Serde(_) | ChatError::BadRequest(_) => ErrorCode::BadRequest,
+            ChatError::UnknownRoom(_) => ErrorCode::UnknownRoom,
+            ChatError::NotMember(_) => ErrorCode::NotMember,
+            ChatError::NameTaken(_) => ErrorCode::NameTaken,
+            ChatError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            ChatError::Unauthorized(_) => ErrorCode::Unauthorized,
+            ChatError::RateLimited(_) | ChatError::Muted(_) => ErrorCode::RateLimited,
+            ChatError::Invalid(_) => ErrorCode::InvalidInput,
+            ChatError::Forbidden(_) => ErrorCode::Forbidden,
+            ChatError::Banned { .. } => ErrorCode::Banned,
+            ChatError::Io(_) | ChatError::Tungstenite(_) | ChatError::Custom(_) => {
+                ErrorCode::Internal
+            }
+        }
+    }
+
+    /// Seconds the client should wait before retrying, if that is known.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ChatError::RateLimited(secs) | ChatError::Muted(secs) => Some(*secs),
+            ChatError::Banned { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for ChatError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ChatError::Io(err) => write!(f, "IO Error: {}", err),
             ChatError::Serde(err) => write!(f, "Serde Error: {}", err),
             ChatError::Tungstenite(err) => write!(f, "Tungstenite Error: {}", err),
+            ChatError::UnknownRoom(room) => write!(f, "unknown room: {}", room),
+            ChatError::NotMember(room) => write!(f, "not a member of {}", room),
+            ChatError::NameTaken(name) => write!(f, "name already taken: {}", name),
+            ChatError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            ChatError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            ChatError::Unauthorized(msg) => write!(f, "unauthorized: {}", msg),
+            ChatError::RateLimited(secs) => write!(f, "rate limited, retry in {}s", secs),
+            ChatError::Muted(secs) => write!(f, "muted for flooding, {}s left", secs),
+            ChatError::Invalid(msg) => write!(f, "invalid input: {}", msg),
+            ChatError::Forbidden(msg) => write!(f, "forbidden: {}", msg),
+            ChatError::Banned { room, retry_after: None } => write!(f, "banned from {}", room),
+            ChatError::Banned { room, retry_after: Some(secs) } => {
+                write!(f, "banned from {}, {}s left", room, secs)
+            }
             ChatError::Custom(msg) => write!(f, "{}", msg),
         }
     }
@@ -38,7 +106,7 @@ impl From<serde_json::Error> for ChatError {
 
 impl From<tungstenite::Error> for ChatError {
     fn from(err: tungstenite::Error) -> Self {
-        ChatError::Tungstenite(err)
+        ChatError::Tungstenite(Box::new(err))
     }
 }
 
@@ -49,7 +117,7 @@ mod tests {
 
     #[test]
     fn test_display_io_error() {
-        let err = ChatError::Io(io::Error::new(io::ErrorKind::Other, "disk full"));
+        let err = ChatError::Io(io::Error::other("disk full"));
         assert!(format!("{}", err).contains("disk full"));
     }
 
@@ -58,4 +126,23 @@ mod tests {
         let err = ChatError::Custom("my error".into());
         assert_eq!(format!("{}", err), "my error");
     }
+
+    #[test]
+    fn test_error_codes() {
+        let bad_json = serde_json::from_str::<u8>("x").unwrap_err();
+        assert_eq!(ChatError::from(bad_json).code(), ErrorCode::BadRequest);
+        assert_eq!(ChatError::UnknownRoom("r".into()).code(), ErrorCode::UnknownRoom);
+        assert_eq!(ChatError::NotMember("r".into()).code(), ErrorCode::NotMember);
+        assert_eq!(ChatError::NameTaken("n".into()).code(), ErrorCode::NameTaken);
+        assert_eq!(ChatError::UnsupportedVersion(0).code(), ErrorCode::UnsupportedVersion);
+        assert_eq!(ChatError::Muted(5).code(), ErrorCode::RateLimited);
+        assert_eq!(ChatError::RateLimited(2).retry_after(), Some(2));
+        assert_eq!(ChatError::Invalid("x".into()).code(), ErrorCode::InvalidInput);
+        assert_eq!(ChatError::Forbidden("x".into()).code(), ErrorCode::Forbidden);
+        let banned = ChatError::Banned { room: "r".into(), retry_after: Some(9) };
+        assert_eq!(banned.code(), ErrorCode::Banned);
+        assert_eq!(banned.retry_after(), Some(9));
+        assert_eq!(banned.to_string(), "banned from r, 9s left");
+        assert_eq!(ChatError::Custom("x".into()).code(), ErrorCode::Internal);
+    }
 }
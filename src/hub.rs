@@ -1,60 +1,187 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
-use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
 
 use crate::config::Config;
-use crate::protocol::ServerEvent;
-use crate::room::{spawn_room_task, RoomCmd};
+use crate::direct::{spawn_directory, DirectCmd};
+use crate::error::ChatError;
+use crate::moderation::ModAction;
+use crate::presence::PresenceRegistry;
+use crate::protocol::{LeaveReason, ServerEvent, TypingState, Visibility};
+use crate::room::{spawn_room_task, thread_history, Amendment, Membership, RoomCmd};
+use crate::storage::{self, HistoryQuery, Storage};
+
+/// How often users are checked for having gone idle.
+const PRESENCE_SWEEP: Duration = Duration::from_secs(5);
 
 /// Commands accepted by [`ChatHub`].
 pub enum HubCmd {
     Join {
         room: String,
         name: String,
-        /// oneshot channel to return a broadcast receiver for this client
-        resp: oneshot::Sender<broadcast::Receiver<Bytes>>, 
+        /// `name` is the session's authenticated identity
+        verified: bool,
+        /// join password and invite token presented by the client
+        password: Option<String>,
+        invite: Option<String>,
+        /// oneshot channel to return the room subscription for this client,
+        /// or why it may not join
+        resp: oneshot::Sender<Result<Membership, ChatError>>,
     },
     Send {
         room: String,
         event: ServerEvent,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    /// Change message `id` as member `actor`.
+    Amend {
+        room: String,
+        actor: String,
+        id: String,
+        change: Amendment,
+        resp: oneshot::Sender<Result<(), ChatError>>,
     },
     Leave {
         room: String,
         name: String,
+        reason: LeaveReason,
+    },
+    Typing {
+        room: String,
+        name: String,
+        state: TypingState,
+    },
+    MarkRead {
+        room: String,
+        name: String,
+        id: String,
+        receipt: bool,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    Rename {
+        room: String,
+        old: String,
+        new: String,
+        resp: oneshot::Sender<Result<(), ChatError>>,
     },
     GetMembers {
         room: String,
-        resp: oneshot::Sender<Vec<String>>,
+        /// `None` when the room does not exist
+        resp: oneshot::Sender<Option<Vec<String>>>,
     },
     GetHistory {
         room: String,
-        resp: oneshot::Sender<Vec<Bytes>>,
+        query: HistoryQuery,
+        /// only this thread and its root
+        thread: Option<String>,
+        /// `(index, frame)` pairs, oldest first
+        resp: oneshot::Sender<Vec<(u64, Bytes)>>,
     },
     GetRoomList {
-        resp: oneshot::Sender<Vec<String>>,
+        /// rooms the caller is in (room → name there); listed even when not
+        /// public
+        joined: HashMap<String, String>,
+        /// the rooms, and the caller's unread count in each joined one
+        resp: oneshot::Sender<(Vec<String>, BTreeMap<String, u64>)>,
+    },
+    Moderate {
+        room: String,
+        /// the requesting member's name in `room`
+        actor: String,
+        action: ModAction,
+        /// reply meant for the actor alone, e.g. `InviteCreated`
+        resp: oneshot::Sender<Result<Option<ServerEvent>, ChatError>>,
+    },
+    /// Deliver `user`'s direct messages to connection `session` via `feed`.
+    Register {
+        user: String,
+        session: String,
+        feed: mpsc::Sender<Bytes>,
+    },
+    Unregister {
+        user: String,
+        session: String,
+    },
+    /// Connection `session` of `user` was active just now and is in
+    /// `rooms` (room → name there).
+    Presence {
+        user: String,
+        session: String,
+        rooms: HashMap<String, String>,
+    },
+    /// Connection `session` of `user` closed.
+    Offline {
+        user: String,
+        session: String,
+    },
+    SetStatus {
+        user: String,
+        away: bool,
+        status: Option<String>,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    WhoIs {
+        name: String,
+        /// a `UserInfo`
+        resp: oneshot::Sender<ServerEvent>,
+    },
+    Direct {
+        from: String,
+        to: String,
+        text: String,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    GetDirectHistory {
+        user: String,
+        with: String,
+        query: HistoryQuery,
+        /// `(index, frame)` pairs, oldest first
+        resp: oneshot::Sender<Vec<(u64, Bytes)>>,
+    },
+    /// Stop every room (flushing its history) and exit; `resp` fires once
+    /// all rooms are down.
+    Shutdown {
+        resp: oneshot::Sender<()>,
     },
 }
 
 struct RoomHandle {
     tx: mpsc::Sender<RoomCmd>,
-    _join: JoinHandle<()>, // kept to avoid detaching silently
+    join: JoinHandle<()>, // awaited on shutdown so history gets flushed
+    /// cached for `GetRoomList`; read when the room starts and updated on
+    /// every accepted `SetVisibility`
+    visibility: Visibility,
 }
 
 /// Lightweight router hub
 pub struct ChatHub {
     rooms: HashMap<String, RoomHandle>,
+    directory: mpsc::Sender<DirectCmd>,
+    /// awaited on shutdown so conversations get flushed
+    directory_task: JoinHandle<()>,
+    presence: PresenceRegistry,
     rx: mpsc::Receiver<HubCmd>,
     cfg: Config,
+    storage: Arc<dyn Storage>,
 }
 
 impl ChatHub {
     pub fn new(rx: mpsc::Receiver<HubCmd>) -> Self {
+        let cfg = Config::from_env();
+        let storage = storage::from_config(&cfg);
+        let (directory, directory_task) = spawn_directory(&cfg, storage.clone());
         Self {
             rooms: HashMap::new(),
+            directory,
+            directory_task,
+            presence: PresenceRegistry::new(&cfg),
             rx,
-            cfg: Config::from_env(),
+            storage,
+            cfg,
         }
     }
 
@@ -67,69 +194,312 @@ impl ChatHub {
     }
 
     async fn run(&mut self) {
-        while let Some(cmd) = self.rx.recv().await {
-            self.handle_cmd(cmd).await;
+        let mut sweep = tokio::time::interval(PRESENCE_SWEEP);
+        loop {
+            tokio::select! {
+                cmd = self.rx.recv() => {
+                    let Some(cmd) = cmd else { break };
+                    let stop = matches!(cmd, HubCmd::Shutdown { .. });
+                    self.handle_cmd(cmd).await;
+                    if stop {
+                        break;
+                    }
+                }
+                _ = sweep.tick() => {
+                    let events = self.presence.sweep(now_ms());
+                    self.announce(events).await;
+                }
+            }
+        }
+    }
+
+    /// Hand each room-addressed event to its room for fan-out.
+    async fn announce(&mut self, events: Vec<ServerEvent>) {
+        for event in events {
+            let ServerEvent::PresenceChanged { room, .. } = &event else { continue };
+            if let Some(handle) = self.rooms.get(room) {
+                let _ = handle.tx.send(RoomCmd::Announce { event }).await;
+            }
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        for (_, handle) in self.rooms.iter() {
+            let _ = handle.tx.send(RoomCmd::Shutdown).await;
+        }
+        for (room, handle) in self.rooms.drain() {
+            if let Err(e) = handle.join.await {
+                tracing::error!(room=%room, error=%e, "room task failed during shutdown");
+            }
+        }
+        let _ = self.directory.send(DirectCmd::Shutdown).await;
+        if let Err(e) = (&mut self.directory_task).await {
+            tracing::error!(error=%e, "direct message task failed during shutdown");
         }
     }
 
-    async fn room_entry(&mut self, room: &str) -> &RoomHandle {
-        if !self.rooms.contains_key(room) {
-            let (tx, jh) = spawn_room_task(&self.cfg, room.to_string());
-            self.rooms.insert(room.to_string(), RoomHandle { tx, _join: jh });
+    async fn room_entry(&mut self, room: &str) -> Result<&RoomHandle, ChatError> {
+        // a room that expired after its TTL is started again
+        if self.rooms.get(room).is_none_or(|h| h.tx.is_closed()) {
+            let (tx, jh, visibility) = spawn_room_task(&self.cfg, &self.storage, room.to_string())?;
+            self.rooms.insert(room.to_string(), RoomHandle { tx, join: jh, visibility });
         }
         // unwrap safe now
-        self.rooms.get(room).unwrap()
+        Ok(self.rooms.get(room).unwrap())
     }
 
     async fn handle_cmd(&mut self, cmd: HubCmd) {
         match cmd {
-            HubCmd::Join { room, name, resp } => {
-                let room_handle = self.room_entry(&room).await;
-                let (rx_tx, rx_rx) = oneshot::channel();
-                // forward
-                let _ = room_handle
-                    .tx
-                    .send(RoomCmd::Join { name, resp: rx_tx })
-                    .await;
-                // wait for room to give us broadcast receiver then relay back
-                if let Ok(bc_rx) = rx_rx.await {
-                    let _ = resp.send(bc_rx);
+            HubCmd::Join { room, name, verified, password, invite, resp } => {
+                // the room checks access against its own roles and settings;
+                // a room expiring just as we join drops the request; the
+                // second try starts it again
+                for _ in 0..2 {
+                    let room_handle = match self.room_entry(&room).await {
+                        Ok(handle) => handle,
+                        Err(e) => {
+                            let _ = resp.send(Err(e));
+                            return;
+                        }
+                    };
+                    let (rx_tx, rx_rx) = oneshot::channel();
+                    let (password, invite) = (password.clone(), invite.clone());
+                    let join = RoomCmd::Join { name: name.clone(), verified, password, invite, resp: rx_tx };
+                    if room_handle.tx.send(join).await.is_err() {
+                        continue;
+                    }
+                    // wait for room to give us broadcast receiver then relay back
+                    if let Ok(res) = rx_rx.await {
+                        let _ = resp.send(res);
+                        return;
+                    }
+                }
+            }
+            HubCmd::Send { room, event, resp } => {
+                if let Some(handle) = self.rooms.get(&room) {
+                    let _ = handle.tx.send(RoomCmd::Send { event, resp }).await;
+                } else {
+                    let _ = resp.send(Err(ChatError::UnknownRoom(room)));
+                }
+            }
+            HubCmd::Amend { room, actor, id, change, resp } => {
+                if let Some(handle) = self.rooms.get(&room) {
+                    let _ = handle.tx.send(RoomCmd::Amend { actor, id, change, resp }).await;
+                } else {
+                    let _ = resp.send(Err(ChatError::UnknownRoom(room)));
+                }
+            }
+            HubCmd::Leave { room, name, reason } => {
+                if let Some(handle) = self.rooms.get(&room) {
+                    let _ = handle.tx.send(RoomCmd::Leave { name, reason }).await;
                 }
             }
-            HubCmd::Send { room, event } => {
+            HubCmd::Typing { room, name, state } => {
                 if let Some(handle) = self.rooms.get(&room) {
-                    let _ = handle.tx.send(RoomCmd::Send ( event )).await;
+                    let _ = handle.tx.send(RoomCmd::Typing { name, state }).await;
                 }
             }
-            HubCmd::Leave { room, name } => {
+            HubCmd::MarkRead { room, name, id, receipt, resp } => {
                 if let Some(handle) = self.rooms.get(&room) {
-                    let _ = handle.tx.send(RoomCmd::Leave { name }).await;
+                    let _ = handle.tx.send(RoomCmd::MarkRead { name, id, receipt, resp }).await;
+                } else {
+                    let _ = resp.send(Err(ChatError::UnknownRoom(room)));
+                }
+            }
+            HubCmd::Rename { room, old, new, resp } => {
+                if let Some(handle) = self.rooms.get(&room) {
+                    let _ = handle.tx.send(RoomCmd::Rename { old, new, resp }).await;
+                } else {
+                    let _ = resp.send(Err(ChatError::UnknownRoom(room)));
                 }
             }
+            HubCmd::Moderate { room, actor, action, resp } => {
+                let Some(handle) = self.rooms.get(&room) else {
+                    let _ = resp.send(Err(ChatError::UnknownRoom(room)));
+                    return;
+                };
+                let visibility = match action {
+                    ModAction::SetVisibility(visibility) => Some(visibility),
+                    _ => None,
+                };
+                let (tx, rx) = oneshot::channel();
+                let _ = handle.tx.send(RoomCmd::Moderate { actor, action, resp: tx }).await;
+                let res = rx.await.unwrap_or_else(|_| Err(ChatError::UnknownRoom(room.clone())));
+                if res.is_ok()
+                    && let Some(visibility) = visibility
+                    && let Some(handle) = self.rooms.get_mut(&room)
+                {
+                    handle.visibility = visibility;
+                }
+                let _ = resp.send(res);
+            }
             HubCmd::GetMembers { room, resp } => {
                 if let Some(handle) = self.rooms.get(&room) {
                     let (tx, rx) = oneshot::channel();
                     let _ = handle.tx.send(RoomCmd::GetMembers { resp: tx }).await;
-                    let _ = resp.send(rx.await.unwrap_or_default());
+                    let _ = resp.send(rx.await.ok());
                 } else {
-                    let _ = resp.send(Vec::new());
+                    let _ = resp.send(None);
                 }
             }
-            HubCmd::GetHistory { room, resp } => {
+            HubCmd::GetHistory { room, query, thread, resp } => {
                 if let Some(handle) = self.rooms.get(&room) {
                     let (tx, rx) = oneshot::channel();
-                    let _ = handle.tx.send(RoomCmd::GetHistory { resp: tx }).await;
+                    let cmd = match thread {
+                        Some(thread) => RoomCmd::GetThread { thread, query, resp: tx },
+                        None => RoomCmd::GetHistory { query, resp: tx },
+                    };
+                    let _ = handle.tx.send(cmd).await;
                     let _ = resp.send(rx.await.unwrap_or_default());
                 } else {
-                    let _ = resp.send(Vec::new());
+                    // room not live (e.g. after a restart) – read straight from storage
+                    let hist = self
+                        .storage
+                        .open_room(&room)
+                        .map(|mut log| match &thread {
+                            Some(thread) => thread_history(log.as_mut(), thread, &query),
+                            None => log.query(&query),
+                        })
+                        .unwrap_or_default();
+                    let _ = resp.send(hist);
                 }
             }
-            HubCmd::GetRoomList { resp } => {
+            HubCmd::GetRoomList { joined, resp } => {
                 self.rooms.retain(|_, h| !h.tx.is_closed());
-                let list: Vec<String> = self.rooms.keys().cloned().collect();
-                let _ = resp.send(list);
+                let list: Vec<String> = self
+                    .rooms
+                    .iter()
+                    .filter(|(name, h)| h.visibility == Visibility::Public || joined.contains_key(*name))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                let mut unread = BTreeMap::new();
+                for (room, name) in joined {
+                    let Some(handle) = self.rooms.get(&room) else { continue };
+                    let (tx, rx) = oneshot::channel();
+                    let _ = handle.tx.send(RoomCmd::Unread { name, resp: tx }).await;
+                    if let Ok(count) = rx.await {
+                        unread.insert(room, count);
+                    }
+                }
+                let _ = resp.send((list, unread));
+            }
+            HubCmd::Register { user, session, feed } => {
+                let _ = self.directory.send(DirectCmd::Register { user, session, feed }).await;
+            }
+            HubCmd::Unregister { user, session } => {
+                let _ = self.directory.send(DirectCmd::Unregister { user, session }).await;
+            }
+            HubCmd::Presence { user, session, rooms } => {
+                let events = self.presence.touch(&user, &session, rooms, now_ms());
+                self.announce(events).await;
+            }
+            HubCmd::Offline { user, session } => {
+                let events = self.presence.disconnect(&user, &session, now_ms());
+                self.announce(events).await;
+            }
+            HubCmd::SetStatus { user, away, status, resp } => {
+                match self.presence.set_status(&user, away, status, now_ms()) {
+                    Ok(events) => {
+                        self.announce(events).await;
+                        let _ = resp.send(Ok(()));
+                    }
+                    Err(e) => {
+                        let _ = resp.send(Err(e));
+                    }
+                }
+            }
+            HubCmd::WhoIs { name, resp } => {
+                let rooms = &self.rooms;
+                let listed = |room: &str| rooms.get(room).is_some_and(|h| h.visibility == Visibility::Public);
+                let _ = resp.send(self.presence.who_is(&name, now_ms(), listed));
+            }
+            HubCmd::Direct { from, to, text, resp } => {
+                let _ = self.directory.send(DirectCmd::Send { from, to, text, resp }).await;
+            }
+            HubCmd::GetDirectHistory { user, with, query, resp } => {
+                let _ = self.directory.send(DirectCmd::History { user, with, query, resp }).await;
+            }
+            HubCmd::Shutdown { resp } => {
+                self.shutdown().await;
+                let _ = resp.send(());
             }
         }
     }
 }
 
+fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_stops_every_room() {
+        let hub = ChatHub::spawn();
+        for room in ["a", "b"] {
+            let (tx, rx) = oneshot::channel();
+            let join =
+                HubCmd::Join { room: room.into(), name: "alice".into(), verified: false, password: None, invite: None, resp: tx };
+            hub.send(join).await.unwrap();
+            rx.await.unwrap().unwrap();
+        }
+
+        let (tx, rx) = oneshot::channel();
+        hub.send(HubCmd::Shutdown { resp: tx }).await.unwrap();
+        rx.await.unwrap();
+        // the hub task has exited and dropped its receiver
+        hub.closed().await;
+    }
+
+    #[tokio::test]
+    async fn expired_rooms_start_again_on_join() {
+        let (hub, rx) = mpsc::channel(8);
+        let mut chat = ChatHub::new(rx);
+        chat.cfg.room_ttl_secs = 0;
+        tokio::spawn(async move { chat.run().await });
+        // alice owns the room, so she comes back authenticated
+        let join = || async {
+            let (tx, rx) = oneshot::channel();
+            let join =
+                HubCmd::Join { room: "a".into(), name: "alice".into(), verified: true, password: None, invite: None, resp: tx };
+            hub.send(join).await.unwrap();
+            rx.await.unwrap()
+        };
+
+        let membership = join().await.unwrap();
+        let leave = HubCmd::Leave { room: "a".into(), name: "alice".into(), reason: LeaveReason::Left };
+        hub.send(leave).await.unwrap();
+        drop(membership);
+        // the empty room expires on one of its next sweeps
+        tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+        assert!(join().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn only_authenticated_staff_skip_access_checks() {
+        let hub = ChatHub::spawn();
+        let room = format!("private-{}", crate::ids::unique_id());
+        let join = |verified| {
+            let (hub, room) = (hub.clone(), room.clone());
+            async move {
+                let (tx, rx) = oneshot::channel();
+                let join = HubCmd::Join { room, name: "alice".into(), verified, password: None, invite: None, resp: tx };
+                hub.send(join).await.unwrap();
+                rx.await.unwrap()
+            }
+        };
+
+        let membership = join(true).await.unwrap();
+        let (tx, rx) = oneshot::channel();
+        let action = ModAction::SetVisibility(Visibility::Private);
+        hub.send(HubCmd::Moderate { room: room.clone(), actor: "alice".into(), action, resp: tx }).await.unwrap();
+        rx.await.unwrap().unwrap();
+        hub.send(HubCmd::Leave { room: room.clone(), name: "alice".into(), reason: LeaveReason::Left }).await.unwrap();
+        drop(membership);
+
+        assert!(join(false).await.is_err());
+        assert!(join(true).await.is_ok());
+    }
+}
@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use once_cell::sync::Lazy;
+
+/// Process-unique id: boot time + pid + running counter, all hex.
+///
+/// Unique across rooms and restarts; used for message and session ids.
+pub fn unique_id() -> String {
+    static BOOT: Lazy<(u64, u32)> =
+        Lazy::new(|| (chrono::Utc::now().timestamp_millis() as u64, std::process::id()));
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{:x}", BOOT.0, BOOT.1, n)
+}
+
+/// `len` random bytes from the OS, URL-safe base64; for secrets such as
+/// salts and tokens.
+pub fn random_b64(len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    OsRng.fill_bytes(&mut buf);
+    B64.encode(buf)
+}
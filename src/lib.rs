@@ -1,8 +1,17 @@
+pub mod access;
 pub mod hub;
 pub mod protocol;
 pub mod server;
 pub mod client;
 pub mod config;
+pub mod direct;
 pub mod error;
+pub mod ids;
 pub mod memory_pool;
-pub mod room;
\ No newline at end of file
+pub mod metrics;
+pub mod moderation;
+pub mod presence;
+pub mod receipts;
+pub mod room;
+pub mod storage;
+pub mod tls;
\ No newline at end of file
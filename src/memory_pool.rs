@@ -56,7 +56,7 @@ pub struct MemoryPool {
 impl MemoryPool {
     /// Global singleton accessor.
     pub fn global() -> &'static MemoryPool {
-        static INSTANCE: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::default());
+        static INSTANCE: Lazy<MemoryPool> = Lazy::new(MemoryPool::default);
         &INSTANCE
     }
 
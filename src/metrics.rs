@@ -0,0 +1,73 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+use warp::Filter;
+
+/// Process-wide counters, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// times a room subscriber fell behind its broadcast channel
+    pub lag_events: AtomicU64,
+    /// broadcast frames skipped by lagging subscribers
+    pub lag_missed: AtomicU64,
+    /// connections closed by the `disconnect` lag policy
+    pub lag_disconnects: AtomicU64,
+    /// chat messages refused by the rate limiter
+    pub rate_limited: AtomicU64,
+    /// senders muted for repeated flooding
+    pub auto_mutes: AtomicU64,
+}
+
+impl Metrics {
+    /// Global singleton accessor.
+    pub fn global() -> &'static Metrics {
+        static INSTANCE: Lazy<Metrics> = Lazy::new(Metrics::default);
+        &INSTANCE
+    }
+
+    pub fn incr(counter: &AtomicU64, by: u64) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+
+    /// `name value` lines with `# HELP`/`# TYPE` headers.
+    pub fn render(&self) -> String {
+        let counters = [
+            ("chat_lag_events_total", "Room subscribers that fell behind", &self.lag_events),
+            ("chat_lag_missed_total", "Broadcast frames skipped by lagging subscribers", &self.lag_missed),
+            ("chat_lag_disconnects_total", "Connections dropped for lagging", &self.lag_disconnects),
+            ("chat_rate_limited_total", "Messages refused by the rate limiter", &self.rate_limited),
+            ("chat_auto_mutes_total", "Senders muted for flooding", &self.auto_mutes),
+        ];
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+/// Serve `GET /metrics` on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr) {
+    let route = warp::path("metrics")
+        .and(warp::get())
+        .map(|| Metrics::global().render());
+    warp::serve(route).run(addr).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counters() {
+        let m = Metrics::default();
+        Metrics::incr(&m.lag_missed, 7);
+        let text = m.render();
+        assert!(text.contains("# TYPE chat_lag_missed_total counter\nchat_lag_missed_total 7\n"));
+        assert!(text.contains("chat_lag_events_total 0\n"));
+    }
+}
@@ -0,0 +1,349 @@
+//! Room roles, bans, mutes and access settings.
+//!
+//! Each room task owns a [`RoomAcl`] and runs every moderation request
+//! through [`RoomAcl::apply`]. Roles, bans and access are saved with the room's
+//! storage so they outlive restarts and room expiry; mutes last only as long
+//! as the room task. Everything is keyed by member name; a name that holds a
+//! role is only given to a session authenticated as it.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::access::RoomAccess;
+use crate::error::ChatError;
+use crate::protocol::{Role, ServerEvent, Visibility};
+
+/// A moderation request as seen by the room; the actor travels alongside.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModAction {
+    Kick { name: String, reason: Option<String> },
+    Ban { name: String, duration: Option<u64>, reason: Option<String> },
+    Unban { name: String },
+    Mute { name: String, duration: Option<u64> },
+    Unmute { name: String },
+    SetRole { name: String, role: Role },
+    SetVisibility(Visibility),
+    SetPassword(Option<String>),
+    CreateInvite { ttl: Option<u64> },
+    RevokeInvite { token: String },
+}
+
+/// Outcome of an accepted [`ModAction`].
+#[derive(Debug)]
+pub struct Verdict {
+    /// announced to the whole room
+    pub event: Option<ServerEvent>,
+    /// answer for the actor alone
+    pub reply: Option<ServerEvent>,
+    /// member to evict from the room
+    pub remove: Option<String>,
+    /// roles or bans changed and should be saved
+    pub persist: bool,
+}
+
+/// Roles, bans and mutes of one room.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomAcl {
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub moderators: HashSet<String>,
+    /// name → end of the ban in ms since epoch; `None` is permanent
+    #[serde(default)]
+    pub bans: HashMap<String, Option<u64>>,
+    #[serde(default)]
+    pub access: RoomAccess,
+    /// name → end of the mute; never persisted
+    #[serde(skip)]
+    mutes: HashMap<String, Option<u64>>,
+}
+
+impl RoomAcl {
+    pub fn role(&self, name: &str) -> Role {
+        if self.owner.as_deref() == Some(name) {
+            Role::Owner
+        } else if self.moderators.contains(name) {
+            Role::Moderator
+        } else {
+            Role::Member
+        }
+    }
+
+    /// Whether `name` holds a role, and so may only be taken by a session
+    /// authenticated as it.
+    pub fn reserved(&self, name: &str) -> bool {
+        self.role(name) > Role::Member
+    }
+
+    /// Make `name` the owner if the room has none yet.
+    pub fn claim(&mut self, name: &str) -> bool {
+        if self.owner.is_some() {
+            return false;
+        }
+        self.owner = Some(name.to_string());
+        true
+    }
+
+    /// `Some(until)` while `name` is banned at `now`; expired bans are dropped.
+    pub fn banned(&mut self, name: &str, now: u64) -> Option<Option<u64>> {
+        active(&mut self.bans, name, now)
+    }
+
+    /// `Some(until)` while `name` is muted at `now`; expired mutes are dropped.
+    pub fn muted(&mut self, name: &str, now: u64) -> Option<Option<u64>> {
+        active(&mut self.mutes, name, now)
+    }
+
+    /// Carry a member's role and mute over to its new name. Returns whether
+    /// anything persisted changed.
+    pub fn rename(&mut self, old: &str, new: &str) -> bool {
+        if let Some(until) = self.mutes.remove(old) {
+            self.mutes.insert(new.to_string(), until);
+        }
+        if self.owner.as_deref() == Some(old) {
+            self.owner = Some(new.to_string());
+            true
+        } else if self.moderators.remove(old) {
+            self.moderators.insert(new.to_string());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Check `action` by `actor` against the room's roles and apply it.
+    /// `present` tells whether a name is currently in the room.
+    pub fn apply(
+        &mut self,
+        room: &str,
+        actor: &str,
+        action: ModAction,
+        present: impl Fn(&str) -> bool,
+        now: u64,
+    ) -> Result<Verdict, ChatError> {
+        let (room, by) = (room.to_string(), actor.to_string());
+        let verdict = match action {
+            ModAction::Kick { name, reason } => {
+                self.outranks(actor, &name)?;
+                if !present(&name) {
+                    return Err(ChatError::BadRequest(format!("{name} is not in {room}")));
+                }
+                let event = ServerEvent::UserKicked { room, name: name.clone(), by, reason };
+                Verdict { remove: Some(name), ..Verdict::announce(event, false) }
+            }
+            ModAction::Ban { name, duration, reason } => {
+                self.outranks(actor, &name)?;
+                let until = duration.map(|secs| deadline(now, secs));
+                self.bans.insert(name.clone(), until);
+                self.moderators.remove(&name);
+                let remove = present(&name).then(|| name.clone());
+                let event = ServerEvent::UserBanned { room, name, by, until, reason };
+                Verdict { remove, ..Verdict::announce(event, true) }
+            }
+            ModAction::Unban { name } => {
+                self.outranks(actor, &name)?;
+                if self.bans.remove(&name).is_none() {
+                    return Err(ChatError::BadRequest(format!("{name} is not banned")));
+                }
+                Verdict::announce(ServerEvent::UserUnbanned { room, name, by }, true)
+            }
+            ModAction::Mute { name, duration } => {
+                self.outranks(actor, &name)?;
+                let until = duration.map(|secs| deadline(now, secs));
+                self.mutes.insert(name.clone(), until);
+                Verdict::announce(ServerEvent::UserMuted { room, name, by, until }, false)
+            }
+            ModAction::Unmute { name } => {
+                self.outranks(actor, &name)?;
+                if self.muted(&name, now).is_none() {
+                    return Err(ChatError::BadRequest(format!("{name} is not muted")));
+                }
+                self.mutes.remove(&name);
+                Verdict::announce(ServerEvent::UserUnmuted { room, name, by }, false)
+            }
+            ModAction::SetRole { name, role } => {
+                self.owner_only(actor, "change roles")?;
+                if name == actor {
+                    return Err(ChatError::BadRequest("cannot change your own role".into()));
+                }
+                match role {
+                    Role::Member => {
+                        self.moderators.remove(&name);
+                    }
+                    Role::Moderator => {
+                        self.moderators.insert(name.clone());
+                    }
+                    Role::Owner => {
+                        // the previous owner stays on as a moderator
+                        self.moderators.remove(&name);
+                        self.moderators.insert(by.clone());
+                        self.owner = Some(name.clone());
+                    }
+                }
+                Verdict::announce(ServerEvent::RoleChanged { room, name, role, by }, true)
+            }
+            ModAction::SetVisibility(visibility) => {
+                self.owner_only(actor, "change room settings")?;
+                self.access.visibility = visibility;
+                Verdict::announce(self.updated(room, by), true)
+            }
+            ModAction::SetPassword(password) => {
+                self.owner_only(actor, "change room settings")?;
+                self.access.set_password(password.as_deref());
+                Verdict::announce(self.updated(room, by), true)
+            }
+            ModAction::CreateInvite { ttl } => {
+                self.owner_only(actor, "invite")?;
+                let (token, expires) = self.access.mint_invite(ttl, now);
+                let reply = ServerEvent::InviteCreated { room, token, expires };
+                Verdict { event: None, reply: Some(reply), remove: None, persist: true }
+            }
+            ModAction::RevokeInvite { token } => {
+                self.owner_only(actor, "revoke invites")?;
+                if !self.access.revoke_invite(&token) {
+                    return Err(ChatError::BadRequest("no such invite".into()));
+                }
+                Verdict { event: None, reply: None, remove: None, persist: true }
+            }
+        };
+        Ok(verdict)
+    }
+
+    fn updated(&self, room: String, by: String) -> ServerEvent {
+        let (visibility, password) = (self.access.visibility, self.access.has_password());
+        ServerEvent::RoomUpdated { room, visibility, password, by }
+    }
+
+    fn owner_only(&self, actor: &str, what: &str) -> Result<(), ChatError> {
+        if self.role(actor) != Role::Owner {
+            return Err(ChatError::Forbidden(format!("only the owner can {what}")));
+        }
+        Ok(())
+    }
+
+    /// Moderators and owners act on anyone ranked below them.
+    fn outranks(&self, actor: &str, target: &str) -> Result<(), ChatError> {
+        let role = self.role(actor);
+        if role < Role::Moderator {
+            return Err(ChatError::Forbidden("moderators only".into()));
+        }
+        if role <= self.role(target) {
+            return Err(ChatError::Forbidden(format!("{target} is not below you")));
+        }
+        Ok(())
+    }
+}
+
+impl Verdict {
+    /// Announce `event` to the room and nothing else.
+    fn announce(event: ServerEvent, persist: bool) -> Self {
+        Self { event: Some(event), reply: None, remove: None, persist }
+    }
+}
+
+/// Entry for `name` in a `name → until` map, unless it has expired.
+fn active(map: &mut HashMap<String, Option<u64>>, name: &str, now: u64) -> Option<Option<u64>> {
+    let until = *map.get(name)?;
+    if until.is_some_and(|t| t <= now) {
+        map.remove(name);
+        return None;
+    }
+    Some(until)
+}
+
+/// `secs` seconds after `now` (ms), saturating rather than wrapping.
+pub fn deadline(now: u64, secs: u64) -> u64 {
+    now.saturating_add(secs.saturating_mul(1000))
+}
+
+/// Whole seconds from `now` until `until` (ms), rounded up.
+pub fn secs_left(until: u64, now: u64) -> u64 {
+    until.saturating_sub(now).div_ceil(1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl() -> RoomAcl {
+        let mut acl = RoomAcl::default();
+        assert!(acl.claim("alice"));
+        assert!(!acl.claim("bob"));
+        acl.moderators.insert("mod".into());
+        acl
+    }
+
+    fn everyone(_: &str) -> bool {
+        true
+    }
+
+    #[test]
+    fn roles_rank_actions() {
+        let mut acl = acl();
+        let kick = |name: &str| ModAction::Kick { name: name.into(), reason: None };
+        assert!(matches!(acl.apply("r", "bob", kick("mod"), everyone, 0), Err(ChatError::Forbidden(_))));
+        assert!(matches!(acl.apply("r", "mod", kick("alice"), everyone, 0), Err(ChatError::Forbidden(_))));
+        assert!(matches!(acl.apply("r", "mod", kick("mod"), everyone, 0), Err(ChatError::Forbidden(_))));
+        let v = acl.apply("r", "mod", kick("bob"), everyone, 0).unwrap();
+        assert_eq!(v.remove.as_deref(), Some("bob"));
+        assert!(acl.apply("r", "mod", kick("ghost"), |_| false, 0).is_err());
+    }
+
+    #[test]
+    fn bans_expire() {
+        let mut acl = acl();
+        let ban = ModAction::Ban { name: "bob".into(), duration: Some(60), reason: None };
+        let v = acl.apply("r", "alice", ban, |_| false, 1_000).unwrap();
+        assert!(v.persist && v.remove.is_none());
+        assert_eq!(acl.banned("bob", 30_000), Some(Some(61_000)));
+        assert_eq!(secs_left(61_000, 30_000), 31);
+        assert_eq!(acl.banned("bob", 61_000), None);
+        assert!(acl.bans.is_empty());
+
+        let unban = ModAction::Unban { name: "bob".into() };
+        assert!(matches!(acl.apply("r", "alice", unban, everyone, 0), Err(ChatError::BadRequest(_))));
+
+        let ban = ModAction::Ban { name: "bob".into(), duration: Some(u64::MAX), reason: None };
+        acl.apply("r", "alice", ban, everyone, 1_000).unwrap();
+        assert_eq!(acl.banned("bob", 2_000), Some(Some(u64::MAX)));
+    }
+
+    #[test]
+    fn mutes_follow_renames() {
+        let mut acl = acl();
+        let mute = ModAction::Mute { name: "bob".into(), duration: None };
+        acl.apply("r", "mod", mute, everyone, 0).unwrap();
+        assert!(!acl.rename("bob", "robert"));
+        assert_eq!(acl.muted("robert", 1), Some(None));
+        assert_eq!(acl.muted("bob", 1), None);
+        // persisted state leaves mutes out
+        let json = serde_json::to_string(&acl).unwrap();
+        assert_eq!(serde_json::from_str::<RoomAcl>(&json).unwrap().muted("robert", 1), None);
+    }
+
+    #[test]
+    fn ownership_transfer() {
+        let mut acl = acl();
+        let promote = ModAction::SetRole { name: "bob".into(), role: Role::Owner };
+        assert!(acl.apply("r", "mod", promote.clone(), everyone, 0).is_err());
+        acl.apply("r", "alice", promote, everyone, 0).unwrap();
+        assert_eq!(acl.role("bob"), Role::Owner);
+        assert_eq!(acl.role("alice"), Role::Moderator);
+    }
+
+    #[test]
+    fn only_owner_configures_access() {
+        let mut acl = acl();
+        let private = ModAction::SetVisibility(Visibility::Private);
+        assert!(matches!(acl.apply("r", "mod", private.clone(), everyone, 0), Err(ChatError::Forbidden(_))));
+        let v = acl.apply("r", "alice", private, everyone, 0).unwrap();
+        assert!(matches!(v.event, Some(ServerEvent::RoomUpdated { visibility: Visibility::Private, password: false, .. })));
+
+        let v = acl.apply("r", "alice", ModAction::CreateInvite { ttl: None }, everyone, 0).unwrap();
+        assert!(v.event.is_none() && v.persist);
+        let Some(ServerEvent::InviteCreated { token, .. }) = v.reply else { panic!("no invite") };
+        assert!(acl.access.admit("r", None, Some(&token), 0).is_ok());
+        acl.apply("r", "alice", ModAction::RevokeInvite { token: token.clone() }, everyone, 0).unwrap();
+        assert!(acl.access.admit("r", None, Some(&token), 0).is_err());
+    }
+}
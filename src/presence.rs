@@ -0,0 +1,242 @@
+//! Who is online, across every room.
+//!
+//! The hub keeps a [`PresenceRegistry`] of the users with at least one live
+//! connection: the rooms each connection is in and under which name, when
+//! the user last sent anything and the status they set. Whenever what others
+//! see changes — online, away, idle or offline, the status text, or the
+//! number of connections — every room the user is in gets `PresenceChanged`;
+//! a room the user just joined gets it too.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::config::Config;
+use crate::error::ChatError;
+use crate::protocol::{PresenceState, ServerEvent};
+
+/// What rooms were last told about a user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Shown {
+    state: PresenceState,
+    status: Option<String>,
+    connections: usize,
+}
+
+#[derive(Default)]
+struct UserPresence {
+    /// session id → room → the user's name there
+    sessions: HashMap<String, HashMap<String, String>>,
+    /// ms since epoch
+    last_active: u64,
+    away: bool,
+    status: Option<String>,
+    shown: Option<Shown>,
+    /// `(room, name)` pairs `shown` went to
+    shown_in: BTreeSet<(String, String)>,
+}
+
+impl UserPresence {
+    fn state(&self, now: u64, idle_after: u64) -> PresenceState {
+        if self.sessions.is_empty() {
+            PresenceState::Offline
+        } else if self.away {
+            PresenceState::Away
+        } else if idle_after > 0 && now.saturating_sub(self.last_active) >= idle_after {
+            PresenceState::Idle
+        } else {
+            PresenceState::Online
+        }
+    }
+
+    fn rooms(&self) -> BTreeSet<(String, String)> {
+        self.sessions.values().flatten().map(|(room, name)| (room.clone(), name.clone())).collect()
+    }
+
+    /// `PresenceChanged` for every room that has not seen the current state.
+    fn announce(&mut self, now: u64, idle_after: u64) -> Vec<ServerEvent> {
+        let shown = Shown {
+            state: self.state(now, idle_after),
+            status: self.status.clone(),
+            connections: self.sessions.len(),
+        };
+        let rooms = self.rooms();
+        let targets: Vec<_> = if shown.state == PresenceState::Offline {
+            // the rooms it was in still list the member
+            self.shown_in.iter().cloned().collect()
+        } else if self.shown.as_ref() != Some(&shown) {
+            rooms.iter().cloned().collect()
+        } else {
+            rooms.difference(&self.shown_in).cloned().collect()
+        };
+        let events = targets
+            .into_iter()
+            .map(|(room, name)| ServerEvent::PresenceChanged {
+                room,
+                name,
+                state: shown.state,
+                status: shown.status.clone(),
+                connections: shown.connections,
+            })
+            .collect();
+        self.shown = Some(shown);
+        self.shown_in = rooms;
+        events
+    }
+}
+
+/// Presence of every connected user; owned by the hub task.
+pub struct PresenceRegistry {
+    /// ms without activity before a user shows as idle; 0 never
+    idle_after: u64,
+    users: HashMap<String, UserPresence>,
+}
+
+impl PresenceRegistry {
+    pub fn new(cfg: &Config) -> Self {
+        Self { idle_after: cfg.idle_secs * 1000, users: HashMap::new() }
+    }
+
+    /// Connection `session` of `user` did something and is in `rooms`
+    /// (room → name). Returns the announcements to deliver.
+    pub fn touch(&mut self, user: &str, session: &str, rooms: HashMap<String, String>, now: u64) -> Vec<ServerEvent> {
+        let presence = self.users.entry(user.to_string()).or_default();
+        presence.sessions.insert(session.to_string(), rooms);
+        presence.last_active = now;
+        presence.announce(now, self.idle_after)
+    }
+
+    /// Connection `session` of `user` closed.
+    pub fn disconnect(&mut self, user: &str, session: &str, now: u64) -> Vec<ServerEvent> {
+        let Some(presence) = self.users.get_mut(user) else { return Vec::new() };
+        presence.sessions.remove(session);
+        let events = presence.announce(now, self.idle_after);
+        if presence.sessions.is_empty() {
+            // status and away end with the last connection
+            self.users.remove(user);
+        }
+        events
+    }
+
+    /// Mark `user` away or back and set or clear their status text.
+    pub fn set_status(
+        &mut self,
+        user: &str,
+        away: bool,
+        status: Option<String>,
+        now: u64,
+    ) -> Result<Vec<ServerEvent>, ChatError> {
+        let presence = self
+            .users
+            .get_mut(user)
+            .ok_or_else(|| ChatError::BadRequest("join a room or authenticate first".into()))?;
+        presence.away = away;
+        presence.status = status;
+        presence.last_active = now;
+        Ok(presence.announce(now, self.idle_after))
+    }
+
+    /// Announce users who went idle since the last sweep.
+    pub fn sweep(&mut self, now: u64) -> Vec<ServerEvent> {
+        let idle_after = self.idle_after;
+        self.users.values_mut().flat_map(|presence| presence.announce(now, idle_after)).collect()
+    }
+
+    /// `UserInfo` for `name`, listing only the rooms `listed` lets through.
+    pub fn who_is(&self, name: &str, now: u64, listed: impl Fn(&str) -> bool) -> ServerEvent {
+        let Some(presence) = self.users.get(name) else {
+            return ServerEvent::UserInfo {
+                name: name.to_string(),
+                state: PresenceState::Offline,
+                status: None,
+                connections: 0,
+                last_active: None,
+                rooms: Vec::new(),
+            };
+        };
+        let rooms: BTreeSet<&str> = presence.sessions.values().flat_map(|rooms| rooms.keys()).map(String::as_str).collect();
+        ServerEvent::UserInfo {
+            name: name.to_string(),
+            state: presence.state(now, self.idle_after),
+            status: presence.status.clone(),
+            connections: presence.sessions.len(),
+            last_active: Some(presence.last_active),
+            rooms: rooms.into_iter().filter(|room| listed(room)).map(str::to_string).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> PresenceRegistry {
+        PresenceRegistry::new(&Config { idle_secs: 60, ..Config::default() })
+    }
+
+    fn rooms(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(room, name)| (room.to_string(), name.to_string())).collect()
+    }
+
+    /// `(room, state, connections)` of each announcement, sorted.
+    fn summary(events: Vec<ServerEvent>) -> Vec<(String, PresenceState, usize)> {
+        let mut out: Vec<_> = events
+            .into_iter()
+            .map(|ev| match ev {
+                ServerEvent::PresenceChanged { room, state, connections, .. } => (room, state, connections),
+                other => panic!("unexpected {other:?}"),
+            })
+            .collect();
+        out.sort_by(|a, b| a.0.cmp(&b.0));
+        out
+    }
+
+    #[test]
+    fn announces_changes_to_the_users_rooms() {
+        let mut reg = registry();
+        let on = PresenceState::Online;
+        assert_eq!(summary(reg.touch("bob", "s1", rooms(&[("rust", "bob")]), 0)), vec![("rust".into(), on, 1)]);
+        // activity alone changes nothing others see
+        assert!(reg.touch("bob", "s1", rooms(&[("rust", "bob")]), 1_000).is_empty());
+        // a second connection joining another room: both rooms hear of it
+        assert_eq!(
+            summary(reg.touch("bob", "s2", rooms(&[("go", "bobby")]), 2_000)),
+            vec![("go".into(), on, 2), ("rust".into(), on, 2)]
+        );
+
+        let events = reg.set_status("bob", true, Some("lunch".into()), 3_000).unwrap();
+        assert!(events.iter().all(|ev| matches!(
+            ev,
+            ServerEvent::PresenceChanged { state: PresenceState::Away, status: Some(s), .. } if s == "lunch"
+        )));
+        assert_eq!(events.len(), 2);
+
+        reg.disconnect("bob", "s2", 4_000);
+        assert_eq!(
+            summary(reg.disconnect("bob", "s1", 5_000)),
+            vec![("rust".into(), PresenceState::Offline, 0)]
+        );
+        assert!(reg.set_status("bob", false, None, 6_000).is_err());
+    }
+
+    #[test]
+    fn idle_users_and_who_is() {
+        let mut reg = registry();
+        reg.touch("alice", "s1", rooms(&[("rust", "alice"), ("staff", "alice")]), 0);
+        assert!(reg.sweep(59_999).is_empty());
+        assert_eq!(summary(reg.sweep(60_000)).len(), 2);
+        assert!(reg.sweep(70_000).is_empty());
+
+        match reg.who_is("alice", 70_000, |room| room != "staff") {
+            ServerEvent::UserInfo { state, connections, last_active, rooms, .. } => {
+                assert_eq!(state, PresenceState::Idle);
+                assert_eq!(connections, 1);
+                assert_eq!(last_active, Some(0));
+                assert_eq!(rooms, vec!["rust".to_string()]);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(matches!(
+            reg.who_is("nobody", 0, |_| true),
+            ServerEvent::UserInfo { state: PresenceState::Offline, last_active: None, .. }
+        ));
+    }
+}
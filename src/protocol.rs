@@ -1,18 +1,274 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+/// Wire protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version still served. Clients that never send `Hello` are
+/// treated as this version and only receive events it understands.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features this server offers in `Welcome`.
+pub const SERVER_CAPABILITIES: &[&str] = &["request_ids", "message_ids", "history_paging", "auth", "resume"];
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum ClientRequest {
 
-    Join { room: String, name: String },
+    /// Opening handshake; must precede `Join` to get anything beyond v1.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+
+    /// Authenticate this connection; the verified user name then replaces
+    /// the `name` given in `Join`.
+    Auth(Credentials),
+
+    /// Take over a dropped session with the token from `SessionResumable`;
+    /// must follow `Hello` and precede anything else. Events after message
+    /// `last_seen_id` (all buffered ones when `None`) are delivered again.
+    Resume {
+        token: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen_id: Option<String>,
+    },
+
+    Join {
+        room: String,
+        name: String,
+        /// History replayed right after joining; defaults to everything retained.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        replay: Option<Replay>,
+        /// for rooms with a join password
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        /// invite token; admits to private rooms and skips the password
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        invite: Option<String>,
+    },
 
     Leave { room: String },
 
-    Message { room: String, text: String },
+    /// Change this connection's name in `room`; fails if the name is taken.
+    Rename { room: String, name: String },
+
+    /// Post `text` to `room`, as a threaded reply to message `reply_to` if set.
+    Message {
+        room: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<String>,
+    },
 
     RoomList,
 
     Members { room: String },
+
+    /// Fetch up to `limit` messages older than `before` (newest page when
+    /// `None`); only thread `thread`, root included, if set.
+    History {
+        room: String,
+        before: Option<u64>,
+        limit: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thread: Option<String>,
+    },
+
+    /// Replace the text of message `id`. Only its author or a moderator may.
+    EditMessage { room: String, id: String, text: String },
+
+    /// Blank out message `id`, leaving a tombstone. Only its author or a
+    /// moderator may.
+    DeleteMessage { room: String, id: String },
+
+    /// Say this member started or stopped composing in `room`. `Started`
+    /// has to be repeated while typing or it expires.
+    Typing { room: String, state: TypingState },
+
+    /// This member has read message `up_to_id` of `room` and everything
+    /// before it. With `receipt` the other members get a `ReadReceipt`.
+    MarkRead {
+        room: String,
+        up_to_id: String,
+        #[serde(default, skip_serializing_if = "is_false")]
+        receipt: bool,
+    },
+
+    /// Add this member's `emoji` reaction to a message.
+    React { room: String, message_id: String, emoji: String },
+
+    Unreact { room: String, message_id: String, emoji: String },
+
+    /// Remove `name` from `room`. Moderation requests need a role above the
+    /// target's.
+    Kick {
+        room: String,
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+
+    /// Kick `name` and refuse it for `duration` seconds (for good when `None`).
+    Ban {
+        room: String,
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+
+    Unban { room: String, name: String },
+
+    /// Silence `name` in `room` for `duration` seconds (until `Unmute` when `None`).
+    Mute {
+        room: String,
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u64>,
+    },
+
+    Unmute { room: String, name: String },
+
+    /// Owner only. Making someone else `owner` hands the room over.
+    SetRole { room: String, name: String, role: Role },
+
+    /// Owner only: who can find and enter the room.
+    SetVisibility { room: String, visibility: Visibility },
+
+    /// Owner only: require a password to join, or drop it with `None`.
+    SetPassword { room: String, password: Option<String> },
+
+    /// Owner only: mint an invite valid for `ttl` seconds (until revoked when
+    /// `None`); answered with `InviteCreated`.
+    CreateInvite {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+
+    /// Owner only.
+    RevokeInvite { room: String, token: String },
+
+    /// Private message to user `to`, delivered to every connection of theirs
+    /// and queued while they have none.
+    DirectMessage { to: String, text: String },
+
+    /// Fetch up to `limit` direct messages exchanged with `with`, older than
+    /// `before` (newest page when `None`).
+    DirectHistory { with: String, before: Option<u64>, limit: usize },
+
+    /// Show this user as away or back, with an optional status text; both
+    /// last until changed or the user's last connection closes.
+    SetStatus {
+        away: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<String>,
+    },
+
+    /// Ask for the presence of user `name`; answered with `UserInfo`.
+    WhoIs { name: String },
+}
+
+/// Who can find and enter a room.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// listed in `RoomList`, open to anyone
+    #[default]
+    Public,
+    /// hidden from `RoomList`, open to anyone who knows the name
+    Unlisted,
+    /// hidden, and only staff or invite holders may join
+    Private,
+}
+
+/// Whether a member is composing a message.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TypingState {
+    Started,
+    Stopped,
+}
+
+/// Why a member left a room.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaveReason {
+    /// sent `Leave`
+    Left,
+    /// the connection closed or failed, or a dropped session was not resumed
+    Disconnected,
+    /// stopped answering pings, or idle too long
+    TimedOut,
+}
+
+/// Whether a user is around, server-wide.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Online,
+    /// set by the user
+    Away,
+    /// connected but inactive for a while
+    Idle,
+    Offline,
+}
+
+/// A member's standing in a room, lowest first.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
+
+/// Proof of identity accepted by `ClientRequest::Auth`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub enum Credentials {
+    /// checked against the server's local user file
+    Password { user: String, password: String },
+    /// signed token, also accepted as an `Authorization: Bearer` upgrade header
+    Bearer { token: String },
+}
+
+/// A request plus its optional client-chosen correlation `id`.
+///
+/// On the wire the id sits next to the variant key, e.g.
+/// `{"id":"7","Message":{"room":"rust","text":"hi"}}`. A bare request
+/// without any id (including `"RoomList"`) is accepted as well.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct ClientFrame {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub req: ClientRequest,
+}
+
+impl ClientFrame {
+    /// Parse a text frame, falling back to a bare `ClientRequest`.
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        match serde_json::from_str::<ClientFrame>(text) {
+            Ok(frame) => Ok(frame),
+            Err(e) => serde_json::from_str::<ClientRequest>(text)
+                .map(|req| ClientFrame { id: None, req })
+                .map_err(|_| e),
+        }
+    }
+}
+
+/// What a client wants replayed when it joins a room.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum Replay {
+    /// the most recent N messages
+    Last(usize),
+    /// nothing; the client pages with `History` instead
+    None,
+    /// every retained message with `ts` (ms since epoch) at or after this
+    Since(u64),
 }
 
 
@@ -21,13 +277,277 @@ pub enum ServerEvent {
 
     UserJoined { room: String, name: String },
 
-    UserLeft { room: String, name: String },
+    /// `reason` is absent for protocol v1.
+    UserLeft {
+        room: String,
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<LeaveReason>,
+    },
 
-    NewMessage { room: String, name: String, text: String, ts: u64 },
+    /// `seq` increases monotonically per room and doubles as the `History`
+    /// cursor; `id` is unique across rooms and restarts. Both are assigned
+    /// by the room, so clients can de-duplicate on reconnect.
+    NewMessage {
+        room: String,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        id: String,
+        name: String,
+        text: String,
+        ts: u64,
+        /// when the text was last edited (ms since epoch)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        edited: Option<u64>,
+        /// a tombstone; `text` is empty
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        deleted: bool,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        reactions: Vec<Reaction>,
+        /// the message this one answers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<String>,
+        /// id of the thread's first message; set on every reply
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thread_id: Option<String>,
+        /// replies in the thread this message starts
+        #[serde(default, skip_serializing_if = "is_zero")]
+        replies: u64,
+    },
 
-    RoomList { rooms: Vec<String> },
+    /// `unread` counts the messages this connection has not read in each
+    /// room it is in; absent for v1 sessions.
+    RoomList {
+        rooms: Vec<String>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        unread: BTreeMap<String, u64>,
+    },
 
     MemberList { room: String, members: Vec<String> },
+
+    /// Reply to `History`, oldest first. `next_before` is the cursor for the
+    /// next older page, `None` once history is exhausted.
+    HistoryPage {
+        room: String,
+        messages: Vec<ServerEvent>,
+        next_before: Option<u64>,
+        /// the thread asked for, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thread: Option<String>,
+    },
+
+    /// A request carrying `id` completed successfully.
+    Ack { id: String },
+
+    /// A request failed; `id` echoes the request's id when it had one.
+    /// `retry_after` (seconds) accompanies `rate_limited`.
+    Error {
+        id: Option<String>,
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+
+    /// Reply to `Hello` with the negotiated version and shared capabilities.
+    Welcome { version: u32, server_capabilities: Vec<String>, session_id: String },
+
+    /// The connection is now authenticated as `name`.
+    Authenticated { name: String },
+
+    /// Sent after the first `Join`, and again after each `Resume`: if the
+    /// connection drops, `Resume` with `token` within `grace_secs` picks the
+    /// session up with its rooms, and nobody sees it leave.
+    SessionResumable { token: String, grace_secs: u64 },
+
+    /// Reply to `Resume`: this connection is now the dropped session, a
+    /// member of `rooms`. The missed events follow.
+    Resumed { session_id: String, rooms: Vec<String> },
+
+    /// A member of `room` changed its name.
+    UserRenamed { room: String, old: String, new: String },
+
+    /// The server is going away; the connection closes right after.
+    /// `reconnect_after` is a suggested delay in seconds.
+    ServerShutdown { reason: String, reconnect_after: Option<u64> },
+
+    /// This connection fell behind and `missed` messages of `room` were
+    /// skipped. The next frame from the room is the first one after the gap;
+    /// `History` with `before` set to its `seq` fills the hole.
+    Gap { room: String, missed: u64 },
+
+    /// `name` was removed from `room` by `by`.
+    UserKicked { room: String, name: String, by: String, reason: Option<String> },
+
+    /// `name` was removed and may not rejoin before `until` (ms since epoch;
+    /// `None` = permanently).
+    UserBanned { room: String, name: String, by: String, until: Option<u64>, reason: Option<String> },
+
+    UserUnbanned { room: String, name: String, by: String },
+
+    /// `name` may not send messages in `room` before `until` (`None` = until unmuted).
+    UserMuted { room: String, name: String, by: String, until: Option<u64> },
+
+    UserUnmuted { room: String, name: String, by: String },
+
+    /// `name` now holds `role` in `room`. The first member of a new room
+    /// becomes its owner.
+    RoleChanged { room: String, name: String, role: Role, by: String },
+
+    /// The room's access settings changed.
+    RoomUpdated { room: String, visibility: Visibility, password: bool, by: String },
+
+    /// Reply to `CreateInvite`, sent only to the owner who asked.
+    InviteCreated { room: String, token: String, expires: Option<u64> },
+
+    /// A private message, sent to the recipient and echoed to the sender.
+    /// `seq` counts per conversation and is the `DirectHistory` cursor.
+    DirectMessage { id: String, seq: u64, from: String, to: String, text: String, ts: u64 },
+
+    /// Message `id` (`seq` in history) of `room` now reads `text`. History
+    /// already holds the new version.
+    MessageEdited { room: String, id: String, seq: u64, text: String, by: String, ts: u64 },
+
+    /// Message `id` of `room` was replaced by a tombstone.
+    MessageDeleted { room: String, id: String, seq: u64, by: String },
+
+    /// The reactions on message `message_id` changed; `reactions` is the
+    /// full new set, as also stored in history.
+    ReactionUpdated { room: String, message_id: String, seq: u64, reactions: Vec<Reaction> },
+
+    /// `name` started or stopped typing in `room`. Never stored; a message
+    /// from `name` also ends it.
+    UserTyping { room: String, name: String, state: TypingState },
+
+    /// Member `name` of `room` has read up to message `up_to_id` (`seq` in
+    /// history). Sent only when the reader asks for it; never stored.
+    ReadReceipt { room: String, name: String, up_to_id: String, seq: u64 },
+
+    /// Member `name` of `room` went online, away, idle or offline, changed
+    /// their status text, or opened or closed a connection.
+    PresenceChanged {
+        room: String,
+        name: String,
+        state: PresenceState,
+        status: Option<String>,
+        connections: usize,
+    },
+
+    /// Reply to `WhoIs`. `rooms` lists only public rooms; `last_active` is
+    /// ms since epoch, `None` when offline.
+    UserInfo {
+        name: String,
+        state: PresenceState,
+        status: Option<String>,
+        connections: usize,
+        last_active: Option<u64>,
+        rooms: Vec<String>,
+    },
+
+    /// Reply to `DirectHistory`, oldest first; paged like `HistoryPage`.
+    DirectHistoryPage { with: String, messages: Vec<ServerEvent>, next_before: Option<u64> },
+}
+
+impl ServerEvent {
+    /// Protocol version that introduced this event; older sessions never see it.
+    pub fn since_version(&self) -> u32 {
+        match self {
+            ServerEvent::UserJoined { .. }
+            | ServerEvent::UserLeft { .. }
+            | ServerEvent::NewMessage { .. }
+            | ServerEvent::RoomList { .. }
+            | ServerEvent::MemberList { .. } => 1,
+            ServerEvent::HistoryPage { .. }
+            | ServerEvent::Ack { .. }
+            | ServerEvent::Error { .. }
+            | ServerEvent::Welcome { .. }
+            | ServerEvent::Authenticated { .. }
+            | ServerEvent::SessionResumable { .. }
+            | ServerEvent::Resumed { .. }
+            | ServerEvent::UserRenamed { .. }
+            | ServerEvent::ServerShutdown { .. }
+            | ServerEvent::Gap { .. }
+            | ServerEvent::UserKicked { .. }
+            | ServerEvent::UserBanned { .. }
+            | ServerEvent::UserUnbanned { .. }
+            | ServerEvent::UserMuted { .. }
+            | ServerEvent::UserUnmuted { .. }
+            | ServerEvent::RoleChanged { .. }
+            | ServerEvent::RoomUpdated { .. }
+            | ServerEvent::InviteCreated { .. }
+            | ServerEvent::DirectMessage { .. }
+            | ServerEvent::DirectHistoryPage { .. }
+            | ServerEvent::MessageEdited { .. }
+            | ServerEvent::MessageDeleted { .. }
+            | ServerEvent::ReactionUpdated { .. }
+            | ServerEvent::UserTyping { .. }
+            | ServerEvent::ReadReceipt { .. }
+            | ServerEvent::PresenceChanged { .. }
+            | ServerEvent::UserInfo { .. } => 2,
+        }
+    }
+
+    /// `text` from `name` in `room` as a session hands it to the room,
+    /// before the room stamps `seq` and `id`.
+    #[cfg(test)]
+    pub(crate) fn message(room: &str, name: &str, text: &str, reply_to: Option<&str>) -> Self {
+        ServerEvent::NewMessage {
+            room: room.into(),
+            seq: 0,
+            id: String::new(),
+            name: name.into(),
+            text: text.into(),
+            ts: 1,
+            edited: None,
+            deleted: false,
+            reactions: Vec::new(),
+            reply_to: reply_to.map(str::to_string),
+            thread_id: None,
+            replies: 0,
+        }
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Everyone who reacted to a message with one emoji, in order.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub names: Vec<String>,
+}
+
+/// Stable, machine-readable error codes carried by `ServerEvent::Error`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// malformed JSON or an unknown request
+    BadRequest,
+    UnknownRoom,
+    NotMember,
+    /// the requested name is already used in the room
+    NameTaken,
+    /// the client's protocol version is below `MIN_PROTOCOL_VERSION`
+    UnsupportedVersion,
+    /// missing or invalid credentials
+    Unauthorized,
+    /// sending too fast, or muted for flooding; see `retry_after`
+    RateLimited,
+    /// a room name, nickname or message broke the server's limits
+    InvalidInput,
+    /// the request needs a higher room role, or the sender is muted
+    Forbidden,
+    /// banned from the room; `retry_after` is set for temporary bans
+    Banned,
+    Internal,
 }
 
 #[cfg(test)]
@@ -40,21 +560,236 @@ mod tests {
         let req = ClientRequest::Join {
             room: "rust".into(),
             name: "alice".into(),
+            replay: Some(Replay::Last(20)),
+            password: Some("pw".into()),
+            invite: None,
         };
         let json = serde_json::to_string(&req).unwrap();
         assert_eq!(serde_json::from_str::<ClientRequest>(&json).unwrap(), req);
     }
 
+    #[test]
+    fn join_without_replay() {
+        let json = r#"{"Join":{"room":"rust","name":"alice"}}"#;
+        let req = serde_json::from_str::<ClientRequest>(json).unwrap();
+        assert_eq!(
+            req,
+            ClientRequest::Join { room: "rust".into(), name: "alice".into(), replay: None, password: None, invite: None }
+        );
+        assert_eq!(serde_json::to_string(&req).unwrap(), json);
+    }
+
+    #[test]
+    fn serialize_history_page() {
+        let ev = ServerEvent::HistoryPage {
+            room: "rust".into(),
+            messages: vec![ServerEvent::NewMessage {
+                room: "rust".into(),
+                seq: 6,
+                id: "18c2f-6".into(),
+                name: "bob".into(),
+                text: "hi".into(),
+                ts: 1,
+                edited: Some(2),
+                deleted: false,
+                reactions: Vec::new(),
+                reply_to: None,
+                thread_id: Some("18c2f-5".into()),
+                replies: 0,
+            }],
+            next_before: Some(7),
+            thread: Some("18c2f-5".into()),
+        };
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+    }
+
     #[test]
     fn serialize_new_msg() {
         let ev = ServerEvent::NewMessage {
             room: "rust".into(),
+            seq: 42,
+            id: "18c2f-2a".into(),
             name: "bob".into(),
             text: "hello".into(),
             ts: 123,
+            edited: None,
+            deleted: false,
+            reactions: Vec::new(),
+            reply_to: None,
+            thread_id: None,
+            replies: 0,
+        };
+        let json = serde_json::to_string(&ev).unwrap();
+        // plain messages look as they always did
+        for field in ["edited", "deleted", "reactions", "reply_to", "thread_id", "replies"] {
+            assert!(!json.contains(field), "{json}");
+        }
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+    }
+
+    #[test]
+    fn new_msg_without_ids() {
+        // frames persisted before ids existed still decode
+        let json = r#"{"NewMessage":{"room":"rust","name":"bob","text":"hi","ts":1}}"#;
+        match serde_json::from_str::<ServerEvent>(json).unwrap() {
+            ServerEvent::NewMessage { seq, id, .. } => {
+                assert_eq!(seq, 0);
+                assert!(id.is_empty());
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn frame_with_id() {
+        let frame = ClientFrame {
+            id: Some("7".into()),
+            req: ClientRequest::Members { room: "rust".into() },
+        };
+        let json = serde_json::to_string(&frame).unwrap();
+        assert_eq!(json, r#"{"id":"7","Members":{"room":"rust"}}"#);
+        assert_eq!(ClientFrame::parse(&json).unwrap(), frame);
+    }
+
+    #[test]
+    fn frame_accepts_bare_request() {
+        let frame = ClientFrame::parse(r#""RoomList""#).unwrap();
+        assert_eq!(frame, ClientFrame { id: None, req: ClientRequest::RoomList });
+        let frame = ClientFrame::parse(r#"{"Leave":{"room":"rust"}}"#).unwrap();
+        assert_eq!(frame.req, ClientRequest::Leave { room: "rust".into() });
+        assert!(ClientFrame::parse("{not json").is_err());
+    }
+
+    #[test]
+    fn serialize_error() {
+        let ev = ServerEvent::Error {
+            id: Some("7".into()),
+            code: ErrorCode::NotMember,
+            message: "not a member of rust".into(),
+            retry_after: None,
+        };
+        let json = serde_json::to_string(&ev).unwrap();
+        assert!(json.contains(r#""code":"not_member""#));
+        assert!(!json.contains("retry_after"));
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+
+        let json = r#"{"Error":{"id":null,"code":"rate_limited","message":"slow down","retry_after":2}}"#;
+        match serde_json::from_str::<ServerEvent>(json).unwrap() {
+            ServerEvent::Error { code, retry_after, .. } => {
+                assert_eq!(code, ErrorCode::RateLimited);
+                assert_eq!(retry_after, Some(2));
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn hello_defaults_capabilities() {
+        let req = serde_json::from_str::<ClientRequest>(r#"{"Hello":{"version":2}}"#).unwrap();
+        assert_eq!(req, ClientRequest::Hello { version: 2, capabilities: vec![] });
+    }
+
+    #[test]
+    fn serialize_welcome() {
+        let ev = ServerEvent::Welcome {
+            version: PROTOCOL_VERSION,
+            server_capabilities: vec!["request_ids".into()],
+            session_id: "abc".into(),
+        };
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+        assert_eq!(ev.since_version(), 2);
+    }
+
+    #[test]
+    fn serialize_auth() {
+        let req = ClientRequest::Auth(Credentials::Bearer { token: "t".into() });
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"Auth":{"Bearer":{"token":"t"}}}"#);
+        assert_eq!(serde_json::from_str::<ClientRequest>(&json).unwrap(), req);
+    }
+
+    #[test]
+    fn serialize_rename() {
+        let req = ClientRequest::Rename { room: "rust".into(), name: "carol".into() };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"Rename":{"room":"rust","name":"carol"}}"#);
+        assert_eq!(serde_json::from_str::<ClientRequest>(&json).unwrap(), req);
+
+        let ev = ServerEvent::UserRenamed { room: "rust".into(), old: "bob".into(), new: "carol".into() };
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+        assert_eq!(ev.since_version(), 2);
+    }
+
+    #[test]
+    fn serialize_server_shutdown() {
+        let ev = ServerEvent::ServerShutdown { reason: "restart".into(), reconnect_after: Some(5) };
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(json, r#"{"ServerShutdown":{"reason":"restart","reconnect_after":5}}"#);
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+    }
+
+    #[test]
+    fn serialize_moderation() {
+        let json = r#"{"Ban":{"room":"rust","name":"troll","duration":600}}"#;
+        let req = serde_json::from_str::<ClientRequest>(json).unwrap();
+        assert_eq!(
+            req,
+            ClientRequest::Ban { room: "rust".into(), name: "troll".into(), duration: Some(600), reason: None }
+        );
+        assert_eq!(serde_json::to_string(&req).unwrap(), json);
+
+        let req = ClientRequest::SetRole { room: "rust".into(), name: "bob".into(), role: Role::Moderator };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"SetRole":{"room":"rust","name":"bob","role":"moderator"}}"#);
+
+        let ev = ServerEvent::UserKicked {
+            room: "rust".into(),
+            name: "troll".into(),
+            by: "alice".into(),
+            reason: Some("spam".into()),
+        };
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+        assert!(Role::Owner > Role::Moderator && Role::Moderator > Role::Member);
+    }
+
+    #[test]
+    fn serialize_access() {
+        let req = ClientRequest::SetVisibility { room: "rust".into(), visibility: Visibility::Unlisted };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"SetVisibility":{"room":"rust","visibility":"unlisted"}}"#);
+        let json = r#"{"Join":{"room":"rust","name":"bob","invite":"inv_x"}}"#;
+        match serde_json::from_str::<ClientRequest>(json).unwrap() {
+            ClientRequest::Join { invite, password, .. } => {
+                assert_eq!(invite.as_deref(), Some("inv_x"));
+                assert_eq!(password, None);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        let ev = ServerEvent::InviteCreated { room: "rust".into(), token: "inv_x".into(), expires: None };
+        let json = serde_json::to_string(&ev).unwrap();
+        assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+    }
+
+    #[test]
+    fn serialize_direct() {
+        let req = ClientRequest::DirectMessage { to: "bob".into(), text: "psst".into() };
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(json, r#"{"DirectMessage":{"to":"bob","text":"psst"}}"#);
+        let ev = ServerEvent::DirectMessage {
+            id: "18c2f-7".into(),
+            seq: 0,
+            from: "alice".into(),
+            to: "bob".into(),
+            text: "psst".into(),
+            ts: 1,
         };
         let json = serde_json::to_string(&ev).unwrap();
         assert_eq!(serde_json::from_str::<ServerEvent>(&json).unwrap(), ev);
+        assert_eq!(ev.since_version(), 2);
     }
 
     #[test]
@@ -0,0 +1,84 @@
+//! Read cursors and unread counts.
+//!
+//! Each room task owns the [`ReadCursors`] of its members: per name, the
+//! `seq` of the first message they have not read yet. A cursor starts at
+//! the end of history when a name first joins, moves forward on `MarkRead`
+//! and past every message the member posts, and never moves back. Unread
+//! counts are the messages stored since that have not been deleted. Cursors are saved with the room's
+//! storage; like roles they are keyed by member name.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// name → `seq` of the first unread message.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ReadCursors {
+    cursors: HashMap<String, u64>,
+}
+
+impl ReadCursors {
+    /// Start `name` at `next` (the room's next `seq`) unless it has a cursor;
+    /// `true` if one was created.
+    pub fn start(&mut self, name: &str, next: u64) -> bool {
+        if self.cursors.contains_key(name) {
+            return false;
+        }
+        self.cursors.insert(name.to_string(), next);
+        true
+    }
+
+    /// `name` has read message `seq` and everything before it; `true` if
+    /// the cursor moved.
+    pub fn advance(&mut self, name: &str, seq: u64) -> bool {
+        let cursor = self.cursors.entry(name.to_string()).or_default();
+        if seq < *cursor {
+            return false;
+        }
+        *cursor = seq + 1;
+        true
+    }
+
+    /// Move the cursor of `old` to `new`; `true` if there was one.
+    pub fn rename(&mut self, old: &str, new: &str) -> bool {
+        match self.cursors.remove(old) {
+            Some(cursor) => {
+                self.cursors.insert(new.to_string(), cursor);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Messages `name` has not read; `since(seq)` counts the room's live
+    /// messages from `seq` on.
+    pub fn unread(&self, name: &str, since: impl FnOnce(u64) -> u64) -> u64 {
+        self.cursors.get(name).map_or(0, |cursor| since(*cursor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_only_move_forward() {
+        let mut reads = ReadCursors::default();
+        let upto = |next: u64| move |cursor: u64| next.saturating_sub(cursor);
+        // history before the first join is not unread
+        assert!(reads.start("bob", 10));
+        assert!(!reads.start("bob", 12));
+        assert_eq!(reads.unread("bob", upto(14)), 4);
+
+        assert!(reads.advance("bob", 11));
+        assert_eq!(reads.unread("bob", upto(14)), 2);
+        assert!(!reads.advance("bob", 10));
+        assert!(reads.advance("bob", 13));
+        assert_eq!(reads.unread("bob", upto(14)), 0);
+
+        assert!(reads.rename("bob", "bobby"));
+        assert_eq!(reads.unread("bobby", upto(16)), 2);
+        assert_eq!(reads.unread("bob", upto(16)), 0);
+    }
+}
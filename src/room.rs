@@ -1,4 +1,5 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use bytes::Bytes;
@@ -7,100 +8,664 @@ use tokio::task::JoinHandle;
 use tokio::time::{interval, Interval};
 
 use crate::config::Config;
+use crate::error::ChatError;
+use crate::ids::unique_id;
 use crate::memory_pool::{MemoryPool};
-use crate::protocol::{ServerEvent};
+use crate::moderation::{secs_left, ModAction, RoomAcl};
+use crate::protocol::{LeaveReason, Reaction, Role, ServerEvent, TypingState, Visibility};
+use crate::receipts::ReadCursors;
+use crate::storage::{HistoryQuery, MemoryStorage, Retention, RoomLog, Storage};
+
+/// What a successful `Join` hands back to the connection.
+pub struct Membership {
+    /// every frame broadcast in the room from now on
+    pub events: broadcast::Receiver<Bytes>,
+    /// fires when the member is kicked or banned; errors once the
+    /// membership ends any other way
+    pub evicted: oneshot::Receiver<()>,
+}
+
+/// Distinct emoji a single message can collect.
+const MAX_REACTIONS: usize = 20;
+
+/// A change to a message already in history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Amendment {
+    /// author or moderator only
+    Edit(String),
+    /// author or moderator only
+    Delete,
+    React(String),
+    Unreact(String),
+}
+
+/// Room-side state of one member.
+struct Member {
+    evict: oneshot::Sender<()>,
+}
 
 /// Commands sent from Hub → room task
 pub enum RoomCmd {
     Join {
         name: String,
-        resp: oneshot::Sender<broadcast::Receiver<Bytes>>, // receiver for this client
+        /// `name` is the session's authenticated identity
+        verified: bool,
+        /// join password and invite token presented by the client
+        password: Option<String>,
+        invite: Option<String>,
+        resp: oneshot::Sender<Result<Membership, ChatError>>, // subscription for this client
+    },
+    /// Broadcast a chat message; refused while the sender is muted.
+    Send {
+        event: ServerEvent,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    Leave { name: String, reason: LeaveReason },
+    /// Pass an event computed elsewhere on to the members; not stored.
+    Announce { event: ServerEvent },
+    /// Member `name` started or stopped typing; ignored from non-members
+    /// and the muted.
+    Typing { name: String, state: TypingState },
+    Rename {
+        old: String,
+        new: String,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    /// Change message `id` on behalf of member `actor`.
+    Amend {
+        actor: String,
+        id: String,
+        change: Amendment,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    /// Member `name` has read up to message `id`; announced to the room
+    /// when `receipt` is set and the cursor moved.
+    MarkRead {
+        name: String,
+        id: String,
+        receipt: bool,
+        resp: oneshot::Sender<Result<(), ChatError>>,
+    },
+    /// Messages member `name` has not read.
+    Unread {
+        name: String,
+        resp: oneshot::Sender<u64>,
     },
-    Send(ServerEvent),          // broadcast chat/system event
-    Leave { name: String },
     GetMembers {
         resp: oneshot::Sender<Vec<String>>,               // current members
     },
     GetHistory {
-        resp: oneshot::Sender<Vec<Bytes>>,                // copy of history frames
+        query: HistoryQuery,
+        resp: oneshot::Sender<Vec<(u64, Bytes)>>,         // indexed history frames
+    },
+    /// Like `GetHistory`, counting only thread `thread` and its root.
+    GetThread {
+        thread: String,
+        query: HistoryQuery,
+        resp: oneshot::Sender<Vec<(u64, Bytes)>>,
+    },
+    /// Kick, ban, mute, change roles or access on behalf of member `actor`;
+    /// answers with the reply meant for the actor alone, if any.
+    Moderate {
+        actor: String,
+        action: ModAction,
+        resp: oneshot::Sender<Result<Option<ServerEvent>, ChatError>>,
     },
     Shutdown, // Hub dropped
 }
 
-/// Spawn a new room task; returns its sender + JoinHandle
-pub fn spawn_room_task(cfg: &Config, room: String) -> (mpsc::Sender<RoomCmd>, JoinHandle<()>) {
+/// Spawn a new room task; returns its sender, JoinHandle and saved
+/// visibility. A room whose
+/// saved roles and bans cannot be read is not started, lest it come up
+/// without them and overwrite them on the next change.
+pub fn spawn_room_task(
+    cfg: &Config,
+    storage: &Arc<dyn Storage>,
+    room: String,
+) -> Result<(mpsc::Sender<RoomCmd>, JoinHandle<()>, Visibility), ChatError> {
+    let mut acl = storage.load_acl(&room).map_err(|e| {
+        tracing::error!(room=%room, error=%e, "room ACL unreadable, not starting the room");
+        ChatError::Custom("room settings unavailable".into())
+    })?;
+    let visibility = acl.access.visibility;
     let (cmd_tx, mut cmd_rx) = mpsc::channel::<RoomCmd>(32);
 
     // broadcast capacity comes from env or fixed 1024
     let (tx, _) = broadcast::channel::<Bytes>(cfg.history_limit.max(1024));
 
-    let history_cap = cfg.history_limit;
     let ttl = Duration::from_secs(cfg.room_ttl_secs);
+    let typing_ttl = Duration::from_secs(cfg.typing_ttl_secs);
+
+    // reload persisted history; a broken store must not take the room down
+    let mut history: Box<dyn RoomLog> = storage.open_room(&room).unwrap_or_else(|e| {
+        tracing::error!(room=%room, error=%e, "history store unavailable, using memory");
+        MemoryStorage::new(Retention::from_config(cfg))
+            .open_room(&room)
+            .expect("memory store")
+    });
+
+    let mut ids = MessageIndex::load(history.as_mut(), cfg.history_limit);
+
+    let mut reads = storage.load_reads(&room).unwrap_or_else(|e| {
+        tracing::error!(room=%room, error=%e, "read cursors unreadable, starting without");
+        ReadCursors::default()
+    });
+    let acl_store = storage.clone();
+    let save_acl = move |room: &str, acl: &RoomAcl| {
+        if let Err(e) = acl_store.save_acl(room, acl) {
+            tracing::error!(room=%room, error=%e, "failed to save room ACL");
+        }
+    };
+    let storage = storage.clone();
+    let save_reads = move |room: &str, reads: &ReadCursors| {
+        if let Err(e) = storage.save_reads(room, reads) {
+            tracing::error!(room=%room, error=%e, "failed to save read cursors");
+        }
+    };
 
     let handle = tokio::spawn(async move {
-        let mut members: HashSet<String> = HashSet::new();
-        let mut history: VecDeque<Bytes> = VecDeque::with_capacity(history_cap);
-        let mut last_empty_at: Option<Instant> = None;
+        let mut members: HashMap<String, Member> = HashMap::new();
+        // a room started for a join that was then refused expires too
+        let mut last_empty_at = Some(Instant::now());
+        // member → when their typing indicator runs out
+        let mut typing: HashMap<String, Instant> = HashMap::new();
+        // cursors moved since they were last saved; saved on the next sweep
+        let mut reads_dirty = false;
         let mut sweep: Interval = interval(Duration::from_secs(1));
 
         loop {
             tokio::select! {
                 Some(cmd) = cmd_rx.recv() => match cmd {
-                    RoomCmd::Join { name, resp } => {
-                        members.insert(name.clone());
+                    RoomCmd::Join { name, verified, password, invite, resp } => {
+                        let now = now_ms();
+                        // staff get in regardless, but only once they proved who they are
+                        if !(verified && acl.role(&name) >= Role::Moderator)
+                            && let Err(e) = acl.access.admit(&room, password.as_deref(), invite.as_deref(), now)
+                        {
+                            let _ = resp.send(Err(e));
+                            continue;
+                        }
+                        // names are unique per room
+                        if members.contains_key(&name) {
+                            let _ = resp.send(Err(ChatError::NameTaken(name)));
+                            continue;
+                        }
+                        if let Some(until) = acl.banned(&name, now) {
+                            let retry_after = until.map(|t| secs_left(t, now));
+                            let _ = resp.send(Err(ChatError::Banned { room: room.clone(), retry_after }));
+                            continue;
+                        }
+                        if !verified && acl.reserved(&name) {
+                            let _ = resp.send(Err(role_taken(&room, &name)));
+                            continue;
+                        }
+                        let (evict, evicted) = oneshot::channel();
+                        members.insert(name.clone(), Member { evict });
+                        // what was said before a name first joined is not unread
+                        reads_dirty |= reads.start(&name, history.next_index());
                         last_empty_at = None;
                         // send UserJoined event
-                        let evt = ServerEvent::UserJoined { room: room.clone(), name };
-                        broadcast_event(&tx, &mut history, history_cap, evt);
-                        let _ = resp.send(tx.subscribe());
+                        let evt = ServerEvent::UserJoined { room: room.clone(), name: name.clone() };
+                        broadcast_event(&tx, history.as_mut(), evt);
+                        let events = tx.subscribe();
+                        // the first member of a room nobody owns takes it over
+                        if acl.claim(&name) {
+                            save_acl(&room, &acl);
+                            let evt = ServerEvent::RoleChanged { room: room.clone(), name: name.clone(), role: Role::Owner, by: name };
+                            broadcast_event(&tx, history.as_mut(), evt);
+                        }
+                        let _ = resp.send(Ok(Membership { events, evicted }));
                     }
-                    RoomCmd::Send(ev) => {
-                        broadcast_event(&tx, &mut history, history_cap, ev);
+                    RoomCmd::Send { mut event, resp } => {
+                        if let ServerEvent::NewMessage { name, .. } = &event {
+                            let now = now_ms();
+                            if let Some(until) = acl.muted(name, now) {
+                                let _ = resp.send(Err(muted_error(&room, until, now)));
+                                continue;
+                            }
+                            if !members.contains_key(name) {
+                                let _ = resp.send(Err(ChatError::NotMember(room.clone())));
+                                continue;
+                            }
+                        }
+                        // a reply joins its parent's thread
+                        let mut thread = None;
+                        if let ServerEvent::NewMessage { reply_to: Some(parent), thread_id, .. } = &mut event {
+                            match thread_of(&room, history.as_mut(), &ids, parent) {
+                                Ok(root) => {
+                                    *thread_id = Some(root.clone());
+                                    thread = Some(root);
+                                }
+                                Err(e) => {
+                                    let _ = resp.send(Err(e));
+                                    continue;
+                                }
+                            }
+                        }
+                        if let ServerEvent::NewMessage { seq, id, name, .. } = &mut event {
+                            *seq = history.next_index();
+                            *id = unique_id();
+                            ids.insert(id.clone(), *seq);
+                            // the message itself tells everyone they stopped
+                            typing.remove(name.as_str());
+                            // and means everything before it was read
+                            reads_dirty |= reads.advance(name, *seq);
+                        }
+                        broadcast_event(&tx, history.as_mut(), event);
+                        if let Some(root) = thread
+                            && let Err(e) = count_reply(history.as_mut(), &ids, &root)
+                        {
+                            tracing::error!(room=%room, error=%e, "failed to update thread reply count");
+                        }
+                        let _ = resp.send(Ok(()));
                     }
-                    RoomCmd::Leave { name } => {
-                        members.remove(&name);
-                        let evt = ServerEvent::UserLeft { room: room.clone(), name };
-                        broadcast_event(&tx, &mut history, history_cap, evt);
+                    RoomCmd::Announce { event } => broadcast_event(&tx, history.as_mut(), event),
+                    RoomCmd::Typing { name, state } => {
+                        let changed = match state {
+                            TypingState::Started => {
+                                if !members.contains_key(&name) || acl.muted(&name, now_ms()).is_some() {
+                                    continue;
+                                }
+                                typing.insert(name.clone(), Instant::now() + typing_ttl).is_none()
+                            }
+                            TypingState::Stopped => typing.remove(&name).is_some(),
+                        };
+                        if changed {
+                            let evt = ServerEvent::UserTyping { room: room.clone(), name, state };
+                            broadcast_event(&tx, history.as_mut(), evt);
+                        }
+                    }
+                    RoomCmd::Leave { name, reason } => {
+                        if members.remove(&name).is_none() {
+                            continue; // already kicked
+                        }
+                        let evt = ServerEvent::UserLeft { room: room.clone(), name, reason: Some(reason) };
+                        broadcast_event(&tx, history.as_mut(), evt);
                         if members.is_empty() {
                             last_empty_at = Some(Instant::now());
                         }
                     }
+                    RoomCmd::Rename { old, new, resp } => {
+                        // check and swap in one step so member lists never show both;
+                        // renames are for free-form names only
+                        let now = now_ms();
+                        let res = if members.contains_key(&new) {
+                            Err(ChatError::NameTaken(new))
+                        } else if let Some(until) = acl.banned(&new, now) {
+                            Err(ChatError::Banned { room: room.clone(), retry_after: until.map(|t| secs_left(t, now)) })
+                        } else if acl.reserved(&new) {
+                            Err(role_taken(&room, &new))
+                        } else if let Some(member) = members.remove(&old) {
+                            members.insert(new.clone(), member);
+                            if acl.rename(&old, &new) {
+                                save_acl(&room, &acl);
+                            }
+                            reads_dirty |= reads.rename(&old, &new);
+                            let evt = ServerEvent::UserRenamed { room: room.clone(), old, new };
+                            broadcast_event(&tx, history.as_mut(), evt);
+                            Ok(())
+                        } else {
+                            Err(ChatError::NotMember(room.clone()))
+                        };
+                        let _ = resp.send(res);
+                    }
+                    RoomCmd::Amend { actor, id, change, resp } => {
+                        let now = now_ms();
+                        if !members.contains_key(&actor) {
+                            let _ = resp.send(Err(ChatError::NotMember(room.clone())));
+                            continue;
+                        }
+                        // the muted may still take their words back
+                        if !matches!(change, Amendment::Delete | Amendment::Unreact(_))
+                            && let Some(until) = acl.muted(&actor, now)
+                        {
+                            let _ = resp.send(Err(muted_error(&room, until, now)));
+                            continue;
+                        }
+                        let Some(found) = ids.find(history.as_mut(), &id) else {
+                            let _ = resp.send(Err(ChatError::BadRequest(format!("no message {id} in {room}"))));
+                            continue;
+                        };
+                        let deleting = matches!(change, Amendment::Delete);
+                        let res = amend(&room, history.as_mut(), &acl, &actor, found, change, now).map(|event| {
+                            if deleting {
+                                ids.delete(&id);
+                            }
+                            if let Some(event) = event {
+                                broadcast_event(&tx, history.as_mut(), event);
+                            }
+                        });
+                        let _ = resp.send(res);
+                    }
+                    RoomCmd::MarkRead { name, id, receipt, resp } => {
+                        if !members.contains_key(&name) {
+                            let _ = resp.send(Err(ChatError::NotMember(room.clone())));
+                            continue;
+                        }
+                        let Some(seq) = ids.seq(history.as_mut(), &id) else {
+                            let _ = resp.send(Err(ChatError::BadRequest(format!("no message {id} in {room}"))));
+                            continue;
+                        };
+                        if reads.advance(&name, seq) {
+                            reads_dirty = true;
+                            if receipt {
+                                let evt = ServerEvent::ReadReceipt { room: room.clone(), name, up_to_id: id, seq };
+                                broadcast_event(&tx, history.as_mut(), evt);
+                            }
+                        }
+                        let _ = resp.send(Ok(()));
+                    }
+                    RoomCmd::Unread { name, resp } => {
+                        let _ = resp.send(reads.unread(&name, |cursor| ids.live_since(cursor)));
+                    }
                     RoomCmd::GetMembers { resp } => {
-                        let _ = resp.send(members.iter().cloned().collect());
+                        let _ = resp.send(members.keys().cloned().collect());
                     }
-                    RoomCmd::GetHistory { resp } => {
-                        let _ = resp.send(history.iter().cloned().collect());
+                    RoomCmd::Moderate { actor, action, resp } => {
+                        if !members.contains_key(&actor) {
+                            let _ = resp.send(Err(ChatError::NotMember(room.clone())));
+                            continue;
+                        }
+                        let verdict = match acl.apply(&room, &actor, action, |n| members.contains_key(n), now_ms()) {
+                            Ok(verdict) => verdict,
+                            Err(e) => {
+                                let _ = resp.send(Err(e));
+                                continue;
+                            }
+                        };
+                        if verdict.persist {
+                            save_acl(&room, &acl);
+                        }
+                        // announce first so the evicted member still sees why
+                        if let Some(event) = verdict.event {
+                            broadcast_event(&tx, history.as_mut(), event);
+                        }
+                        if let Some(member) = verdict.remove.and_then(|name| members.remove(&name)) {
+                            let _ = member.evict.send(());
+                            if members.is_empty() {
+                                last_empty_at = Some(Instant::now());
+                            }
+                        }
+                        let _ = resp.send(Ok(verdict.reply));
+                    }
+                    RoomCmd::GetHistory { query, resp } => {
+                        let _ = resp.send(history.query(&query));
+                    }
+                    RoomCmd::GetThread { thread, query, resp } => {
+                        let _ = resp.send(thread_history(history.as_mut(), &thread, &query));
                     }
                     RoomCmd::Shutdown => {
                         break; // graceful exit
                     }
                 },
                 _ = sweep.tick() => {
-                    if members.is_empty() {
-                        if let Some(t0) = last_empty_at {
-                            if t0.elapsed() > ttl {
-                                tracing::info!(room=%room, "room expired after TTL");
-                                break; // exit task; Hub cleans up map on Join error
-                            }
+                    if reads_dirty {
+                        save_reads(&room, &reads);
+                        reads_dirty = false;
+                    }
+                    // indicators of the silent and of those who left run out
+                    let now = Instant::now();
+                    let expired: Vec<String> = typing
+                        .iter()
+                        .filter(|(name, until)| **until <= now || !members.contains_key(*name))
+                        .map(|(name, _)| name.clone())
+                        .collect();
+                    for name in expired {
+                        typing.remove(&name);
+                        let evt = ServerEvent::UserTyping { room: room.clone(), name, state: TypingState::Stopped };
+                        broadcast_event(&tx, history.as_mut(), evt);
+                    }
+                    // members whose connection went away without leaving
+                    let gone: Vec<String> =
+                        members.iter().filter(|(_, m)| m.evict.is_closed()).map(|(name, _)| name.clone()).collect();
+                    for name in gone {
+                        members.remove(&name);
+                        let reason = Some(LeaveReason::Disconnected);
+                        let evt = ServerEvent::UserLeft { room: room.clone(), name, reason };
+                        broadcast_event(&tx, history.as_mut(), evt);
+                        if members.is_empty() {
+                            last_empty_at = Some(Instant::now());
                         }
                     }
+                    if members.is_empty()
+                        && last_empty_at.is_some_and(|t0| t0.elapsed() > ttl)
+                    {
+                        tracing::info!(room=%room, "room expired after TTL");
+                        break; // exit task; Hub cleans up map on Join error
+                    }
                 }
             }
         }
+
+        if let Err(e) = history.flush() {
+            tracing::error!(room=%room, error=%e, "failed to flush history");
+        }
+        if reads_dirty {
+            save_reads(&room, &reads);
+        }
     });
 
-    (cmd_tx, handle)
+    Ok((cmd_tx, handle, visibility))
+}
+
+fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+/// `Forbidden` for a member muted until `until`.
+fn muted_error(room: &str, until: Option<u64>, now: u64) -> ChatError {
+    let left = until.map_or(String::new(), |t| format!(" for {}s", secs_left(t, now)));
+    ChatError::Forbidden(format!("muted in {room}{left}"))
+}
+
+/// Apply `change` to retained message `found`, as looked up by
+/// [`MessageIndex::find`], as `actor`. Returns the event announcing it, or
+/// `None` if nothing changed.
+fn amend(
+    room: &str,
+    history: &mut dyn RoomLog,
+    acl: &RoomAcl,
+    actor: &str,
+    found: (u64, ServerEvent),
+    change: Amendment,
+    now: u64,
+) -> Result<Option<ServerEvent>, ChatError> {
+    let (index, mut msg) = found;
+    let ServerEvent::NewMessage { seq, id, name, text: body, edited, deleted, reactions, .. } = &mut msg else {
+        unreachable!("only messages are indexed");
+    };
+    if *deleted {
+        return Err(ChatError::BadRequest(format!("message {id} was deleted")));
+    }
+    let by_staff = acl.role(actor) >= Role::Moderator;
+    if matches!(change, Amendment::Edit(_) | Amendment::Delete) && name != actor && !by_staff {
+        return Err(ChatError::Forbidden("only the author or a moderator can change a message".into()));
+    }
+    let (room, id, by, seq) = (room.to_string(), id.clone(), actor.to_string(), *seq);
+    let event = match change {
+        Amendment::Edit(text) => {
+            *body = text.clone();
+            *edited = Some(now);
+            ServerEvent::MessageEdited { room, id, seq, text, by, ts: now }
+        }
+        Amendment::Delete => {
+            body.clear();
+            reactions.clear();
+            *deleted = true;
+            ServerEvent::MessageDeleted { room, id, seq, by }
+        }
+        Amendment::React(emoji) => {
+            if !react(reactions, emoji, by)? {
+                return Ok(None);
+            }
+            ServerEvent::ReactionUpdated { room, message_id: id, seq, reactions: reactions.clone() }
+        }
+        Amendment::Unreact(emoji) => {
+            if !unreact(reactions, &emoji, &by) {
+                return Ok(None);
+            }
+            ServerEvent::ReactionUpdated { room, message_id: id, seq, reactions: reactions.clone() }
+        }
+    };
+    history.replace(index, Bytes::from(serde_json::to_vec(&msg)?))?;
+    Ok(Some(event))
 }
 
-/// helper – encode event → Bytes and fan‑out, push history if chat message
-fn broadcast_event(
-    tx: &broadcast::Sender<Bytes>,
-    history: &mut VecDeque<Bytes>,
+/// Message ids of a room and the history index each was stored at, so
+/// edits, replies and read marks decode one record instead of the whole
+/// history, and unread counts skip deleted messages. Holds as many ids as
+/// history can retain; anything older is gone from history anyway.
+struct MessageIndex {
+    seqs: HashMap<String, u64>,
+    /// ids oldest first, for eviction
+    order: VecDeque<String>,
+    /// indexes of the messages not deleted
+    live: BTreeSet<u64>,
     cap: usize,
-    event: ServerEvent,
-) {
+}
+
+impl MessageIndex {
+    /// Index the messages `history` retains now.
+    fn load(history: &mut dyn RoomLog, cap: usize) -> Self {
+        let mut index = Self { seqs: HashMap::new(), order: VecDeque::new(), live: BTreeSet::new(), cap: cap.max(1) };
+        for (seq, frame) in history.query(&HistoryQuery::latest(index.cap)) {
+            if let Ok(ServerEvent::NewMessage { id, deleted, .. }) = serde_json::from_slice(&frame) {
+                index.insert(id.clone(), seq);
+                if deleted {
+                    index.delete(&id);
+                }
+            }
+        }
+        index
+    }
+
+    fn insert(&mut self, id: String, seq: u64) {
+        if self.order.len() >= self.cap
+            && let Some(old) = self.order.pop_front()
+            && let Some(old_seq) = self.seqs.remove(&old)
+        {
+            self.live.remove(&old_seq);
+        }
+        self.seqs.insert(id.clone(), seq);
+        self.live.insert(seq);
+        self.order.push_back(id);
+    }
+
+    /// Message `id` was deleted; it stays findable as a tombstone.
+    fn delete(&mut self, id: &str) {
+        if let Some(seq) = self.seqs.get(id) {
+            self.live.remove(seq);
+        }
+    }
+
+    /// Messages not deleted from index `from` on.
+    fn live_since(&self, from: u64) -> u64 {
+        self.live.range(from..).count() as u64
+    }
+
+    /// Index of message `id`, if history still retains it.
+    fn seq(&self, history: &mut dyn RoomLog, id: &str) -> Option<u64> {
+        self.find(history, id).map(|(seq, _)| seq)
+    }
+
+    /// Retained message `id` and its index.
+    fn find(&self, history: &mut dyn RoomLog, id: &str) -> Option<(u64, ServerEvent)> {
+        let seq = *self.seqs.get(id)?;
+        let query = HistoryQuery { before: Some(seq.checked_add(1)?), since: None, limit: 1 };
+        let (index, frame) = history.query(&query).pop()?;
+        if index != seq {
+            return None;
+        }
+        let msg: ServerEvent = serde_json::from_slice(&frame).ok()?;
+        matches!(&msg, ServerEvent::NewMessage { id: i, .. } if i == id).then_some((index, msg))
+    }
+}
+
+/// Id of the thread a reply to `parent` belongs to: the parent's own
+/// thread, or the thread the parent starts.
+fn thread_of(room: &str, history: &mut dyn RoomLog, ids: &MessageIndex, parent: &str) -> Result<String, ChatError> {
+    match ids.find(history, parent) {
+        Some((_, ServerEvent::NewMessage { deleted: true, .. })) => {
+            Err(ChatError::BadRequest(format!("message {parent} was deleted")))
+        }
+        Some((_, ServerEvent::NewMessage { id, thread_id, .. })) => Ok(thread_id.unwrap_or(id)),
+        _ => Err(ChatError::BadRequest(format!("no message {parent} in {room}"))),
+    }
+}
+
+/// Count one more reply on thread root `root`, if it is still retained.
+fn count_reply(history: &mut dyn RoomLog, ids: &MessageIndex, root: &str) -> Result<(), ChatError> {
+    let Some((index, mut msg)) = ids.find(history, root) else { return Ok(()) };
+    if let ServerEvent::NewMessage { replies, .. } = &mut msg {
+        *replies += 1;
+    }
+    history.replace(index, Bytes::from(serde_json::to_vec(&msg)?))?;
+    Ok(())
+}
+
+/// The newest `query.limit` messages of thread `thread` matching `query`,
+/// its root included.
+pub fn thread_history(history: &mut dyn RoomLog, thread: &str, query: &HistoryQuery) -> Vec<(u64, Bytes)> {
+    let all = HistoryQuery { limit: usize::MAX, ..*query };
+    let mut page: Vec<_> = history
+        .query(&all)
+        .into_iter()
+        .filter(|(_, frame)| match serde_json::from_slice(frame) {
+            Ok(ServerEvent::NewMessage { id, thread_id, .. }) => id == thread || thread_id.as_deref() == Some(thread),
+            _ => false,
+        })
+        .collect();
+    let skip = page.len().saturating_sub(query.limit);
+    page.drain(..skip);
+    page
+}
+
+/// Add `name` under `emoji`; `false` if it was already there.
+fn react(reactions: &mut Vec<Reaction>, emoji: String, name: String) -> Result<bool, ChatError> {
+    match reactions.iter().position(|r| r.emoji == emoji) {
+        Some(pos) if reactions[pos].names.contains(&name) => Ok(false),
+        Some(pos) => {
+            let r = &mut reactions[pos];
+            r.names.push(name);
+            r.count = r.names.len();
+            Ok(true)
+        }
+        None if reactions.len() >= MAX_REACTIONS => {
+            Err(ChatError::BadRequest(format!("a message takes at most {MAX_REACTIONS} different reactions")))
+        }
+        None => {
+            reactions.push(Reaction { emoji, count: 1, names: vec![name] });
+            Ok(true)
+        }
+    }
+}
+
+/// Take `name` off `emoji`, dropping emoji nobody uses; `false` if absent.
+fn unreact(reactions: &mut Vec<Reaction>, emoji: &str, name: &str) -> bool {
+    let Some(pos) = reactions.iter().position(|r| r.emoji == emoji && r.names.iter().any(|n| n == name)) else {
+        return false;
+    };
+    let r = &mut reactions[pos];
+    r.names.retain(|n| n != name);
+    r.count = r.names.len();
+    if r.names.is_empty() {
+        reactions.remove(pos);
+    }
+    true
+}
+
+/// Refusal of a free-form name that holds a role in `room`.
+fn role_taken(room: &str, name: &str) -> ChatError {
+    ChatError::Unauthorized(format!("{name} holds a role in {room}; authenticate to use it"))
+}
+
+/// helper – encode event → Bytes and fan‑out, append to history if chat message
+fn broadcast_event(tx: &broadcast::Sender<Bytes>, history: &mut dyn RoomLog, event: ServerEvent) {
     // Only keep chat messages in history (UserJoined/UserLeft skipped)
-    let is_chat = matches!(event, ServerEvent::NewMessage { .. });
+    let ts = match &event {
+        ServerEvent::NewMessage { ts, .. } => Some(*ts),
+        _ => None,
+    };
 
     let json = serde_json::to_vec(&event).expect("serialize");
     let mut buf = MemoryPool::global().alloc(json.len());
@@ -109,10 +674,432 @@ fn broadcast_event(
 
     let _ = tx.send(frame.clone());
 
-    if is_chat {
-        history.push_back(frame);
-        if history.len() > cap {
-            history.pop_front();
+    if let Some(ts) = ts
+        && let Err(e) = history.append(ts, frame)
+    {
+        tracing::error!(error=%e, "failed to persist message");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SegmentStorage;
+
+    /// A member stays only while its `Membership` is held; the sweep drops
+    /// the rest as disconnected.
+    async fn join(tx: &mpsc::Sender<RoomCmd>, name: &str) -> Result<Membership, ChatError> {
+        join_as(tx, name, false).await
+    }
+
+    /// Like `join`; `verified` as if the session authenticated as `name`.
+    async fn join_as(tx: &mpsc::Sender<RoomCmd>, name: &str, verified: bool) -> Result<Membership, ChatError> {
+        let (resp, rx) = oneshot::channel();
+        tx.send(RoomCmd::Join { name: name.into(), verified, password: None, invite: None, resp }).await.unwrap();
+        rx.await.unwrap()
+    }
+
+    async fn members(tx: &mpsc::Sender<RoomCmd>) -> Vec<String> {
+        let (resp, rx) = oneshot::channel();
+        tx.send(RoomCmd::GetMembers { resp }).await.unwrap();
+        let mut list = rx.await.unwrap();
+        list.sort();
+        list
+    }
+
+    fn room(name: &str) -> mpsc::Sender<RoomCmd> {
+        let cfg = Config::default();
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new(Retention::from_config(&cfg)));
+        spawn_room_task(&cfg, &storage, name.into()).unwrap().0
+    }
+
+    async fn next_event(events: &mut broadcast::Receiver<Bytes>) -> ServerEvent {
+        serde_json::from_slice(&events.recv().await.unwrap()).unwrap()
+    }
+
+    /// Post `text` as `name` and return the message's id.
+    async fn post(tx: &mpsc::Sender<RoomCmd>, name: &str, text: &str) -> String {
+        reply(tx, name, text, None).await.unwrap()
+    }
+
+    /// Post `text` as `name` in reply to `reply_to`, if set.
+    async fn reply(
+        tx: &mpsc::Sender<RoomCmd>,
+        name: &str,
+        text: &str,
+        reply_to: Option<&str>,
+    ) -> Result<String, ChatError> {
+        let (resp, rx) = oneshot::channel();
+        let event = ServerEvent::message("rust", name, text, reply_to);
+        tx.send(RoomCmd::Send { event, resp }).await.unwrap();
+        rx.await.unwrap()?;
+        match history(tx).await.pop() {
+            Some(ServerEvent::NewMessage { id, .. }) => Ok(id),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    async fn amend(tx: &mpsc::Sender<RoomCmd>, actor: &str, id: &str, change: Amendment) -> Result<(), ChatError> {
+        let (resp, rx) = oneshot::channel();
+        tx.send(RoomCmd::Amend { actor: actor.into(), id: id.into(), change, resp }).await.unwrap();
+        rx.await.unwrap()
+    }
+
+    async fn history(tx: &mpsc::Sender<RoomCmd>) -> Vec<ServerEvent> {
+        let (resp, rx) = oneshot::channel();
+        tx.send(RoomCmd::GetHistory { query: HistoryQuery::latest(usize::MAX), resp }).await.unwrap();
+        rx.await.unwrap().iter().map(|(_, frame)| serde_json::from_slice(frame).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn names_are_unique_and_renames_swap() {
+        let tx = room("rust");
+
+        let Membership { mut events, evicted: _alice } = join(&tx, "alice").await.unwrap();
+        assert!(matches!(next_event(&mut events).await, ServerEvent::RoleChanged { role: Role::Owner, .. }));
+        let _bob = join(&tx, "bob").await.unwrap();
+        assert!(matches!(join(&tx, "alice").await, Err(ChatError::NameTaken(n)) if n == "alice"));
+
+        let rename = |old: &str, new: &str| {
+            let (resp, rx) = oneshot::channel();
+            let cmd = RoomCmd::Rename { old: old.into(), new: new.into(), resp };
+            (cmd, rx)
+        };
+        let (cmd, rx) = rename("bob", "alice");
+        tx.send(cmd).await.unwrap();
+        assert!(matches!(rx.await.unwrap(), Err(ChatError::NameTaken(_))));
+
+        let (cmd, rx) = rename("bob", "carol");
+        tx.send(cmd).await.unwrap();
+        rx.await.unwrap().unwrap();
+        assert_eq!(members(&tx).await, vec!["alice", "carol"]);
+
+        // alice's receiver sees bob join, then the rename
+        assert!(matches!(next_event(&mut events).await, ServerEvent::UserJoined { .. }));
+        assert_eq!(
+            next_event(&mut events).await,
+            ServerEvent::UserRenamed { room: "rust".into(), old: "bob".into(), new: "carol".into() }
+        );
+    }
+
+    #[tokio::test]
+    async fn moderators_kick_ban_and_mute() {
+        let tx = room("rust");
+        let moderate = |actor: &str, action: ModAction| {
+            let (resp, rx) = oneshot::channel();
+            let cmd = RoomCmd::Moderate { actor: actor.into(), action, resp };
+            (cmd, rx)
+        };
+        let say = |name: &str| {
+            let (resp, rx) = oneshot::channel();
+            let event = ServerEvent::message("rust", name, "hi", None);
+            (RoomCmd::Send { event, resp }, rx)
+        };
+
+        let _alice = join(&tx, "alice").await.unwrap();
+        let bob = join(&tx, "bob").await.unwrap();
+
+        // plain members cannot moderate
+        let (cmd, rx) = moderate("bob", ModAction::Kick { name: "alice".into(), reason: None });
+        tx.send(cmd).await.unwrap();
+        assert!(matches!(rx.await.unwrap(), Err(ChatError::Forbidden(_))));
+
+        let (cmd, rx) = moderate("alice", ModAction::Mute { name: "bob".into(), duration: Some(60) });
+        tx.send(cmd).await.unwrap();
+        rx.await.unwrap().unwrap();
+        let (cmd, rx) = say("bob");
+        tx.send(cmd).await.unwrap();
+        assert!(matches!(rx.await.unwrap(), Err(ChatError::Forbidden(_))));
+
+        let ban = ModAction::Ban { name: "bob".into(), duration: None, reason: Some("spam".into()) };
+        let (cmd, rx) = moderate("alice", ban);
+        tx.send(cmd).await.unwrap();
+        rx.await.unwrap().unwrap();
+        bob.evicted.await.unwrap();
+        assert_eq!(members(&tx).await, vec!["alice"]);
+        assert!(matches!(join(&tx, "bob").await, Err(ChatError::Banned { retry_after: None, .. })));
+    }
+
+    #[tokio::test]
+    async fn free_form_names_cannot_take_roles_or_bans() {
+        let tx = room("rust");
+        let alice = join(&tx, "alice").await.unwrap();
+        let _carol = join(&tx, "carol").await.unwrap();
+        let (resp, rx) = oneshot::channel();
+        let ban = ModAction::Ban { name: "mallory".into(), duration: None, reason: None };
+        tx.send(RoomCmd::Moderate { actor: "alice".into(), action: ban, resp }).await.unwrap();
+        rx.await.unwrap().unwrap();
+        tx.send(RoomCmd::Leave { name: "alice".into(), reason: LeaveReason::Left }).await.unwrap();
+        drop(alice);
+
+        let rename = |new: &str| {
+            let (resp, rx) = oneshot::channel();
+            (RoomCmd::Rename { old: "carol".into(), new: new.into(), resp }, rx)
+        };
+        let (cmd, rx) = rename("alice");
+        tx.send(cmd).await.unwrap();
+        assert!(matches!(rx.await.unwrap(), Err(ChatError::Unauthorized(_))));
+        let (cmd, rx) = rename("mallory");
+        tx.send(cmd).await.unwrap();
+        assert!(matches!(rx.await.unwrap(), Err(ChatError::Banned { .. })));
+
+        assert!(matches!(join(&tx, "alice").await, Err(ChatError::Unauthorized(_))));
+        let _alice = join_as(&tx, "alice", true).await.unwrap();
+        assert_eq!(members(&tx).await, vec!["alice", "carol"]);
+    }
+
+    #[tokio::test]
+    async fn members_leave_once_with_a_reason() {
+        let tx = room("rust");
+        let Membership { mut events, evicted: _alice } = join(&tx, "alice").await.unwrap();
+        let bob = join(&tx, "bob").await.unwrap();
+        let _carol = join(&tx, "carol").await.unwrap();
+        next_event(&mut events).await; // owner claim
+        next_event(&mut events).await; // bob joined
+        next_event(&mut events).await; // carol joined
+
+        let leave = |reason| RoomCmd::Leave { name: "carol".into(), reason };
+        tx.send(leave(LeaveReason::TimedOut)).await.unwrap();
+        tx.send(leave(LeaveReason::Left)).await.unwrap();
+        assert!(matches!(
+            next_event(&mut events).await,
+            ServerEvent::UserLeft { name, reason: Some(LeaveReason::TimedOut), .. } if name == "carol"
+        ));
+        // a connection gone without a word is noticed on the next sweep
+        drop(bob);
+        assert!(matches!(
+            next_event(&mut events).await,
+            ServerEvent::UserLeft { name, reason: Some(LeaveReason::Disconnected), .. } if name == "bob"
+        ));
+        assert_eq!(members(&tx).await, vec!["alice".to_string()]);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn authors_and_moderators_amend_messages() {
+        let tx = room("rust");
+        let mut held = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            held.push(join(&tx, name).await.unwrap());
+        }
+        let id = post(&tx, "bob", "helo").await;
+
+        // carol is neither the author nor a moderator
+        assert!(matches!(amend(&tx, "carol", &id, Amendment::Delete).await, Err(ChatError::Forbidden(_))));
+
+        amend(&tx, "bob", &id, Amendment::Edit("hello".into())).await.unwrap();
+        assert!(matches!(&history(&tx).await[0], ServerEvent::NewMessage { text, edited: Some(_), .. } if text == "hello"));
+
+        // the owner may delete it; late joiners then only see a tombstone
+        amend(&tx, "alice", &id, Amendment::Delete).await.unwrap();
+        assert!(matches!(&history(&tx).await[0], ServerEvent::NewMessage { text, deleted: true, .. } if text.is_empty()));
+
+        let edit = amend(&tx, "bob", &id, Amendment::Edit("back".into())).await;
+        assert!(matches!(edit, Err(ChatError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn only_retained_messages_can_be_amended() {
+        let dir = std::env::temp_dir().join(format!("webchathub-amend-{}", unique_id()));
+        let cfg = Config { history_limit: 2, ..Config::default() };
+        let storage: Arc<dyn Storage> =
+            Arc::new(SegmentStorage::new(&dir, Retention::from_config(&cfg), cfg.segment_bytes));
+        let tx = spawn_room_task(&cfg, &storage, "rust".into()).unwrap().0;
+        let held = join(&tx, "bob").await.unwrap();
+        let ids = [post(&tx, "bob", "one").await, post(&tx, "bob", "two").await, post(&tx, "bob", "three").await];
+        let gone = amend(&tx, "bob", &ids[0], Amendment::Edit("1".into())).await;
+        assert!(matches!(gone, Err(ChatError::BadRequest(_))));
+        amend(&tx, "bob", &ids[1], Amendment::Edit("2".into())).await.unwrap();
+        drop((held, tx));
+
+        // a room started again finds the messages it retained
+        let tx = spawn_room_task(&cfg, &storage, "rust".into()).unwrap().0;
+        // bob owns the room by now
+        let _held = join_as(&tx, "bob", true).await.unwrap();
+        amend(&tx, "bob", &ids[2], Amendment::Edit("3".into())).await.unwrap();
+        let texts: Vec<_> = history(&tx).await.into_iter().map(|msg| match msg {
+            ServerEvent::NewMessage { text, .. } => text,
+            other => panic!("unexpected {other:?}"),
+        }).collect();
+        assert_eq!(texts, ["2", "3"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn reactions_are_counted_and_stored() {
+        let tx = room("rust");
+        let Membership { mut events, evicted: _alice } = join(&tx, "alice").await.unwrap();
+        let _bob = join(&tx, "bob").await.unwrap();
+        let id = post(&tx, "alice", "ship it").await;
+
+        for (name, emoji) in [("alice", "👍"), ("bob", "👍"), ("bob", "👍"), ("bob", "🎉")] {
+            amend(&tx, name, &id, Amendment::React(emoji.into())).await.unwrap();
         }
+        amend(&tx, "alice", &id, Amendment::Unreact("👍".into())).await.unwrap();
+        let ServerEvent::NewMessage { reactions, .. } = history(&tx).await.remove(0) else { panic!("no message") };
+        assert_eq!(
+            reactions,
+            vec![
+                Reaction { emoji: "👍".into(), count: 1, names: vec!["bob".into()] },
+                Reaction { emoji: "🎉".into(), count: 1, names: vec!["bob".into()] },
+            ]
+        );
+
+        // the repeated 👍 from bob changed nothing and was not announced
+        let mut updates = 0;
+        while let Ok(frame) = events.try_recv() {
+            if matches!(serde_json::from_slice(&frame).unwrap(), ServerEvent::ReactionUpdated { .. }) {
+                updates += 1;
+            }
+        }
+        assert_eq!(updates, 4);
+    }
+
+    #[tokio::test]
+    async fn replies_form_threads() {
+        let tx = room("rust");
+        let _alice = join(&tx, "alice").await.unwrap();
+        let _bob = join(&tx, "bob").await.unwrap();
+        let root = post(&tx, "alice", "lunch?").await;
+        post(&tx, "bob", "unrelated").await;
+        let first = reply(&tx, "bob", "yes", Some(&root)).await.unwrap();
+        // a reply to a reply stays in the same thread
+        reply(&tx, "alice", "noon", Some(&first)).await.unwrap();
+        assert!(matches!(reply(&tx, "bob", "?", Some("nope")).await, Err(ChatError::BadRequest(_))));
+
+        let all = history(&tx).await;
+        let ServerEvent::NewMessage { replies, .. } = &all[0] else { panic!("no root") };
+        assert_eq!(*replies, 2);
+        let ServerEvent::NewMessage { thread_id, reply_to, .. } = &all[3] else { panic!("no reply") };
+        assert_eq!((thread_id.as_deref(), reply_to.as_deref()), (Some(root.as_str()), Some(first.as_str())));
+
+        let (resp, rx) = oneshot::channel();
+        tx.send(RoomCmd::GetThread { thread: root.clone(), query: HistoryQuery::latest(2), resp }).await.unwrap();
+        let page: Vec<_> = rx.await.unwrap().into_iter().map(|(index, _)| index).collect();
+        assert_eq!(page, vec![2, 3]);
+        let (resp, rx) = oneshot::channel();
+        let query = HistoryQuery { before: Some(2), since: None, limit: 10 };
+        tx.send(RoomCmd::GetThread { thread: root, query, resp }).await.unwrap();
+        assert_eq!(rx.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn joins_are_checked_against_the_room_access() {
+        let tx = room("rust");
+        let _alice = join_as(&tx, "alice", true).await.unwrap();
+        let (resp, rx) = oneshot::channel();
+        let action = ModAction::SetPassword(Some("pw".into()));
+        tx.send(RoomCmd::Moderate { actor: "alice".into(), action, resp }).await.unwrap();
+        rx.await.unwrap().unwrap();
+
+        let join_with = |name: &str, password: Option<&str>| {
+            let (resp, rx) = oneshot::channel();
+            let password = password.map(str::to_string);
+            (RoomCmd::Join { name: name.into(), verified: false, password, invite: None, resp }, rx)
+        };
+        let (cmd, rx) = join_with("bob", None);
+        tx.send(cmd).await.unwrap();
+        assert!(matches!(rx.await.unwrap(), Err(ChatError::Unauthorized(_))));
+        let (cmd, rx) = join_with("bob", Some("pw"));
+        tx.send(cmd).await.unwrap();
+        let _bob = rx.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreadable_acl_keeps_the_room_down() {
+        let dir = std::env::temp_dir().join(format!("webchathub-acl-{}", unique_id()));
+        std::fs::create_dir_all(dir.join("rust")).unwrap();
+        std::fs::write(dir.join("rust").join("acl.json"), "{not json").unwrap();
+        let cfg = Config::default();
+        let storage: Arc<dyn Storage> =
+            Arc::new(SegmentStorage::new(&dir, Retention::from_config(&cfg), cfg.segment_bytes));
+        assert!(spawn_room_task(&cfg, &storage, "rust".into()).is_err());
+        // nothing was written over it
+        assert_eq!(std::fs::read_to_string(dir.join("rust").join("acl.json")).unwrap(), "{not json");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn typing_is_fanned_out_and_expires() {
+        // indicators run out on the first sweep
+        let cfg = Config { typing_ttl_secs: 0, ..Config::default() };
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new(Retention::from_config(&cfg)));
+        let tx = spawn_room_task(&cfg, &storage, "rust".into()).unwrap().0;
+        let Membership { mut events, evicted: _alice } = join(&tx, "alice").await.unwrap();
+        let _bob = join(&tx, "bob").await.unwrap();
+        next_event(&mut events).await; // owner claim
+        next_event(&mut events).await; // bob joined
+
+        let typing = |state| RoomCmd::Typing { name: "bob".into(), state };
+        tx.send(typing(TypingState::Started)).await.unwrap();
+        // a refresh is not announced again
+        tx.send(typing(TypingState::Started)).await.unwrap();
+        tx.send(RoomCmd::Typing { name: "mallory".into(), state: TypingState::Started }).await.unwrap();
+        assert!(matches!(
+            next_event(&mut events).await,
+            ServerEvent::UserTyping { name, state: TypingState::Started, .. } if name == "bob"
+        ));
+        assert!(matches!(
+            next_event(&mut events).await,
+            ServerEvent::UserTyping { name, state: TypingState::Stopped, .. } if name == "bob"
+        ));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn read_cursors_count_unread_and_send_receipts() {
+        let tx = room("rust");
+        let _alice = join(&tx, "alice").await.unwrap();
+        post(&tx, "alice", "before bob").await;
+        let Membership { mut events, evicted: _bob } = join(&tx, "bob").await.unwrap();
+        let unread = |name: &str| {
+            let (resp, rx) = oneshot::channel();
+            let tx = tx.clone();
+            let name = name.to_string();
+            async move {
+                tx.send(RoomCmd::Unread { name, resp }).await.unwrap();
+                rx.await.unwrap()
+            }
+        };
+        let mark = |id: &str, receipt: bool| {
+            let (resp, rx) = oneshot::channel();
+            let tx = tx.clone();
+            let id = id.to_string();
+            async move {
+                tx.send(RoomCmd::MarkRead { name: "bob".into(), id, receipt, resp }).await.unwrap();
+                rx.await.unwrap()
+            }
+        };
+        assert_eq!(unread("bob").await, 0);
+        let one = post(&tx, "alice", "one").await;
+        let two = post(&tx, "alice", "two").await;
+        assert_eq!(unread("bob").await, 2);
+
+        mark(&one, false).await.unwrap();
+        assert_eq!(unread("bob").await, 1);
+        mark(&two, true).await.unwrap();
+        // marking something older changes nothing and says nothing
+        mark(&one, true).await.unwrap();
+        assert!(mark("nope", true).await.is_err());
+        assert_eq!(unread("bob").await, 0);
+        let receipts: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|frame| match serde_json::from_slice(&frame).unwrap() {
+                ServerEvent::ReadReceipt { name, up_to_id, seq, .. } => Some((name, up_to_id, seq)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(receipts, vec![("bob".to_string(), two, 2)]);
+
+        // posting reads everything before it
+        post(&tx, "alice", "three").await;
+        post(&tx, "bob", "mine").await;
+        assert_eq!(unread("bob").await, 0);
+
+        // deleted messages are not waiting to be read
+        let four = post(&tx, "alice", "four").await;
+        post(&tx, "alice", "five").await;
+        assert_eq!(unread("bob").await, 2);
+        amend(&tx, "alice", &four, Amendment::Delete).await.unwrap();
+        assert_eq!(unread("bob").await, 1);
     }
 }
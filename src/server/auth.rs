@@ -0,0 +1,366 @@
+//! Pluggable client authentication.
+//!
+//! An [`Authenticator`] turns [`Credentials`] into a verified identity. The
+//! listener accepts credentials either as an `Authorization: Bearer …`
+//! header on the WebSocket upgrade or as a `ClientRequest::Auth` request.
+//!
+//! Providers shipped here:
+//!
+//! * [`UserFile`] — `name:<argon2 PHC hash>` lines, one user per line.
+//! * [`TokenAuth`] — HS256-signed JWT bearer tokens (`sub` = user name).
+//! * [`Chain`] — tries several providers in order.
+//!
+//! When authentication is optional, names of registered users and names
+//! anyone has authenticated as ([`Claimed`], up to [`MAX_CLAIMED`] of the
+//! most recent) are kept for sessions authenticated as them.
+
+use std::fs;
+use std::io;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::Config;
+use crate::error::ChatError;
+use crate::protocol::Credentials;
+
+/// Verifies credentials and returns the authenticated user name.
+pub trait Authenticator: Send + Sync {
+    /// `Ok(None)` means "not my kind of credentials", letting a [`Chain`]
+    /// try the next provider; `Err` is a definite rejection.
+    fn authenticate(&self, creds: &Credentials) -> Result<Option<String>, ChatError>;
+
+    /// Whether `name` is a user this provider knows without being shown
+    /// credentials.
+    fn knows(&self, _name: &str) -> bool {
+        false
+    }
+}
+
+/// Claimed names remembered; the least recently authenticated are
+/// forgotten beyond this.
+pub const MAX_CLAIMED: usize = 100_000;
+
+/// Names connections have authenticated as since the server started.
+#[derive(Default)]
+pub struct Claimed(Mutex<ClaimedNames>);
+
+#[derive(Default)]
+struct ClaimedNames {
+    /// name → when it was last authenticated as, in insertions
+    names: HashMap<String, u64>,
+    clock: u64,
+}
+
+impl Claimed {
+    pub fn insert(&self, name: &str) {
+        let mut claimed = self.0.lock().expect("claimed lock");
+        claimed.clock += 1;
+        if !claimed.names.contains_key(name) && claimed.names.len() >= MAX_CLAIMED {
+            let oldest = claimed.names.iter().min_by_key(|(_, at)| **at).map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                claimed.names.remove(&oldest);
+            }
+        }
+        let at = claimed.clock;
+        claimed.names.insert(name.to_string(), at);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.lock().expect("claimed lock").names.contains_key(name)
+    }
+}
+
+/// Build the providers enabled in `cfg`; `None` when none are configured.
+pub fn from_config(cfg: &Config) -> io::Result<Option<Arc<dyn Authenticator>>> {
+    let mut providers: Vec<Box<dyn Authenticator>> = Vec::new();
+    if let Some(path) = &cfg.auth_users_file {
+        providers.push(Box::new(UserFile::load(path)?));
+    }
+    if let Some(secret) = &cfg.auth_token_secret {
+        providers.push(Box::new(TokenAuth::new(secret.as_bytes())));
+    }
+    Ok(match providers.len() {
+        0 => None,
+        _ => Some(Arc::new(Chain(providers))),
+    })
+}
+
+fn rejected() -> ChatError {
+    ChatError::Unauthorized("invalid credentials".into())
+}
+
+// ---------------------------------------------------------------------------
+// local user file
+// ---------------------------------------------------------------------------
+
+/// Users and argon2 password hashes loaded from a text file.
+///
+/// Format: one `name:$argon2id$…` entry per line; blank lines and lines
+/// starting with `#` are ignored.
+pub struct UserFile {
+    users: HashMap<String, String>,
+}
+
+impl UserFile {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut users = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, hash) = line.split_once(':').ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: expected name:hash", n + 1))
+            })?;
+            PasswordHash::new(hash).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", n + 1))
+            })?;
+            users.insert(name.to_string(), hash.to_string());
+        }
+        Ok(Self { users })
+    }
+}
+
+impl Authenticator for UserFile {
+    fn authenticate(&self, creds: &Credentials) -> Result<Option<String>, ChatError> {
+        let Credentials::Password { user, password } = creds else {
+            return Ok(None);
+        };
+        // unknown users cost a verification too, so timing gives no names away
+        let (known, hash) = match self.users.get(user) {
+            Some(hash) => (true, hash.as_str()),
+            None => (false, dummy_hash()),
+        };
+        let parsed = PasswordHash::new(hash).map_err(|_| rejected())?;
+        let verified = Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok();
+        if !(known && verified) {
+            return Err(rejected());
+        }
+        Ok(Some(user.clone()))
+    }
+
+    fn knows(&self, name: &str) -> bool {
+        self.users.contains_key(name)
+    }
+}
+
+/// A hash no password is known for, checked in place of a missing user's.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let secret = SaltString::generate(&mut OsRng);
+        hash_password(secret.as_str()).expect("argon2 hashes any password")
+    })
+}
+
+/// Hash a password for a [`UserFile`] entry.
+pub fn hash_password(password: &str) -> Result<String, ChatError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| ChatError::Custom(e.to_string()))
+}
+
+// ---------------------------------------------------------------------------
+// signed bearer tokens
+// ---------------------------------------------------------------------------
+
+const JWT_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    /// expiry, seconds since epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+/// HS256 JWT verifier/issuer sharing one secret.
+pub struct TokenAuth {
+    secret: Vec<u8>,
+}
+
+impl TokenAuth {
+    pub fn new(secret: &[u8]) -> Self {
+        Self { secret: secret.to_vec() }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts any key length")
+    }
+
+    /// Issue a token for `user`, valid for `ttl_secs` (0 = no expiry).
+    pub fn mint(&self, user: &str, ttl_secs: u64) -> String {
+        let exp = (ttl_secs > 0).then(|| now_secs() + ttl_secs);
+        let claims = serde_json::to_vec(&Claims { sub: user.to_string(), exp }).expect("serialize");
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(JWT_HEADER),
+            URL_SAFE_NO_PAD.encode(claims)
+        );
+        let mut mac = self.mac();
+        mac.update(signing_input.as_bytes());
+        let sig = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signing_input}.{sig}")
+    }
+
+    /// Check signature and expiry, returning the subject.
+    pub fn verify(&self, token: &str) -> Result<String, ChatError> {
+        let mut parts = token.split('.');
+        let (Some(h), Some(p), Some(sig), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(rejected());
+        };
+
+        let header: Header = decode_json(h)?;
+        if header.alg != "HS256" {
+            return Err(rejected());
+        }
+        let sig = URL_SAFE_NO_PAD.decode(sig).map_err(|_| rejected())?;
+        let mut mac = self.mac();
+        mac.update(h.as_bytes());
+        mac.update(b".");
+        mac.update(p.as_bytes());
+        mac.verify_slice(&sig).map_err(|_| rejected())?;
+
+        let claims: Claims = decode_json(p)?;
+        if claims.exp.is_some_and(|exp| exp <= now_secs()) {
+            return Err(ChatError::Unauthorized("token expired".into()));
+        }
+        Ok(claims.sub)
+    }
+}
+
+impl Authenticator for TokenAuth {
+    fn authenticate(&self, creds: &Credentials) -> Result<Option<String>, ChatError> {
+        match creds {
+            Credentials::Bearer { token } => self.verify(token).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, ChatError> {
+    let raw = URL_SAFE_NO_PAD.decode(part).map_err(|_| rejected())?;
+    serde_json::from_slice(&raw).map_err(|_| rejected())
+}
+
+fn now_secs() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+// ---------------------------------------------------------------------------
+// chain
+// ---------------------------------------------------------------------------
+
+/// Asks each provider in turn; the first one that recognises the
+/// credentials decides.
+pub struct Chain(pub Vec<Box<dyn Authenticator>>);
+
+impl Authenticator for Chain {
+    fn authenticate(&self, creds: &Credentials) -> Result<Option<String>, ChatError> {
+        for provider in &self.0 {
+            if let Some(name) = provider.authenticate(creds)? {
+                return Ok(Some(name));
+            }
+        }
+        Err(ChatError::Unauthorized("unsupported credentials".into()))
+    }
+
+    fn knows(&self, name: &str) -> bool {
+        self.0.iter().any(|provider| provider.knows(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn password(user: &str, password: &str) -> Credentials {
+        Credentials::Password { user: user.into(), password: password.into() }
+    }
+
+    #[test]
+    fn user_file_verifies_argon2() {
+        let hash = hash_password("s3cret").unwrap();
+        let users = UserFile::parse(&format!("# users\nalice:{hash}\n")).unwrap();
+        assert_eq!(users.authenticate(&password("alice", "s3cret")).unwrap(), Some("alice".into()));
+        assert!(users.authenticate(&password("alice", "wrong")).is_err());
+        assert!(users.authenticate(&password("bob", "s3cret")).is_err());
+        assert!(users.knows("alice") && !users.knows("bob"));
+    }
+
+    #[test]
+    fn user_file_rejects_garbage() {
+        assert!(UserFile::parse("alice").is_err());
+        assert!(UserFile::parse("alice:plaintext").is_err());
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let auth = TokenAuth::new(b"k");
+        let token = auth.mint("alice", 60);
+        assert_eq!(auth.verify(&token).unwrap(), "alice");
+        assert!(TokenAuth::new(b"other").verify(&token).is_err());
+    }
+
+    #[test]
+    fn token_tamper_and_expiry() {
+        let auth = TokenAuth::new(b"k");
+        let token = auth.mint("alice", 0);
+        let forged = URL_SAFE_NO_PAD.encode(r#"{"sub":"mallory"}"#);
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[1] = &forged;
+        assert!(auth.verify(&parts.join(".")).is_err());
+
+        let claims = serde_json::to_vec(&Claims { sub: "alice".into(), exp: Some(1) }).unwrap();
+        let input = format!("{}.{}", URL_SAFE_NO_PAD.encode(JWT_HEADER), URL_SAFE_NO_PAD.encode(claims));
+        let mut mac = auth.mac();
+        mac.update(input.as_bytes());
+        let expired = format!("{input}.{}", URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()));
+        assert!(matches!(auth.verify(&expired), Err(ChatError::Unauthorized(m)) if m == "token expired"));
+    }
+
+    #[test]
+    fn chain_picks_matching_provider() {
+        let tokens = TokenAuth::new(b"k");
+        let token = tokens.mint("bob", 60);
+        let chain = Chain(vec![Box::new(UserFile::parse("").unwrap()), Box::new(tokens)]);
+        assert_eq!(chain.authenticate(&Credentials::Bearer { token }).unwrap(), Some("bob".into()));
+        assert!(chain.authenticate(&password("bob", "x")).is_err());
+        // token subjects are unknown until someone presents one
+        assert!(!chain.knows("bob"));
+    }
+
+    #[test]
+    fn claimed_names_are_bounded() {
+        let claimed = Claimed::default();
+        for i in 0..MAX_CLAIMED {
+            claimed.insert(&format!("user{i}"));
+        }
+        // authenticating again keeps a name fresh
+        claimed.insert("user0");
+        claimed.insert("late");
+        assert!(claimed.contains("user0") && claimed.contains("late"));
+        assert!(!claimed.contains("user1"));
+    }
+}
@@ -0,0 +1,177 @@
+//! Keeping connections honest.
+//!
+//! A client that vanishes without closing its TCP connection would stay in
+//! its rooms forever, so every connection pings its client each
+//! `ping_interval_secs` and gives up when no frame at all came back within
+//! `pong_timeout_secs` of a ping. Separately, `idle_timeout_secs` drops a
+//! client that stays connected but has sent no chat request for that long.
+//! Only posting, editing, deleting and reacting count ([`is_activity`]);
+//! pings, pongs, read marks, typing and lookups such as `RoomList` do not.
+//! A connection dropped either way leaves its rooms.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::protocol::ClientRequest;
+
+/// What is due on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    /// send a ping
+    Ping,
+    /// the client is gone or idle; close with this reason
+    Dead(&'static str),
+}
+
+/// The configured intervals, shared by every connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    ping_every: Option<Duration>,
+    pong_within: Duration,
+    idle_after: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn from_config(cfg: &Config) -> Self {
+        let secs = |s: u64| (s > 0).then(|| Duration::from_secs(s));
+        Self {
+            ping_every: secs(cfg.ping_interval_secs),
+            pong_within: Duration::from_secs(cfg.pong_timeout_secs),
+            idle_after: secs(cfg.idle_timeout_secs),
+        }
+    }
+}
+
+/// Whether `req` is a chat request, which keeps a connection from idling out.
+pub fn is_activity(req: &ClientRequest) -> bool {
+    matches!(
+        req,
+        ClientRequest::Message { .. }
+            | ClientRequest::DirectMessage { .. }
+            | ClientRequest::EditMessage { .. }
+            | ClientRequest::DeleteMessage { .. }
+            | ClientRequest::React { .. }
+            | ClientRequest::Unreact { .. }
+    )
+}
+
+/// Ping and timeout schedule of one connection.
+#[derive(Debug)]
+pub struct Heartbeat {
+    limits: Timeouts,
+    /// last frame of any kind
+    heard: Instant,
+    /// last chat request
+    active: Instant,
+    /// our unanswered ping
+    pinged: Option<Instant>,
+}
+
+impl Heartbeat {
+    pub fn new(limits: Timeouts, now: Instant) -> Self {
+        Self { limits, heard: now, active: now, pinged: None }
+    }
+
+    /// A frame arrived; `active` if it was a chat request.
+    pub fn heard(&mut self, now: Instant, active: bool) {
+        self.heard = now;
+        self.pinged = None;
+        if active {
+            self.active = now;
+        }
+    }
+
+    /// When [`Heartbeat::poll`] next has something to do, if ever.
+    pub fn due(&self) -> Option<Instant> {
+        let limits = self.limits;
+        let ping = match self.pinged {
+            Some(at) => Some(at + limits.pong_within),
+            None => limits.ping_every.map(|every| self.heard + every),
+        };
+        let idle = limits.idle_after.map(|after| self.active + after);
+        match (ping, idle) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// What is due at `now`; a `Ping` is counted as sent.
+    pub fn poll(&mut self, now: Instant) -> Option<Beat> {
+        let limits = self.limits;
+        if limits.idle_after.is_some_and(|after| now >= self.active + after) {
+            return Some(Beat::Dead("idle timeout"));
+        }
+        match self.pinged {
+            Some(at) if now >= at + limits.pong_within => Some(Beat::Dead("ping timeout")),
+            Some(_) => None,
+            None if limits.ping_every.is_some_and(|every| now >= self.heard + every) => {
+                self.pinged = Some(now);
+                Some(Beat::Ping)
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(ping: u64, pong: u64, idle: u64, now: Instant) -> Heartbeat {
+        let cfg = Config { ping_interval_secs: ping, pong_timeout_secs: pong, idle_timeout_secs: idle, ..Config::default() };
+        Heartbeat::new(Timeouts::from_config(&cfg), now)
+    }
+
+    #[test]
+    fn pings_quiet_clients_and_gives_up_without_an_answer() {
+        let t0 = Instant::now();
+        let secs = |s| t0 + Duration::from_secs(s);
+        let mut hb = heartbeat(20, 10, 0, t0);
+        assert_eq!(hb.due(), Some(secs(20)));
+        assert_eq!(hb.poll(secs(19)), None);
+        assert_eq!(hb.poll(secs(20)), Some(Beat::Ping));
+        assert_eq!(hb.due(), Some(secs(30)));
+        // the pong pushes the next ping back
+        hb.heard(secs(21), false);
+        assert_eq!(hb.due(), Some(secs(41)));
+        assert_eq!(hb.poll(secs(41)), Some(Beat::Ping));
+        assert_eq!(hb.poll(secs(50)), None);
+        assert_eq!(hb.poll(secs(51)), Some(Beat::Dead("ping timeout")));
+
+        assert_eq!(heartbeat(0, 10, 0, t0).due(), None);
+    }
+
+    #[test]
+    fn pongs_do_not_keep_an_idle_client() {
+        let t0 = Instant::now();
+        let secs = |s| t0 + Duration::from_secs(s);
+        let mut hb = heartbeat(20, 10, 60, t0);
+        hb.heard(secs(30), false);
+        hb.heard(secs(50), false);
+        assert_eq!(hb.due(), Some(secs(60)));
+        assert_eq!(hb.poll(secs(60)), Some(Beat::Dead("idle timeout")));
+
+        let mut hb = heartbeat(0, 10, 60, t0);
+        hb.heard(secs(59), true);
+        assert_eq!(hb.poll(secs(60)), None);
+        assert_eq!(hb.due(), Some(secs(119)));
+    }
+
+    #[test]
+    fn polling_room_lists_is_not_activity() {
+        let t0 = Instant::now();
+        let secs = |s| t0 + Duration::from_secs(s);
+        let mut hb = heartbeat(0, 10, 60, t0);
+        for s in (10..60).step_by(10) {
+            hb.heard(secs(s), is_activity(&ClientRequest::RoomList));
+        }
+        assert_eq!(hb.poll(secs(60)), Some(Beat::Dead("idle timeout")));
+
+        let msg = ClientRequest::Message { room: "rust".into(), text: "hi".into(), reply_to: None };
+        let mut hb = heartbeat(0, 10, 60, t0);
+        hb.heard(secs(50), is_activity(&msg));
+        assert_eq!(hb.poll(secs(60)), None);
+    }
+}
@@ -1,130 +1,1141 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
 use std::str;
-use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::sync::{mpsc, oneshot};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, timeout_at, Instant};
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 
+use crate::config::{Config, LagPolicy};
+use crate::direct::FEED_CAPACITY;
+use crate::error::ChatError;
 use crate::hub::HubCmd;
-use crate::protocol::{ClientRequest, ServerEvent};
+use crate::ids::unique_id;
+use crate::metrics::Metrics;
+use crate::moderation::ModAction;
+use crate::protocol::{
+    ClientFrame, ClientRequest, Credentials, LeaveReason, Replay, ServerEvent, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, SERVER_CAPABILITIES,
+};
+use crate::server::auth::{self, Authenticator, Claimed};
+use crate::server::heartbeat::{is_activity, Beat, Heartbeat, Timeouts};
+use crate::server::ratelimit::{Key, RateLimiter};
+use crate::server::resume::{self, Journal, Parking};
+use crate::server::tls::{self, TlsReloader};
+use crate::room::{Amendment, Membership};
+use crate::server::validate::Validator;
+use crate::storage::HistoryQuery;
 
-pub async fn start_ws_listener(addr: &str, hub_tx: mpsc::Sender<HubCmd>) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(addr).await?;
-    println!("WebSocket listening on: {}", addr);
+/// Activity is reported to the presence registry at most this often while
+/// the connection's rooms stay the same.
+const PRESENCE_REFRESH: Duration = Duration::from_secs(30);
+/// How long a closing connection may take to flush its close frame; a dead
+/// peer never will.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// Listener-wide state shared by every connection.
+#[derive(Clone)]
+struct Shared {
+    hub: mpsc::Sender<HubCmd>,
+    auth: Option<Arc<dyn Authenticator>>,
+    auth_required: bool,
+    claimed: Arc<Claimed>,
+    tls: Option<Arc<TlsReloader>>,
+    /// becomes `Some(ServerShutdown)` when the server starts shutting down
+    shutdown: watch::Receiver<Option<ServerEvent>>,
+    lag: LagLimits,
+    limiter: Arc<RateLimiter>,
+    validator: Arc<Validator>,
+    /// limit on incoming WebSocket messages and frames
+    max_frame_bytes: usize,
+    parking: Arc<Parking<Parked>>,
+    timeouts: Timeouts,
+}
+
+/// How room subscriptions that fall behind are handled.
+#[derive(Clone, Copy)]
+struct LagLimits {
+    policy: LagPolicy,
+    threshold: u64,
+}
+
+/// Accept connections until `shutdown` resolves, then drain them and stop
+/// the hub within `cfg.shutdown_timeout_secs`.
+pub async fn start_ws_listener<F>(cfg: &Config, hub_tx: mpsc::Sender<HubCmd>, shutdown: F) -> anyhow::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let auth = auth::from_config(cfg)?;
+    let tls = TlsReloader::from_config(cfg)?;
+    let client_certs = tls.as_ref().is_some_and(|t| t.requires_client_cert());
+    if cfg.auth_required && auth.is_none() && !client_certs {
+        anyhow::bail!(
+            "AUTH_REQUIRED is set but no AUTH_USERS_FILE, AUTH_TOKEN_SECRET or TLS_CLIENT_CA configured"
+        );
+    }
+    if let Some(tls) = &tls {
+        tls::reload_on_sighup(tls.clone())?;
+    }
+    let (notice_tx, notice_rx) = watch::channel(None);
+    let shared = Shared {
+        hub: hub_tx.clone(),
+        auth,
+        auth_required: cfg.auth_required,
+        claimed: Arc::default(),
+        tls,
+        shutdown: notice_rx,
+        lag: LagLimits { policy: cfg.lag_policy, threshold: cfg.lag_threshold },
+        limiter: Arc::new(RateLimiter::from_config(cfg)),
+        validator: Arc::new(Validator::from_config(cfg)),
+        max_frame_bytes: cfg.max_frame_bytes,
+        parking: Parking::new(Duration::from_secs(cfg.resume_grace_secs)),
+        timeouts: Timeouts::from_config(cfg),
+    };
+    // every connection task holds a clone; `recv` returns `None` once all are gone
+    let (conn_tx, mut conn_rx) = mpsc::channel::<()>(1);
+
+    let listener = TcpListener::bind(&cfg.server_addr).await?;
+    let scheme = if shared.tls.is_some() { "wss" } else { "ws" };
+    println!("WebSocket listening on: {scheme}://{}", cfg.server_addr);
+
+    tokio::pin!(shutdown);
     loop {
-        let (stream, _) = listener.accept().await?;
-        let hub_clone = hub_tx.clone();
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = &mut shutdown => break,
+        };
+        let shared = shared.clone();
+        let conn_guard = conn_tx.clone();
+        // grab the acceptor now so a reload never affects a handshake in flight
+        let acceptor = shared.tls.as_ref().map(|t| t.acceptor());
         tokio::spawn(async move {
-            if let Err(e) = handle_ws(stream, hub_clone).await {
+            let res = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let peer = tls::peer_name(stream.get_ref().1);
+                        handle_ws(stream, shared, addr.ip(), peer).await
+                    }
+                    Err(e) => Err(e.into()),
+                },
+                None => handle_ws(stream, shared, addr.ip(), None).await,
+            };
+            if let Err(e) = res {
                 eprintln!("connection error: {:?}", e);
             }
+            drop(conn_guard);
         });
     }
-}
 
-async fn handle_ws(stream: tokio::net::TcpStream, hub: mpsc::Sender<HubCmd>) -> anyhow::Result<()> {
-    let ws = accept_async(stream).await?;
-    let (mut ws_tx, mut ws_rx) = ws.split();
+    // stop accepting, tell every connection, wait for them to close, then
+    // let the hub stop the rooms so their history is flushed last
+    drop(listener);
+    println!("shutting down");
+    let deadline = Instant::now() + Duration::from_secs(cfg.shutdown_timeout_secs);
+    let reconnect_after = (cfg.shutdown_reconnect_secs > 0).then_some(cfg.shutdown_reconnect_secs);
+    let _ = notice_tx.send(Some(ServerEvent::ServerShutdown {
+        reason: "server shutting down".into(),
+        reconnect_after,
+    }));
+    drop(conn_tx);
+    if timeout_at(deadline, conn_rx.recv()).await.is_err() {
+        tracing::warn!("connections still open at shutdown deadline");
+    }
+    let (tx, rx) = oneshot::channel();
+    if hub_tx.send(HubCmd::Shutdown { resp: tx }).await.is_ok()
+        && timeout_at(deadline, rx).await.is_err()
+    {
+        tracing::warn!("rooms still running at shutdown deadline");
+    }
+    Ok(())
+}
 
-    // -- wait for Join or RoomList
-    let (room, name) = loop {
-        let msg = ws_rx.next().await.ok_or_else(|| anyhow::anyhow!("eof"))??;
-        let req: ClientRequest = serde_json::from_str(msg.to_text()?)?;
-        match req {
-            ClientRequest::Join { room, name } => break (room, name),
-            ClientRequest::RoomList => {
-                let (tx, rx) = oneshot::channel();
-                hub.send(HubCmd::GetRoomList { resp: tx }).await?;
-                let list = rx.await?;
-                let ev = ServerEvent::RoomList { rooms: list };
-                ws_tx.send(Message::Text(serde_json::to_string(&ev)?)).await?;
+/// Serve one connection from `ip`. `peer` is the name from a verified
+/// client certificate, if any.
+async fn handle_ws<S>(stream: S, shared: Shared, ip: IpAddr, peer: Option<String>) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // an `Authorization: Bearer <token>` upgrade header is checked before
+    // the handshake completes; a bad token gets a plain HTTP 401
+    let mut identity = peer;
+    let header_auth = shared.auth.clone();
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(shared.max_frame_bytes),
+        max_frame_size: Some(shared.max_frame_bytes),
+        ..WebSocketConfig::default()
+    };
+    #[allow(clippy::result_large_err)] // callback signature is fixed by tungstenite
+    let callback = |req: &Request, resp: Response| {
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let (Some(token), Some(auth)) = (token, header_auth) else {
+            return Ok(resp);
+        };
+        let creds = Credentials::Bearer { token: token.trim().to_string() };
+        match auth.authenticate(&creds) {
+            Ok(Some(name)) => {
+                identity = Some(name);
+                Ok(resp)
+            }
+            Ok(None) | Err(_) => {
+                let mut err = ErrorResponse::new(Some("invalid bearer token".into()));
+                *err.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(err)
             }
-            _ => {}
         }
     };
+    let ws = accept_hdr_async_with_config(stream, callback, Some(ws_config)).await?;
+    if let Some(name) = &identity {
+        shared.claimed.insert(name);
+    }
+    let (ws_tx, mut ws_rx) = ws.split();
 
-    // -- join room
-    let (join_tx, join_rx) = oneshot::channel();
-    hub.send(HubCmd::Join { room: room.clone(), name: name.clone(), resp: join_tx }).await?;
-    let mut bcast_rx = join_rx.await?;
+    // single writer task; everything else talks to the socket through `out`
+    let (push_tx, push_rx) = mpsc::channel::<Message>(32);
+    let (stop_writer, stop_rx) = oneshot::channel();
+    let writer = tokio::spawn(write_socket(ws_tx, push_rx, stop_rx));
 
-    // push channel -> websocket
-    let (push_tx, mut push_rx) = mpsc::channel::<Message>(32);
+    let mut shutdown = shared.shutdown;
+    let mut session = Session {
+        id: unique_id(),
+        ip,
+        hub: shared.hub,
+        // legacy clients that skip `Hello` stay on the oldest version
+        out: Outbox { tx: push_tx, version: MIN_PROTOCOL_VERSION },
+        rooms: HashMap::new(),
+        inbox: None,
+        seen: None,
+        joined_once: false,
+        auth: shared.auth,
+        auth_required: shared.auth_required,
+        claimed: shared.claimed,
+        identity,
+        lag: shared.lag,
+        limiter: shared.limiter,
+        validator: shared.validator,
+        parking: shared.parking,
+        resume_token: None,
+    };
 
-    // history replay
-    {
-        let (htx, hrx) = oneshot::channel();
-        hub.send(HubCmd::GetHistory { room: room.clone(), resp: htx }).await?;
-        if let Ok(hist) = hrx.await {
-            for frame in hist {
-                if let Ok(txt) = str::from_utf8(&frame) {
-                    push_tx.send(Message::Text(txt.to_owned())).await?;
+    let mut heartbeat = Heartbeat::new(shared.timeouts, Instant::now());
+    let mut timed_out = false;
+    let mut close_frame = None;
+    // however this ends, even by `?`, the session is released below
+    let served: anyhow::Result<()> = async {
+        loop {
+            let due = heartbeat.due();
+            let msg = tokio::select! {
+                msg = ws_rx.next() => match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(WsError::Capacity(e))) => {
+                        let reason = format!("message too big: {e}");
+                        close_frame = Some(CloseFrame { code: CloseCode::Size, reason: reason.into() });
+                        break;
+                    }
+                    _ => break,
+                },
+                // the writer stopped, e.g. a lagging room subscription closed us
+                _ = session.out.tx.closed() => break,
+                Ok(()) = shutdown.changed() => {
+                    let notice = shutdown.borrow().clone();
+                    if let Some(ev) = notice {
+                        let _ = session.out.send(&ev).await;
+                    }
+                    close_frame = Some(CloseFrame { code: CloseCode::Restart, reason: "server shutting down".into() });
+                    break;
                 }
-            }
-        }
-    }
-
-    // background push task
-    let (close_tx_raw, close_rx) = oneshot::channel::<()>();
-        let mut close_tx = Some(close_tx_raw);
-    let push_handle = tokio::spawn(async move {
-        tokio::select! {
-            _ = async {
-                loop {
-                    tokio::select! {
-                        Some(m) = push_rx.recv() => {
-                            if ws_tx.send(m).await.is_err() { break; }
+                _ = sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                    match heartbeat.poll(Instant::now()) {
+                        Some(Beat::Ping) => {
+                            // a full queue will ping again next time
+                            let _ = session.out.tx.try_send(Message::Ping(Vec::new()));
                         }
-                        Ok(frame) = bcast_rx.recv() => {
-                            if let Ok(txt) = str::from_utf8(&frame) {
-                                if ws_tx.send(Message::Text(txt.to_owned())).await.is_err() { break; }
-                            }
+                        Some(Beat::Dead(reason)) => {
+                            timed_out = true;
+                            close_frame = Some(CloseFrame { code: CloseCode::Away, reason: reason.into() });
+                            break;
                         }
+                        None => {}
+                    }
+                    continue;
+                }
+            };
+            heartbeat.heard(Instant::now(), false);
+            if !msg.is_text() { continue; }
+            let ClientFrame { id, req } = match ClientFrame::parse(msg.to_text()?) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    session.out.send(&error_event(None, &e.into())).await?;
+                    continue;
+                }
+            };
+            if is_activity(&req) {
+                heartbeat.heard(Instant::now(), true);
+            }
+            // v1 clients expect the server to hang up once they leave their room
+            let legacy_leave =
+                session.out.version < PROTOCOL_VERSION && matches!(req, ClientRequest::Leave { .. });
+            match session.handle(req).await {
+                Ok(()) => {
+                    session.open_inbox().await?;
+                    session.sync_presence(false).await?;
+                    if let Some(id) = id {
+                        session.out.send(&ServerEvent::Ack { id }).await?;
+                    }
+                }
+                Err(e) => {
+                    session.out.send(&error_event(id, &e)).await?;
+                    if matches!(e, ChatError::UnsupportedVersion(_)) {
+                        break;
                     }
+                    continue;
                 }
-            } => {},
-            _ = close_rx => {
-                let _ = ws_tx.send(Message::Close(None)).await;
             }
+            if legacy_leave && session.rooms.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    // a session that just lost its socket waits for the client to come back
+    if served.is_ok()
+        && close_frame.is_none()
+        && !session.rooms.is_empty()
+        && let Some(token) = session.resume_token.clone()
+    {
+        let _ = stop_writer.send(());
+        match writer.await {
+            Ok(Some((backlog, journal))) => {
+                let parking = session.parking.clone();
+                let owner = session.identity.clone();
+                parking.park(token, owner, Parked { session, backlog, journal }, |parked| async move {
+                    let mut session = parked.session;
+                    session.release(LeaveReason::Disconnected).await;
+                });
+            }
+            // we closed it ourselves, e.g. for lagging
+            _ => session.release(LeaveReason::Disconnected).await,
         }
+        return served;
+    }
+    // a client that stopped answering has left, it did not just drop
+    let reason = if timed_out { LeaveReason::TimedOut } else { LeaveReason::Disconnected };
+    session.release(reason).await;
+    let out = session.out.clone();
+    drop(session);
+    let abort = writer.abort_handle();
+    let closed = timeout(CLOSE_TIMEOUT, async move {
+        out.close(close_frame).await;
+        drop(out);
+        let _ = writer.await;
     });
+    if closed.await.is_err() {
+        abort.abort();
+    }
+    drop(stop_writer);
+    served
+}
+
+/// Write everything queued for the connection to its socket until told to
+/// stop, the queue closes, or a close frame went out. A stopped writer
+/// hands back the queue and what it wrote last, to resume from.
+async fn write_socket<W>(
+    mut ws_tx: W,
+    mut rx: mpsc::Receiver<Message>,
+    mut stop: oneshot::Receiver<()>,
+) -> Option<(mpsc::Receiver<Message>, Journal)>
+where
+    W: Sink<Message> + Unpin,
+{
+    let mut journal = Journal::default();
+    loop {
+        let m = tokio::select! {
+            biased;
+            _ = &mut stop => return Some((rx, journal)),
+            m = rx.recv() => m?,
+        };
+        let closing = matches!(m, Message::Close(_));
+        if let Message::Text(txt) = &m {
+            journal.record(txt);
+        }
+        if ws_tx.send(m).await.is_err() {
+            return Some((rx, journal));
+        }
+        if closing {
+            return None;
+        }
+    }
+}
+
+/// Pass a resumed session's queue on to the connection that took it over.
+async fn bridge(mut from: mpsc::Receiver<Message>, to: mpsc::Sender<Message>) {
+    while let Some(m) = from.recv().await {
+        if to.send(m).await.is_err() {
+            break;
+        }
+    }
+}
 
-    // main loop after join
-    while let Some(Ok(msg)) = ws_rx.next().await {
-        if !msg.is_text() { continue; }
-        let req: ClientRequest = serde_json::from_str(msg.to_text()?)?;
-        match req {
-            ClientRequest::Message { room, text } => {
+/// A session whose connection dropped, waiting for `Resume`.
+struct Parked {
+    session: Session,
+    /// frames queued for the session since its writer stopped
+    backlog: mpsc::Receiver<Message>,
+    journal: Journal,
+}
+
+/// A room this connection has joined.
+struct Joined {
+    name: String,
+    /// ends when the connection leaves, is evicted, or the room stops
+    forwarder: JoinHandle<()>,
+}
+
+/// Where this connection receives its user's direct messages.
+struct Inbox {
+    user: String,
+    forwarder: JoinHandle<()>,
+}
+
+/// What the presence registry last heard from this connection.
+struct Seen {
+    user: String,
+    /// room → our name there
+    rooms: HashMap<String, String>,
+    at: Instant,
+}
+
+/// Per-connection state: negotiated version, identity and joined rooms.
+struct Session {
+    /// sent as `session_id` in `Welcome`
+    id: String,
+    ip: IpAddr,
+    hub: mpsc::Sender<HubCmd>,
+    out: Outbox,
+    rooms: HashMap<String, Joined>,
+    /// set once the connection has authenticated, see [`Session::open_inbox`]
+    inbox: Option<Inbox>,
+    /// see [`Session::sync_presence`]
+    seen: Option<Seen>,
+    /// `Hello` and `Auth` are only accepted before the first `Join`
+    joined_once: bool,
+    auth: Option<Arc<dyn Authenticator>>,
+    auth_required: bool,
+    claimed: Arc<Claimed>,
+    /// verified user name; overrides the name given in `Join`
+    identity: Option<String>,
+    lag: LagLimits,
+    limiter: Arc<RateLimiter>,
+    validator: Arc<Validator>,
+    parking: Arc<Parking<Parked>>,
+    /// handed out with `SessionResumable`; see [`Session::offer_resume`]
+    resume_token: Option<String>,
+}
+
+impl Session {
+    /// Handle one request. Replies other than the final `Ack`/`Error` are
+    /// queued on `out`.
+    async fn handle(&mut self, req: ClientRequest) -> Result<(), ChatError> {
+        // rooms we were kicked or banned from
+        self.rooms.retain(|_, joined| !joined.forwarder.is_finished());
+        match self.validator.request(req)? {
+            ClientRequest::Hello { version, capabilities } => {
+                if self.joined_once {
+                    return Err(ChatError::BadRequest("Hello must precede Join".into()));
+                }
+                if version < MIN_PROTOCOL_VERSION {
+                    // answer in the newest dialect; the client cannot speak ours anyway
+                    self.out.version = PROTOCOL_VERSION;
+                    return Err(ChatError::UnsupportedVersion(version));
+                }
+                self.out.version = version.min(PROTOCOL_VERSION);
+                let shared = SERVER_CAPABILITIES
+                    .iter()
+                    .filter(|c| capabilities.iter().any(|have| have == *c))
+                    .map(|c| c.to_string())
+                    .collect();
+                let ev = ServerEvent::Welcome {
+                    version: self.out.version,
+                    server_capabilities: shared,
+                    session_id: self.id.clone(),
+                };
+                self.out.send(&ev).await?;
+                // identity from the upgrade header predates the handshake
+                if let Some(name) = self.identity.clone() {
+                    self.out.send(&ServerEvent::Authenticated { name }).await?;
+                }
+            }
+            ClientRequest::Auth(creds) => {
+                if self.joined_once {
+                    return Err(ChatError::BadRequest("Auth must precede Join".into()));
+                }
+                self.authenticate(creds).await?;
+            }
+            ClientRequest::Join { room, name, replay, password, invite } => {
+                let name = match &self.identity {
+                    Some(identity) => identity.clone(),
+                    None if self.auth_required => {
+                        return Err(ChatError::Unauthorized("authenticate before joining".into()));
+                    }
+                    None => {
+                        self.unclaimed(&name)?;
+                        name
+                    }
+                };
+                // a returning user that joins instead of resuming replaces
+                // its dropped sessions rather than colliding with their names
+                if !self.joined_once
+                    && let Some(user) = &self.identity
+                {
+                    for parked in self.parking.take_owned(user) {
+                        let mut session = parked.session;
+                        session.release(LeaveReason::Disconnected).await;
+                    }
+                }
+                self.join(room, name, replay, password, invite).await?;
+                self.offer_resume().await?
+            }
+            ClientRequest::Resume { token, last_seen_id } => {
+                if self.joined_once {
+                    return Err(ChatError::BadRequest("Resume must precede Join".into()));
+                }
+                if self.out.version < PROTOCOL_VERSION {
+                    return Err(ChatError::BadRequest("Resume needs protocol v2".into()));
+                }
+                let Parked { mut session, backlog, journal } = self
+                    .parking
+                    .take(&token)
+                    .ok_or_else(|| ChatError::Unauthorized("session expired or unknown".into()))?;
+                session.ip = self.ip;
+                session.resume_token = None;
+                // this connection's own session was only a handshake
+                let mut fresh = std::mem::replace(self, session);
+                fresh.release(LeaveReason::Disconnected).await;
+                let rooms = self.rooms.keys().cloned().collect();
+                fresh.out.send(&ServerEvent::Resumed { session_id: self.id.clone(), rooms }).await?;
+                for frame in journal.after(last_seen_id.as_deref()) {
+                    fresh.out.tx.send(Message::Text(frame)).await.map_err(|_| ChatError::Custom("connection closed".into()))?;
+                }
+                tokio::spawn(bridge(backlog, fresh.out.tx.clone()));
+                self.offer_resume().await?
+            }
+            ClientRequest::Leave { room } => {
+                let joined = self.rooms.remove(&room).ok_or(ChatError::NotMember(room.clone()))?;
+                let left = self.hub.send(HubCmd::Leave { room, name: joined.name, reason: LeaveReason::Left }).await;
+                joined.forwarder.abort();
+                left.map_err(hub_gone)?;
+            }
+            ClientRequest::Typing { room, state } => {
+                let name = self.name_in(&room)?.to_string();
+                // every update reaches the whole room, like a message
+                self.limiter.check(&self.limit_keys(), Some(&room))?;
+                self.hub.send(HubCmd::Typing { room, name, state }).await.map_err(hub_gone)?;
+            }
+            ClientRequest::MarkRead { room, up_to_id, receipt } => {
+                let name = self.name_in(&room)?.to_string();
+                let (tx, rx) = oneshot::channel();
+                self.hub
+                    .send(HubCmd::MarkRead { room, name, id: up_to_id, receipt, resp: tx })
+                    .await
+                    .map_err(hub_gone)?;
+                rx.await.map_err(|_| ChatError::Custom("mark read failed".into()))??;
+            }
+            ClientRequest::Rename { room, name } => {
+                if self.identity.is_some() {
+                    return Err(ChatError::BadRequest("name is fixed by authentication".into()));
+                }
+                let old = self.name_in(&room)?.to_string();
+                if old == name {
+                    return Ok(());
+                }
+                self.unclaimed(&name)?;
+                // every rename is announced to the room
+                self.limiter.check(&self.limit_keys(), Some(&room))?;
+                let (tx, rx) = oneshot::channel();
+                self.hub
+                    .send(HubCmd::Rename { room: room.clone(), old, new: name.clone(), resp: tx })
+                    .await
+                    .map_err(hub_gone)?;
+                rx.await.map_err(|_| ChatError::Custom("rename failed".into()))??;
+                if let Some(joined) = self.rooms.get_mut(&room) {
+                    joined.name = name;
+                }
+            }
+            ClientRequest::Message { room, text, reply_to } => {
+                let name = self.name_in(&room)?.to_string();
+                self.limiter.check(&self.limit_keys(), Some(&room))?;
+                // seq and id are stamped by the room task
                 let ev = ServerEvent::NewMessage {
                     room: room.clone(),
-                    name: name.clone(),
+                    seq: 0,
+                    id: String::new(),
+                    name,
                     text,
                     ts: chrono::Utc::now().timestamp_millis() as u64,
+                    edited: None,
+                    deleted: false,
+                    reactions: Vec::new(),
+                    reply_to,
+                    thread_id: None,
+                    replies: 0,
                 };
-                hub.send(HubCmd::Send { room, event: ev }).await?;
+                let (tx, rx) = oneshot::channel();
+                self.hub.send(HubCmd::Send { room, event: ev, resp: tx }).await.map_err(hub_gone)?;
+                rx.await.map_err(|_| ChatError::Custom("send failed".into()))??;
             }
-            ClientRequest::Leave { room } => {
-                hub.send(HubCmd::Leave { room: room.clone(), name: name.clone() }).await?;
-                if let Some(tx) = close_tx.take() {
-                    let _ = tx.send(());
-                }
-                break;
+            ClientRequest::EditMessage { room, id, text } => {
+                self.limiter.check(&self.limit_keys(), Some(&room))?;
+                self.amend(room, id, Amendment::Edit(text)).await?
+            }
+            ClientRequest::DeleteMessage { room, id } => self.amend(room, id, Amendment::Delete).await?,
+            ClientRequest::React { room, message_id, emoji } => {
+                self.limiter.check(&self.limit_keys(), Some(&room))?;
+                self.amend(room, message_id, Amendment::React(emoji)).await?
+            }
+            ClientRequest::Unreact { room, message_id, emoji } => {
+                self.limiter.check(&self.limit_keys(), Some(&room))?;
+                self.amend(room, message_id, Amendment::Unreact(emoji)).await?
+            }
+            ClientRequest::Kick { room, name, reason } => {
+                self.moderate(room, ModAction::Kick { name, reason }).await?
+            }
+            ClientRequest::Ban { room, name, duration, reason } => {
+                self.moderate(room, ModAction::Ban { name, duration, reason }).await?
+            }
+            ClientRequest::Unban { room, name } => self.moderate(room, ModAction::Unban { name }).await?,
+            ClientRequest::Mute { room, name, duration } => {
+                self.moderate(room, ModAction::Mute { name, duration }).await?
+            }
+            ClientRequest::Unmute { room, name } => self.moderate(room, ModAction::Unmute { name }).await?,
+            ClientRequest::SetRole { room, name, role } => {
+                self.moderate(room, ModAction::SetRole { name, role }).await?
+            }
+            ClientRequest::SetVisibility { room, visibility } => {
+                self.moderate(room, ModAction::SetVisibility(visibility)).await?
+            }
+            ClientRequest::SetPassword { room, password } => {
+                self.moderate(room, ModAction::SetPassword(password)).await?
+            }
+            ClientRequest::CreateInvite { room, ttl } => self.moderate(room, ModAction::CreateInvite { ttl }).await?,
+            ClientRequest::RevokeInvite { room, token } => {
+                self.moderate(room, ModAction::RevokeInvite { token }).await?
             }
             ClientRequest::Members { room } => {
+                // members only, so nobody else learns whether a hidden room exists
+                self.name_in(&room)?;
                 let (tx, rx) = oneshot::channel();
-                hub.send(HubCmd::GetMembers { room: room.clone(), resp: tx }).await?;
-                if let Ok(list) = rx.await {
-                    let ev = ServerEvent::MemberList { room, members: list };
-                    push_tx.send(Message::Text(serde_json::to_string(&ev)?)).await?;
+                self.hub
+                    .send(HubCmd::GetMembers { room: room.clone(), resp: tx })
+                    .await
+                    .map_err(hub_gone)?;
+                match rx.await.ok().flatten() {
+                    None => return Err(ChatError::UnknownRoom(room)),
+                    Some(members) => self.out.send(&ServerEvent::MemberList { room, members }).await?,
                 }
             }
-            ClientRequest::Join { .. } | ClientRequest::RoomList => {}
+            ClientRequest::History { room, before, limit, thread } => {
+                self.name_in(&room)?;
+                // fetch one extra record to learn whether an older page exists
+                let limit = limit.max(1);
+                let query = HistoryQuery { before, since: None, limit: limit.saturating_add(1) };
+                let page = fetch_history(&self.hub, &room, query, thread.clone()).await?;
+                let (messages, next_before) = page_events(page, limit);
+                self.out.send(&ServerEvent::HistoryPage { room, messages, next_before, thread }).await?;
+            }
+            ClientRequest::DirectMessage { to, text } => {
+                let from = self.user()?.to_string();
+                self.known(&to)?;
+                self.limiter.check(&self.limit_keys(), None)?;
+                let (tx, rx) = oneshot::channel();
+                self.hub.send(HubCmd::Direct { from, to, text, resp: tx }).await.map_err(hub_gone)?;
+                rx.await.map_err(|_| ChatError::Custom("send failed".into()))??;
+            }
+            ClientRequest::DirectHistory { with, before, limit } => {
+                let user = self.user()?.to_string();
+                self.known(&with)?;
+                let limit = limit.max(1);
+                let query = HistoryQuery { before, since: None, limit: limit.saturating_add(1) };
+                let (tx, rx) = oneshot::channel();
+                self.hub
+                    .send(HubCmd::GetDirectHistory { user, with: with.clone(), query, resp: tx })
+                    .await
+                    .map_err(hub_gone)?;
+                let (messages, next_before) = page_events(rx.await.unwrap_or_default(), limit);
+                self.out.send(&ServerEvent::DirectHistoryPage { with, messages, next_before }).await?;
+            }
+            ClientRequest::SetStatus { away, status } => {
+                // every change reaches all the user's rooms
+                self.limiter.check(&self.limit_keys(), None)?;
+                self.sync_presence(true).await?;
+                let user = self
+                    .seen
+                    .as_ref()
+                    .map(|seen| seen.user.clone())
+                    .ok_or_else(|| ChatError::BadRequest("join a room or authenticate first".into()))?;
+                let (tx, rx) = oneshot::channel();
+                self.hub.send(HubCmd::SetStatus { user, away, status, resp: tx }).await.map_err(hub_gone)?;
+                rx.await.map_err(|_| ChatError::Custom("status update failed".into()))??;
+            }
+            ClientRequest::WhoIs { name } => {
+                let (tx, rx) = oneshot::channel();
+                self.hub.send(HubCmd::WhoIs { name, resp: tx }).await.map_err(hub_gone)?;
+                let info = rx.await.map_err(|_| ChatError::Custom("lookup failed".into()))?;
+                self.out.send(&info).await?;
+            }
+            ClientRequest::RoomList => {
+                let (tx, rx) = oneshot::channel();
+                let joined = self.rooms.iter().map(|(room, joined)| (room.clone(), joined.name.clone())).collect();
+                self.hub.send(HubCmd::GetRoomList { joined, resp: tx }).await.map_err(hub_gone)?;
+                let (rooms, mut unread) = rx.await.unwrap_or_default();
+                if self.out.version < PROTOCOL_VERSION {
+                    unread.clear(); // v1 knows only the names
+                }
+                self.out.send(&ServerEvent::RoomList { rooms, unread }).await?;
+            }
         }
+        Ok(())
     }
 
-    if let Some(tx) = close_tx.take() {
-        let _ = tx.send(());
+    async fn join(
+        &mut self,
+        room: String,
+        name: String,
+        replay: Option<Replay>,
+        password: Option<String>,
+        invite: Option<String>,
+    ) -> Result<(), ChatError> {
+        if self.rooms.contains_key(&room) {
+            return Err(ChatError::BadRequest(format!("already joined {room}")));
+        }
+
+        let (join_tx, join_rx) = oneshot::channel();
+        self.hub
+            .send(HubCmd::Join {
+                room: room.clone(),
+                name: name.clone(),
+                verified: self.identity.is_some(),
+                password,
+                invite,
+                resp: join_tx,
+            })
+            .await
+            .map_err(hub_gone)?;
+        let membership = join_rx.await.map_err(|_| ChatError::Custom("join failed".into()))??;
+        self.joined_once = true;
+
+        // history replay
+        let query = match replay {
+            None => Some(HistoryQuery::latest(usize::MAX)),
+            Some(Replay::Last(n)) => Some(HistoryQuery::latest(n)),
+            Some(Replay::Since(ts)) => Some(HistoryQuery { before: None, since: Some(ts), limit: usize::MAX }),
+            Some(Replay::None) => None,
+        };
+        if let Some(query) = query {
+            for (_, frame) in fetch_history(&self.hub, &room, query, None).await? {
+                self.out.send_frame(&frame).await?;
+            }
+        }
+
+        // room broadcast -> writer
+        let forwarder = tokio::spawn(forward_room(room.clone(), membership, self.out.clone(), self.lag));
+        self.rooms.insert(room, Joined { name, forwarder });
+        Ok(())
+    }
+
+    /// Hand out a resume token, once, to a v2 session in a room.
+    async fn offer_resume(&mut self) -> Result<(), ChatError> {
+        let grace = self.parking.grace();
+        if self.resume_token.is_some() || grace.is_zero() || self.out.version < PROTOCOL_VERSION {
+            return Ok(());
+        }
+        let token = resume::new_token();
+        self.resume_token = Some(token.clone());
+        self.out.send(&ServerEvent::SessionResumable { token, grace_secs: grace.as_secs() }).await
+    }
+
+    /// Leave the session's rooms for `reason` and let go of its inbox and
+    /// presence. Later calls find nothing left to release.
+    async fn release(&mut self, reason: LeaveReason) {
+        for (room, joined) in self.rooms.drain() {
+            // a finished feed was evicted, so the room already dropped us, or
+            // closed for lagging, so the room drops us on its next sweep
+            if !joined.forwarder.is_finished() {
+                let _ = self.hub.send(HubCmd::Leave { room, name: joined.name, reason }).await;
+            }
+            joined.forwarder.abort();
+        }
+        if let Some(inbox) = self.inbox.take() {
+            inbox.forwarder.abort();
+            let _ = self.hub.send(HubCmd::Unregister { user: inbox.user, session: self.id.clone() }).await;
+        }
+        if let Some(seen) = self.seen.take() {
+            let _ = self.hub.send(HubCmd::Offline { user: seen.user, session: self.id.clone() }).await;
+        }
+    }
+
+    /// Change message `id` in `room` as this connection's member there.
+    async fn amend(&mut self, room: String, id: String, change: Amendment) -> Result<(), ChatError> {
+        let actor = self.name_in(&room)?.to_string();
+        let (tx, rx) = oneshot::channel();
+        self.hub
+            .send(HubCmd::Amend { room, actor, id, change, resp: tx })
+            .await
+            .map_err(hub_gone)?;
+        rx.await.map_err(|_| ChatError::Custom("edit failed".into()))?
+    }
+
+    /// Run a moderation action in `room` as this connection's member there,
+    /// passing on any reply meant for this connection alone.
+    async fn moderate(&mut self, room: String, action: ModAction) -> Result<(), ChatError> {
+        let actor = self.name_in(&room)?.to_string();
+        let (tx, rx) = oneshot::channel();
+        self.hub
+            .send(HubCmd::Moderate { room, actor, action, resp: tx })
+            .await
+            .map_err(hub_gone)?;
+        let reply = rx.await.map_err(|_| ChatError::Custom("moderation failed".into()))??;
+        if let Some(event) = reply {
+            self.out.send(&event).await?;
+        }
+        Ok(())
+    }
+
+    /// Verify `creds` with the configured providers and adopt the identity.
+    async fn authenticate(&mut self, creds: Credentials) -> Result<(), ChatError> {
+        // the inbox and every room membership belong to the first identity
+        if self.identity.is_some() {
+            return Err(ChatError::BadRequest("already authenticated".into()));
+        }
+        let auth = self
+            .auth
+            .clone()
+            .ok_or_else(|| ChatError::BadRequest("authentication is not enabled".into()))?;
+        // argon2 is deliberately slow; keep it off the async workers
+        let name = tokio::task::spawn_blocking(move || auth.authenticate(&creds))
+            .await
+            .map_err(|e| ChatError::Custom(e.to_string()))??
+            .ok_or_else(|| ChatError::Unauthorized("unsupported credentials".into()))?;
+        self.claimed.insert(&name);
+        self.identity = Some(name.clone());
+        self.out.send(&ServerEvent::Authenticated { name }).await
+    }
+
+    /// Whether `name` belongs to a registered user or someone has
+    /// authenticated as it.
+    fn registered(&self, name: &str) -> bool {
+        self.auth.as_ref().is_some_and(|auth| auth.knows(name)) || self.claimed.contains(name)
+    }
+
+    /// Refuse a free-form `name` that is [`Session::registered`].
+    fn unclaimed(&self, name: &str) -> Result<(), ChatError> {
+        if self.registered(name) {
+            return Err(ChatError::Unauthorized(format!("{name} is a registered name; authenticate to use it")));
+        }
+        Ok(())
+    }
+
+    /// Direct messages only go to [`Session::registered`] users, so made-up
+    /// names never get a queue or a conversation.
+    fn known(&self, name: &str) -> Result<(), ChatError> {
+        if !self.registered(name) {
+            return Err(ChatError::BadRequest(format!("no user named {name}")));
+        }
+        Ok(())
+    }
+
+    /// Start receiving direct messages once the connection has authenticated
+    /// and speaks a protocol version that has them. A free-form room name
+    /// proves nothing, so it never gets an inbox.
+    async fn open_inbox(&mut self) -> Result<(), ChatError> {
+        if self.inbox.is_some() || self.out.version < PROTOCOL_VERSION {
+            return Ok(());
+        }
+        let Some(user) = self.identity.clone() else { return Ok(()) };
+        let (feed, mut rx) = mpsc::channel::<Bytes>(FEED_CAPACITY);
+        self.hub
+            .send(HubCmd::Register { user: user.clone(), session: self.id.clone(), feed })
+            .await
+            .map_err(hub_gone)?;
+        let out = self.out.clone();
+        let forwarder = tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                if out.send_frame(&frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.inbox = Some(Inbox { user, forwarder });
+        Ok(())
+    }
+
+    /// Tell the presence registry this connection is active and which rooms
+    /// it is in; skipped if it was told within `PRESENCE_REFRESH` and the
+    /// rooms are the same, unless `force`d.
+    async fn sync_presence(&mut self, force: bool) -> Result<(), ChatError> {
+        let Some(user) = self.seen.as_ref().map(|seen| seen.user.clone()).or_else(|| self.user_name()) else {
+            return Ok(());
+        };
+        let rooms: HashMap<String, String> =
+            self.rooms.iter().map(|(room, joined)| (room.clone(), joined.name.clone())).collect();
+        let fresh = self.seen.as_ref().is_some_and(|seen| seen.rooms == rooms && seen.at.elapsed() < PRESENCE_REFRESH);
+        if fresh && !force {
+            return Ok(());
+        }
+        self.hub
+            .send(HubCmd::Presence { user: user.clone(), session: self.id.clone(), rooms: rooms.clone() })
+            .await
+            .map_err(hub_gone)?;
+        self.seen = Some(Seen { user, rooms, at: Instant::now() });
+        Ok(())
+    }
+
+    /// The authenticated name, else the name of a joined room.
+    fn user_name(&self) -> Option<String> {
+        match (&self.identity, self.rooms.values().next()) {
+            (Some(name), _) => Some(name.clone()),
+            (None, Some(joined)) => Some(joined.name.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// Name this connection sends and receives direct messages as.
+    fn user(&self) -> Result<&str, ChatError> {
+        self.inbox
+            .as_ref()
+            .map(|inbox| inbox.user.as_str())
+            .ok_or_else(|| ChatError::Unauthorized("authenticate to use direct messages".into()))
+    }
+
+    /// Rate-limit buckets this connection's messages are charged to.
+    fn limit_keys(&self) -> Vec<Key> {
+        let mut keys = vec![Key::Conn(self.id.clone()), Key::Ip(self.ip)];
+        if let Some(user) = &self.identity {
+            keys.push(Key::User(user.clone()));
+        }
+        keys
+    }
+
+    /// Name this connection uses in `room`, or `NotMember`.
+    fn name_in(&self, room: &str) -> Result<&str, ChatError> {
+        self.rooms
+            .get(room)
+            .map(|j| j.name.as_str())
+            .ok_or_else(|| ChatError::NotMember(room.to_string()))
+    }
+}
+
+/// Outbound queue of one connection, aware of the negotiated protocol version.
+#[derive(Clone)]
+struct Outbox {
+    tx: mpsc::Sender<Message>,
+    version: u32,
+}
+
+impl Outbox {
+    /// Queue `ev` unless the session's protocol version predates it.
+    async fn send(&self, ev: &ServerEvent) -> Result<(), ChatError> {
+        if ev.since_version() > self.version {
+            return Ok(());
+        }
+        let msg = Message::Text(serde_json::to_string(ev)?);
+        self.tx.send(msg).await.map_err(|_| ChatError::Custom("connection closed".into()))
+    }
+
+    /// Queue a pre-encoded room frame, applying the same version filter.
+    async fn send_frame(&self, frame: &[u8]) -> Result<(), ChatError> {
+        let Ok(txt) = str::from_utf8(frame) else { return Ok(()) };
+        if self.version < PROTOCOL_VERSION {
+            match serde_json::from_str::<ServerEvent>(txt) {
+                // v1 cannot show a tombstone; it just never sees the message
+                Ok(ServerEvent::NewMessage { deleted: true, .. }) => return Ok(()),
+                Ok(ServerEvent::UserLeft { room, name, reason: Some(_) }) => {
+                    return self.send(&ServerEvent::UserLeft { room, name, reason: None }).await;
+                }
+                Ok(ev) if ev.since_version() <= self.version => {}
+                // v1 has no rename; show it as the old name leaving and the new one joining
+                Ok(ServerEvent::UserRenamed { room, old, new }) => {
+                    self.send(&ServerEvent::UserLeft { room: room.clone(), name: old, reason: None }).await?;
+                    return self.send(&ServerEvent::UserJoined { room, name: new }).await;
+                }
+                Ok(ServerEvent::UserKicked { room, name, .. } | ServerEvent::UserBanned { room, name, .. }) => {
+                    return self.send(&ServerEvent::UserLeft { room, name, reason: None }).await;
+                }
+                _ => return Ok(()),
+            }
+        }
+        self.tx
+            .send(Message::Text(txt.to_owned()))
+            .await
+            .map_err(|_| ChatError::Custom("connection closed".into()))
+    }
+
+    /// Ask the writer to send a close frame and stop.
+    async fn close(&self, frame: Option<CloseFrame<'static>>) {
+        let _ = self.tx.send(Message::Close(frame)).await;
+    }
+}
+
+/// Split a page fetched with one record more than `limit` into its events
+/// and the cursor of the next older page, if there is one.
+fn page_events(mut page: Vec<(u64, Bytes)>, limit: usize) -> (Vec<ServerEvent>, Option<u64>) {
+    let next_before = if page.len() > limit {
+        page.remove(0);
+        page.first().map(|(idx, _)| *idx)
+    } else {
+        None
+    };
+    let messages = page.iter().filter_map(|(_, frame)| serde_json::from_slice(frame).ok()).collect();
+    (messages, next_before)
+}
+
+/// Pump one room's broadcast frames into the connection's outbox.
+///
+/// A subscriber that falls behind is told how much it missed with `Gap`;
+/// under [`LagPolicy::Disconnect`] it is closed once the total reaches the
+/// threshold, freeing its broadcast slot. Eviction ends the pump once the
+/// frames queued before it, including the kick itself, are delivered.
+async fn forward_room(room: String, membership: Membership, out: Outbox, lag: LagLimits) {
+    let Membership { events: mut rx, mut evicted } = membership;
+    let metrics = Metrics::global();
+    let mut missed_total = 0u64;
+    loop {
+        let next = tokio::select! {
+            biased;
+            next = rx.recv() => next,
+            _ = &mut evicted => break,
+        };
+        match next {
+            Ok(frame) => {
+                if out.send_frame(&frame).await.is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                Metrics::incr(&metrics.lag_events, 1);
+                Metrics::incr(&metrics.lag_missed, missed);
+                missed_total += missed;
+                tracing::debug!(room=%room, missed, "subscriber lagged");
+                if out.send(&ServerEvent::Gap { room: room.clone(), missed }).await.is_err() {
+                    break;
+                }
+                if lag.policy == LagPolicy::Disconnect && missed_total >= lag.threshold {
+                    Metrics::incr(&metrics.lag_disconnects, 1);
+                    let frame = CloseFrame { code: CloseCode::Policy, reason: "too slow".into() };
+                    out.close(Some(frame)).await;
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+fn error_event(id: Option<String>, err: &ChatError) -> ServerEvent {
+    ServerEvent::Error { id, code: err.code(), message: err.to_string(), retry_after: err.retry_after() }
+}
+
+fn hub_gone<T>(_: mpsc::error::SendError<T>) -> ChatError {
+    ChatError::Custom("chat hub unavailable".into())
+}
+
+async fn fetch_history(
+    hub: &mpsc::Sender<HubCmd>,
+    room: &str,
+    query: HistoryQuery,
+    thread: Option<String>,
+) -> Result<Vec<(u64, Bytes)>, ChatError> {
+    let (tx, rx) = oneshot::channel();
+    hub.send(HubCmd::GetHistory { room: room.to_string(), query, thread, resp: tx })
+        .await
+        .map_err(hub_gone)?;
+    Ok(rx.await.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox() -> (Outbox, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(64);
+        (Outbox { tx, version: PROTOCOL_VERSION }, rx)
+    }
+
+    /// Membership over `events`, plus the room's end of the eviction signal.
+    fn membership(events: broadcast::Receiver<Bytes>) -> (Membership, oneshot::Sender<()>) {
+        let (evict, evicted) = oneshot::channel();
+        (Membership { events, evicted }, evict)
+    }
+
+    fn event(msg: Message) -> Option<ServerEvent> {
+        match msg {
+            Message::Text(txt) => serde_json::from_str(&txt).ok(),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_subscriber_gets_gap() {
+        let (tx, rx) = broadcast::channel::<Bytes>(2);
+        for i in 0..5 {
+            tx.send(Bytes::from(format!(r#"{{"UserJoined":{{"room":"r","name":"u{i}"}}}}"#))).unwrap();
+        }
+        drop(tx);
+        let (out, mut sent) = outbox();
+        let lag = LagLimits { policy: LagPolicy::Notify, threshold: 1 };
+        let (membership, _evict) = membership(rx);
+        forward_room("r".into(), membership, out, lag).await;
+
+        assert_eq!(event(sent.recv().await.unwrap()), Some(ServerEvent::Gap { room: "r".into(), missed: 3 }));
+        // the two newest frames still arrive
+        assert!(matches!(event(sent.recv().await.unwrap()), Some(ServerEvent::UserJoined { name, .. }) if name == "u3"));
+        assert!(matches!(event(sent.recv().await.unwrap()), Some(ServerEvent::UserJoined { name, .. }) if name == "u4"));
+    }
+
+    #[tokio::test]
+    async fn disconnect_policy_closes_over_threshold() {
+        let (tx, rx) = broadcast::channel::<Bytes>(1);
+        for _ in 0..4 {
+            tx.send(Bytes::from_static(b"{}")).unwrap();
+        }
+        let (out, mut sent) = outbox();
+        let lag = LagLimits { policy: LagPolicy::Disconnect, threshold: 3 };
+        let (membership, _evict) = membership(rx);
+        forward_room("r".into(), membership, out, lag).await;
+
+        assert!(matches!(event(sent.recv().await.unwrap()), Some(ServerEvent::Gap { missed: 3, .. })));
+        assert!(matches!(sent.recv().await.unwrap(), Message::Close(Some(f)) if f.code == CloseCode::Policy));
+    }
+
+    #[tokio::test]
+    async fn eviction_delivers_the_kick_first() {
+        let (tx, rx) = broadcast::channel::<Bytes>(8);
+        let (membership, evict) = membership(rx);
+        let kicked = ServerEvent::UserKicked { room: "r".into(), name: "bob".into(), by: "alice".into(), reason: None };
+        tx.send(Bytes::from(serde_json::to_vec(&kicked).unwrap())).unwrap();
+        evict.send(()).unwrap();
+        let (out, mut sent) = outbox();
+        let lag = LagLimits { policy: LagPolicy::Notify, threshold: 1 };
+        // returns although the room's broadcast is still open
+        forward_room("r".into(), membership, out, lag).await;
+        assert_eq!(event(sent.recv().await.unwrap()), Some(kicked));
+        drop(tx);
     }
-    let _ = push_handle.await;
-    Ok(())
 }
@@ -1 +1,7 @@
-pub mod listener;
\ No newline at end of file
+pub mod auth;
+pub mod heartbeat;
+pub mod listener;
+pub mod ratelimit;
+pub mod resume;
+pub mod tls;
+pub mod validate;
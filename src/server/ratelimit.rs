@@ -0,0 +1,275 @@
+//! Token-bucket flood control for chat messages.
+//!
+//! Every message is charged against several buckets at once: the
+//! connection, the authenticated user (if any) and the source IP, so
+//! neither parallel sockets nor reconnecting gets around the limit. Rooms
+//! may override the global rate. The IP bucket is shared by everyone behind
+//! one address, so it has its own, larger rate, counted across all rooms. A
+//! sender rejected `mute_after` times in a row is muted for `mute_secs`;
+//! strikes go only to the connection or user whose own bucket ran out, never
+//! to an IP, so one flooder does not mute their neighbours.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, RateLimit};
+use crate::error::ChatError;
+use crate::metrics::Metrics;
+
+/// Stale buckets are swept every this many checks.
+const PRUNE_EVERY: u32 = 4096;
+
+/// Who a message is charged to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    Conn(String),
+    User(String),
+    Ip(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Self { tokens: limit.burst as f64, last: now, limit }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_sec).min(self.limit.burst as f64);
+        self.last = now;
+    }
+
+    /// Seconds until a token is available (0 if one is now).
+    fn wait(&self) -> f64 {
+        if self.tokens >= 1.0 { 0.0 } else { (1.0 - self.tokens) / self.limit.per_sec }
+    }
+}
+
+#[derive(Default)]
+struct Offender {
+    /// rejections since the last accepted message
+    strikes: u32,
+    muted_until: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    /// `(key, room)`; room is `None` for the global limit
+    buckets: HashMap<(Key, Option<String>), Bucket>,
+    offenders: HashMap<Key, Offender>,
+    checks: u32,
+}
+
+/// Shared limiter for all connections.
+pub struct RateLimiter {
+    global: Option<RateLimit>,
+    rooms: HashMap<String, Option<RateLimit>>,
+    ip: Option<RateLimit>,
+    mute_after: u32,
+    mute_for: Duration,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            global: cfg.rate_limit,
+            rooms: cfg.room_rate_limits.clone(),
+            ip: cfg.ip_rate_limit,
+            mute_after: cfg.mute_after,
+            mute_for: Duration::from_secs(cfg.mute_secs),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Charge one message in `room` (`None` for a direct message) to every
+    /// key, or explain how long to wait.
+    pub fn check(&self, keys: &[Key], room: Option<&str>) -> Result<(), ChatError> {
+        self.check_at(keys, room, Instant::now())
+    }
+
+    fn check_at(&self, keys: &[Key], room: Option<&str>, now: Instant) -> Result<(), ChatError> {
+        let mut st = self.state.lock().expect("rate limiter lock");
+        st.checks = st.checks.wrapping_add(1);
+        if st.checks.is_multiple_of(PRUNE_EVERY) {
+            st.prune(now);
+        }
+
+        let muted = keys
+            .iter()
+            .filter_map(|k| st.offenders.get(k)?.muted_until)
+            .filter(|until| *until > now)
+            .max();
+        if let Some(until) = muted {
+            return Err(ChatError::Muted(ceil_secs(until - now)));
+        }
+
+        let (limit, scope) = match room.filter(|r| self.rooms.contains_key(*r)) {
+            Some(room) => (self.rooms[room], Some(room.to_string())),
+            None => (self.global, None),
+        };
+        let charged: Vec<_> = keys
+            .iter()
+            .filter_map(|key| match key {
+                Key::Ip(_) => Some(((key.clone(), None), self.ip?)),
+                _ => Some(((key.clone(), scope.clone()), limit?)),
+            })
+            .collect();
+        if charged.is_empty() {
+            return Ok(());
+        }
+
+        // all buckets must have a token before any is charged
+        let mut wait: f64 = 0.0;
+        let mut empty = Vec::new();
+        for (bucket_key, limit) in &charged {
+            let bucket = st
+                .buckets
+                .entry(bucket_key.clone())
+                .or_insert_with(|| Bucket::full(*limit, now));
+            bucket.refill(now);
+            if bucket.wait() > 0.0 && !matches!(bucket_key.0, Key::Ip(_)) {
+                empty.push(bucket_key.0.clone());
+            }
+            wait = wait.max(bucket.wait());
+        }
+
+        let metrics = Metrics::global();
+        if wait > 0.0 {
+            Metrics::incr(&metrics.rate_limited, 1);
+            let mut mute = false;
+            for key in &empty {
+                let offender = st.offenders.entry(key.clone()).or_default();
+                offender.strikes += 1;
+                if self.mute_after > 0 && offender.strikes >= self.mute_after {
+                    mute = true;
+                }
+            }
+            if mute {
+                Metrics::incr(&metrics.auto_mutes, 1);
+                for key in &empty {
+                    let offender = st.offenders.entry(key.clone()).or_default();
+                    offender.strikes = 0;
+                    offender.muted_until = Some(now + self.mute_for);
+                }
+                return Err(ChatError::Muted(self.mute_for.as_secs()));
+            }
+            return Err(ChatError::RateLimited(ceil_secs(Duration::from_secs_f64(wait))));
+        }
+
+        for (bucket_key, _) in &charged {
+            if let Some(bucket) = st.buckets.get_mut(bucket_key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        for key in keys {
+            if let Some(offender) = st.offenders.get_mut(key) {
+                offender.strikes = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    /// Forget full buckets and offenders with nothing pending.
+    fn prune(&mut self, now: Instant) {
+        self.buckets.retain(|_, b| {
+            b.refill(now);
+            b.tokens < b.limit.burst as f64
+        });
+        self.offenders
+            .retain(|_, o| o.strikes > 0 || o.muted_until.is_some_and(|t| t > now));
+    }
+}
+
+/// Whole seconds, rounded up, at least 1.
+fn ceil_secs(d: Duration) -> u64 {
+    (d.as_secs_f64().ceil() as u64).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_sec: f64, burst: u32, mute_after: u32) -> RateLimiter {
+        RateLimiter::from_config(&Config {
+            rate_limit: Some(RateLimit { per_sec, burst }),
+            mute_after,
+            mute_secs: 30,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let rl = limiter(1.0, 2, 0);
+        let keys = [Key::Conn("c1".into())];
+        let t0 = Instant::now();
+        rl.check_at(&keys, Some("r"), t0).unwrap();
+        rl.check_at(&keys, Some("r"), t0).unwrap();
+        assert!(matches!(rl.check_at(&keys, Some("r"), t0), Err(ChatError::RateLimited(1))));
+        rl.check_at(&keys, Some("r"), t0 + Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn shared_ip_bucket_limits_parallel_connections() {
+        let mut rl = limiter(1.0, 1, 0);
+        rl.ip = Some(RateLimit { per_sec: 1.0, burst: 2 });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let t0 = Instant::now();
+        // the IP allows more than one connection's worth, in any room
+        rl.check_at(&[Key::Conn("a".into()), Key::Ip(ip)], Some("r"), t0).unwrap();
+        rl.check_at(&[Key::Conn("b".into()), Key::Ip(ip)], Some("s"), t0).unwrap();
+        assert!(rl.check_at(&[Key::Conn("c".into()), Key::Ip(ip)], Some("r"), t0).is_err());
+        // a rejection does not consume the other buckets
+        rl.check_at(&[Key::Conn("c".into())], Some("r"), t0).unwrap();
+    }
+
+    #[test]
+    fn flooding_connection_does_not_mute_its_ip() {
+        let rl = limiter(1.0, 1, 2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let a = [Key::Conn("a".into()), Key::Ip(ip)];
+        let t0 = Instant::now();
+        rl.check_at(&a, Some("r"), t0).unwrap();
+        assert!(matches!(rl.check_at(&a, Some("r"), t0), Err(ChatError::RateLimited(_))));
+        assert!(matches!(rl.check_at(&a, Some("r"), t0), Err(ChatError::Muted(30))));
+        rl.check_at(&[Key::Conn("b".into()), Key::Ip(ip)], Some("r"), t0).unwrap();
+    }
+
+    #[test]
+    fn room_override() {
+        let mut rl = limiter(1.0, 1, 0);
+        rl.rooms.insert("bots".into(), None);
+        let keys = [Key::Conn("c".into())];
+        let t0 = Instant::now();
+        for _ in 0..10 {
+            rl.check_at(&keys, Some("bots"), t0).unwrap();
+        }
+        rl.check_at(&keys, Some("r"), t0).unwrap();
+        assert!(rl.check_at(&keys, Some("r"), t0).is_err());
+    }
+
+    #[test]
+    fn repeat_offender_is_muted() {
+        let rl = limiter(1.0, 1, 3);
+        let keys = [Key::User("spam".into())];
+        let t0 = Instant::now();
+        rl.check_at(&keys, Some("r"), t0).unwrap();
+        assert!(matches!(rl.check_at(&keys, Some("r"), t0), Err(ChatError::RateLimited(_))));
+        assert!(matches!(rl.check_at(&keys, Some("r"), t0), Err(ChatError::RateLimited(_))));
+        assert!(matches!(rl.check_at(&keys, Some("r"), t0), Err(ChatError::Muted(30))));
+        // bucket has refilled but the mute still holds
+        let later = t0 + Duration::from_secs(10);
+        assert!(matches!(rl.check_at(&keys, Some("r"), later), Err(ChatError::Muted(20))));
+        rl.check_at(&keys, Some("r"), t0 + Duration::from_secs(31)).unwrap();
+    }
+}
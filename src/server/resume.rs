@@ -0,0 +1,168 @@
+//! Sessions that outlive their connection.
+//!
+//! A v2 session that joined a room holds a token, announced with
+//! `SessionResumable`. When its socket drops, the listener parks the session
+//! in [`Parking`] instead of tearing it down: it stays a member of its rooms
+//! and their frames queue up behind the dead socket. `Resume` with the token
+//! within the grace window hands the session to the new connection;
+//! otherwise it is released as if it had left. A new connection of the same
+//! authenticated user that joins instead of resuming releases it at once,
+//! so its names are free again.
+//!
+//! Frames already written to the old socket may never have arrived, so the
+//! writer keeps a [`Journal`] of the last ones and a resuming client says
+//! which message it saw last.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::ids::random_b64;
+use crate::protocol::ServerEvent;
+
+/// Frames a connection's writer remembers for a resume.
+pub const JOURNAL_FRAMES: usize = 256;
+/// Random bytes in a resume token.
+const TOKEN_BYTES: usize = 24;
+
+/// A fresh, unguessable resume token.
+pub fn new_token() -> String {
+    random_b64(TOKEN_BYTES)
+}
+
+/// The last [`JOURNAL_FRAMES`] text frames written to a socket, oldest first.
+#[derive(Debug, Default)]
+pub struct Journal {
+    frames: VecDeque<String>,
+}
+
+impl Journal {
+    pub fn record(&mut self, frame: &str) {
+        if self.frames.len() >= JOURNAL_FRAMES {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame.to_string());
+    }
+
+    /// Frames written after the one carrying message `last_seen`; all of
+    /// them if it is `None` or no longer remembered.
+    pub fn after(self, last_seen: Option<&str>) -> Vec<String> {
+        let start = last_seen
+            .and_then(|id| self.frames.iter().rposition(|frame| carries(frame, id)))
+            .map_or(0, |i| i + 1);
+        self.frames.into_iter().skip(start).collect()
+    }
+}
+
+/// Whether `frame` is message `id` itself, not just a reply to it.
+fn carries(frame: &str, id: &str) -> bool {
+    frame.contains(id)
+        && matches!(
+            serde_json::from_str(frame),
+            Ok(ServerEvent::NewMessage { id: msg, .. } | ServerEvent::DirectMessage { id: msg, .. }) if msg == id
+        )
+}
+
+/// Dropped sessions waiting to be resumed, by token, with the user they
+/// were authenticated as.
+pub struct Parking<T> {
+    grace: Duration,
+    parked: Mutex<HashMap<String, (Option<String>, T)>>,
+}
+
+impl<T: Send + 'static> Parking<T> {
+    pub fn new(grace: Duration) -> Arc<Self> {
+        Arc::new(Self { grace, parked: Mutex::new(HashMap::new()) })
+    }
+
+    /// How long a parked session waits; zero when resuming is off.
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Hold `value` of user `owner` under `token` for the grace window, then
+    /// pass it to `expire` unless it was taken by then.
+    pub fn park<F, Fut>(self: &Arc<Self>, token: String, owner: Option<String>, value: T, expire: F)
+    where
+        F: FnOnce(T) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        self.parked.lock().expect("parking lock").insert(token.clone(), (owner, value));
+        let parking = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(parking.grace).await;
+            let expired = parking.take(&token);
+            if let Some(value) = expired {
+                expire(value).await;
+            }
+        });
+    }
+
+    pub fn take(&self, token: &str) -> Option<T> {
+        self.parked.lock().expect("parking lock").remove(token).map(|(_, value)| value)
+    }
+
+    /// Everything parked for user `owner`.
+    pub fn take_owned(&self, owner: &str) -> Vec<T> {
+        let mut parked = self.parked.lock().expect("parking lock");
+        let tokens: Vec<String> =
+            parked.iter().filter(|(_, (o, _))| o.as_deref() == Some(owner)).map(|(token, _)| token.clone()).collect();
+        tokens.into_iter().filter_map(|token| parked.remove(&token)).map(|(_, value)| value).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, reply_to: Option<&str>) -> String {
+        let mut msg = ServerEvent::message("r", "bob", "hi", reply_to);
+        // stamped as the room does; a reply carries its thread too
+        if let ServerEvent::NewMessage { id: stamped, thread_id, .. } = &mut msg {
+            *stamped = id.into();
+            *thread_id = reply_to.map(str::to_string);
+        }
+        serde_json::to_string(&msg).unwrap()
+    }
+
+    #[test]
+    fn journal_replays_after_the_last_seen_message() {
+        let mut journal = Journal::default();
+        let joined = r#"{"UserJoined":{"room":"r","name":"carol"}}"#;
+        for frame in [message("m1", None), joined.to_string(), message("m2", Some("m1"))] {
+            journal.record(&frame);
+        }
+        let frames: Vec<String> = journal.frames.iter().cloned().collect();
+        // a reply mentioning m1 does not count as m1
+        assert_eq!(Journal { frames: frames.clone().into() }.after(Some("m1")), frames[1..].to_vec());
+        assert!(Journal { frames: frames.clone().into() }.after(Some("m2")).is_empty());
+        assert_eq!(Journal { frames: frames.clone().into() }.after(Some("gone")), frames);
+        assert_eq!(Journal { frames: frames.clone().into() }.after(None), frames);
+
+        let mut full = Journal::default();
+        for i in 0..JOURNAL_FRAMES + 1 {
+            full.record(&i.to_string());
+        }
+        assert_eq!(full.after(None).first().map(String::as_str), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn parked_values_expire_unless_taken() {
+        let parking = Parking::new(Duration::from_millis(20));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for (token, owner) in [("a", None), ("b", None), ("c", Some("carol"))] {
+            let tx = tx.clone();
+            parking.park(token.into(), owner.map(str::to_string), token.to_string(), move |v| async move {
+                let _ = tx.send(v);
+            });
+        }
+        assert_eq!(parking.take("a").as_deref(), Some("a"));
+        assert!(parking.take("a").is_none());
+        assert_eq!(parking.take_owned("carol"), vec!["c".to_string()]);
+        assert!(parking.take_owned("carol").is_empty());
+        assert_eq!(rx.recv().await.as_deref(), Some("b"));
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+}
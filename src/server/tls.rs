@@ -0,0 +1,216 @@
+//! TLS termination for the WebSocket listener.
+//!
+//! [`TlsReloader`] owns the current acceptor and rebuilds it from the PEM
+//! files on demand, so certificates can be rotated with a SIGHUP while
+//! connections are being served. When `TLS_CLIENT_CA` is set, clients must
+//! present a certificate signed by it and its common name becomes the
+//! connection's identity.
+
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ServerConfig, ServerConnection};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::Config;
+use crate::tls::{load_certs, load_key, load_roots};
+
+/// Reloadable TLS acceptor built from the `TLS_*` settings.
+pub struct TlsReloader {
+    cert: String,
+    key: String,
+    client_ca: Option<String>,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl TlsReloader {
+    /// `None` when TLS is not configured.
+    pub fn from_config(cfg: &Config) -> io::Result<Option<Arc<Self>>> {
+        let (cert, key) = match (&cfg.tls_cert, &cfg.tls_key) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            (None, None) if cfg.tls_client_ca.is_none() => return Ok(None),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS_CERT and TLS_KEY must be set together (TLS_CLIENT_CA needs both)",
+                ));
+            }
+        };
+        let client_ca = cfg.tls_client_ca.clone();
+        let config = server_config(&cert, &key, client_ca.as_deref())?;
+        Ok(Some(Arc::new(Self { cert, key, client_ca, acceptor: RwLock::new(config.into()) })))
+    }
+
+    /// Acceptor for the next handshake.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().expect("tls lock").clone()
+    }
+
+    /// Whether every client is authenticated by its certificate.
+    pub fn requires_client_cert(&self) -> bool {
+        self.client_ca.is_some()
+    }
+
+    /// Re-read the PEM files; on error the current acceptor stays in place.
+    pub fn reload(&self) -> io::Result<()> {
+        let config = server_config(&self.cert, &self.key, self.client_ca.as_deref())?;
+        *self.acceptor.write().expect("tls lock") = config.into();
+        Ok(())
+    }
+}
+
+fn server_config(cert: &str, key: &str, client_ca: Option<&str>) -> io::Result<Arc<ServerConfig>> {
+    let builder = match client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(path)?))
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// Reload certificates whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_sighup(tls: Arc<TlsReloader>) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match tls.reload() {
+                Ok(()) => tracing::info!("TLS certificates reloaded"),
+                Err(e) => tracing::error!(error=%e, "TLS reload failed, keeping old certificates"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_tls: Arc<TlsReloader>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Common name of the client's verified certificate, if it sent one.
+pub fn peer_name(conn: &ServerConnection) -> Option<String> {
+    let der = conn.peer_certificates()?.first()?;
+    let (_, cert) = X509Certificate::from_der(der.as_ref()).ok()?;
+    let cn = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(cn.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::TlsConnector;
+
+    use crate::tls::client_config;
+
+    /// CA, server and client ("alice") certificates written as PEM files.
+    struct Pki {
+        dir: PathBuf,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("webchathub-tls-{name}-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca = CertificateParams::new(vec![]).unwrap();
+            ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            ca.distinguished_name.push(DnType::CommonName, "test ca");
+            let ca = ca.self_signed(&ca_key).unwrap();
+
+            let issue = |sans: Vec<String>, cn: &str, file: &str| {
+                let key = KeyPair::generate().unwrap();
+                let mut params = CertificateParams::new(sans).unwrap();
+                params.distinguished_name.push(DnType::CommonName, cn);
+                let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+                fs::write(dir.join(format!("{file}.pem")), cert.pem()).unwrap();
+                fs::write(dir.join(format!("{file}.key")), key.serialize_pem()).unwrap();
+            };
+            issue(vec!["localhost".into()], "localhost", "server");
+            issue(vec![], "alice", "client");
+            fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            Self { dir }
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.dir.join(file).to_string_lossy().into_owned()
+        }
+
+        fn config(&self, mtls: bool) -> Config {
+            Config {
+                tls_cert: Some(self.path("server.pem")),
+                tls_key: Some(self.path("server.key")),
+                tls_client_ca: mtls.then(|| self.path("ca.pem")),
+                ..Config::default()
+            }
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn cert_and_key_go_together() {
+        assert!(TlsReloader::from_config(&Config::default()).unwrap().is_none());
+        let half = Config { tls_cert: Some("cert.pem".into()), ..Config::default() };
+        assert!(TlsReloader::from_config(&half).is_err());
+    }
+
+    #[tokio::test]
+    async fn mtls_handshake_yields_client_name() {
+        let pki = Pki::new("mtls");
+        let tls = TlsReloader::from_config(&pki.config(true)).unwrap().unwrap();
+        assert!(tls.requires_client_cert());
+
+        let client = client_config(
+            Some(&pki.path("ca.pem")),
+            Some((&pki.path("client.pem"), &pki.path("client.key"))),
+        )
+        .unwrap();
+        let (a, b) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move {
+            let mut s = tls.acceptor().accept(b).await.unwrap();
+            let name = peer_name(s.get_ref().1);
+            s.write_all(b"ok").await.unwrap();
+            s.flush().await.unwrap();
+            name
+        });
+        let domain = ServerName::try_from("localhost").unwrap();
+        let mut c = TlsConnector::from(client).connect(domain, a).await.unwrap();
+        let mut buf = [0u8; 2];
+        c.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ok");
+        assert_eq!(server.await.unwrap().as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn reload_keeps_old_acceptor_on_error() {
+        let pki = Pki::new("reload");
+        let tls = TlsReloader::from_config(&pki.config(false)).unwrap().unwrap();
+        tls.reload().unwrap();
+        fs::write(pki.path("server.pem"), "garbage").unwrap();
+        assert!(tls.reload().is_err());
+        let _still_usable = tls.acceptor();
+    }
+}
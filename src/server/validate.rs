@@ -0,0 +1,276 @@
+//! Request validation in front of the hub.
+//!
+//! Room names, nicknames and message text are normalized to Unicode NFC
+//! and checked against the configured limits before anything reaches a
+//! room, so every room sees one spelling of a name and never stores an
+//! empty or oversized message.
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::Config;
+use crate::error::ChatError;
+use crate::protocol::ClientRequest;
+
+/// Longest reaction, in characters; room for ZWJ emoji sequences.
+const MAX_EMOJI_CHARS: usize = 16;
+/// Longest presence status text, in characters.
+const MAX_STATUS_CHARS: usize = 128;
+/// Longest timed ban, mute or invite, in seconds; anything longer should
+/// be permanent.
+const MAX_DURATION_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// Limits and character sets applied to every client request.
+pub struct Validator {
+    max_message_bytes: usize,
+    max_room_len: usize,
+    max_name_len: usize,
+    max_history_page: usize,
+    /// allowed in room names besides letters and digits
+    room_chars: String,
+    /// allowed in nicknames besides letters and digits
+    name_chars: String,
+}
+
+impl Validator {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max_message_bytes: cfg.max_message_bytes,
+            max_room_len: cfg.max_room_len,
+            max_name_len: cfg.max_name_len,
+            max_history_page: cfg.max_history_page,
+            room_chars: cfg.room_name_chars.clone(),
+            name_chars: cfg.nick_chars.clone(),
+        }
+    }
+
+    /// Normalize the user-supplied strings of `req`, or say why it is refused.
+    pub fn request(&self, req: ClientRequest) -> Result<ClientRequest, ChatError> {
+        Ok(match req {
+            ClientRequest::Join { room, name, replay, password, invite } => ClientRequest::Join {
+                room: self.room(&room)?,
+                name: self.name(&name)?,
+                replay,
+                password,
+                invite,
+            },
+            ClientRequest::Leave { room } => ClientRequest::Leave { room: self.room(&room)? },
+            ClientRequest::Typing { room, state } => ClientRequest::Typing { room: self.room(&room)?, state },
+            ClientRequest::MarkRead { room, up_to_id, receipt } => {
+                ClientRequest::MarkRead { room: self.room(&room)?, up_to_id, receipt }
+            }
+            ClientRequest::Rename { room, name } => {
+                ClientRequest::Rename { room: self.room(&room)?, name: self.name(&name)? }
+            }
+            ClientRequest::Message { room, text, reply_to } => {
+                ClientRequest::Message { room: self.room(&room)?, text: self.text(&text)?, reply_to }
+            }
+            ClientRequest::Members { room } => ClientRequest::Members { room: self.room(&room)? },
+            ClientRequest::History { room, before, limit, thread } => {
+                ClientRequest::History { room: self.room(&room)?, before, limit: self.page(limit), thread }
+            }
+            ClientRequest::EditMessage { room, id, text } => {
+                ClientRequest::EditMessage { room: self.room(&room)?, id, text: self.text(&text)? }
+            }
+            ClientRequest::DeleteMessage { room, id } => ClientRequest::DeleteMessage { room: self.room(&room)?, id },
+            ClientRequest::React { room, message_id, emoji } => {
+                ClientRequest::React { room: self.room(&room)?, message_id, emoji: emoji_text(&emoji)? }
+            }
+            ClientRequest::Unreact { room, message_id, emoji } => {
+                ClientRequest::Unreact { room: self.room(&room)?, message_id, emoji: emoji_text(&emoji)? }
+            }
+            ClientRequest::Kick { room, name, reason } => ClientRequest::Kick {
+                room: self.room(&room)?,
+                name: self.name(&name)?,
+                reason: reason.map(|r| self.text(&r)).transpose()?,
+            },
+            ClientRequest::Ban { room, name, duration, reason } => ClientRequest::Ban {
+                room: self.room(&room)?,
+                name: self.name(&name)?,
+                duration: duration.map(seconds).transpose()?,
+                reason: reason.map(|r| self.text(&r)).transpose()?,
+            },
+            ClientRequest::Unban { room, name } => {
+                ClientRequest::Unban { room: self.room(&room)?, name: self.name(&name)? }
+            }
+            ClientRequest::Mute { room, name, duration } => {
+                let duration = duration.map(seconds).transpose()?;
+                ClientRequest::Mute { room: self.room(&room)?, name: self.name(&name)?, duration }
+            }
+            ClientRequest::Unmute { room, name } => {
+                ClientRequest::Unmute { room: self.room(&room)?, name: self.name(&name)? }
+            }
+            ClientRequest::SetRole { room, name, role } => {
+                ClientRequest::SetRole { room: self.room(&room)?, name: self.name(&name)?, role }
+            }
+            ClientRequest::SetVisibility { room, visibility } => {
+                ClientRequest::SetVisibility { room: self.room(&room)?, visibility }
+            }
+            ClientRequest::SetPassword { room, password } => {
+                if password.as_deref().is_some_and(str::is_empty) {
+                    return Err(ChatError::Invalid("password is empty".into()));
+                }
+                ClientRequest::SetPassword { room: self.room(&room)?, password }
+            }
+            ClientRequest::CreateInvite { room, ttl } => {
+                ClientRequest::CreateInvite { room: self.room(&room)?, ttl: ttl.map(seconds).transpose()? }
+            }
+            ClientRequest::RevokeInvite { room, token } => {
+                ClientRequest::RevokeInvite { room: self.room(&room)?, token }
+            }
+            ClientRequest::DirectMessage { to, text } => {
+                ClientRequest::DirectMessage { to: self.name(&to)?, text: self.text(&text)? }
+            }
+            ClientRequest::DirectHistory { with, before, limit } => {
+                ClientRequest::DirectHistory { with: self.name(&with)?, before, limit: self.page(limit) }
+            }
+            ClientRequest::SetStatus { away, status } => {
+                ClientRequest::SetStatus { away, status: status.map(|s| self.status(&s)).transpose()? }
+            }
+            ClientRequest::WhoIs { name } => ClientRequest::WhoIs { name: self.name(&name)? },
+            other @ (ClientRequest::Hello { .. }
+            | ClientRequest::Auth(_)
+            | ClientRequest::Resume { .. }
+            | ClientRequest::RoomList) => other,
+        })
+    }
+
+    fn room(&self, room: &str) -> Result<String, ChatError> {
+        identifier("room name", room, self.max_room_len, &self.room_chars)
+    }
+
+    fn name(&self, name: &str) -> Result<String, ChatError> {
+        identifier("name", name, self.max_name_len, &self.name_chars)
+    }
+
+    /// A history page size of at most `max_history_page`.
+    fn page(&self, limit: usize) -> usize {
+        limit.min(self.max_history_page)
+    }
+
+    /// A presence status: one short line of chat text.
+    fn status(&self, status: &str) -> Result<String, ChatError> {
+        let status = self.text(status.trim())?;
+        let len = status.chars().count();
+        if len > MAX_STATUS_CHARS || status.contains('\n') {
+            return Err(ChatError::Invalid(format!("status must be one line of at most {MAX_STATUS_CHARS} characters")));
+        }
+        Ok(status)
+    }
+
+    /// Chat text: any printable text plus newlines and tabs.
+    fn text(&self, text: &str) -> Result<String, ChatError> {
+        let text: String = text.nfc().collect();
+        if text.trim().is_empty() {
+            return Err(ChatError::Invalid("message is empty".into()));
+        }
+        if text.len() > self.max_message_bytes {
+            return Err(ChatError::Invalid(format!(
+                "message is {} bytes, limit is {}",
+                text.len(),
+                self.max_message_bytes
+            )));
+        }
+        if let Some(c) = text.chars().find(|c| c.is_control() && *c != '\n' && *c != '\t') {
+            return Err(ChatError::Invalid(format!("message contains control character {:?}", c)));
+        }
+        Ok(text)
+    }
+}
+
+/// NFC-normalized, trimmed `value` of at most `max_len` characters, each a
+/// letter, a digit or one of `extra`.
+fn identifier(what: &str, value: &str, max_len: usize, extra: &str) -> Result<String, ChatError> {
+    let value: String = value.trim().nfc().collect();
+    if value.is_empty() {
+        return Err(ChatError::Invalid(format!("{what} is empty")));
+    }
+    let len = value.chars().count();
+    if len > max_len {
+        return Err(ChatError::Invalid(format!("{what} is {len} characters, limit is {max_len}")));
+    }
+    if let Some(c) = value.chars().find(|c| !c.is_alphanumeric() && !extra.contains(*c)) {
+        return Err(ChatError::Invalid(format!("{what} may not contain {:?}", c)));
+    }
+    Ok(value)
+}
+
+/// A duration of at most [`MAX_DURATION_SECS`].
+fn seconds(secs: u64) -> Result<u64, ChatError> {
+    if secs > MAX_DURATION_SECS {
+        return Err(ChatError::Invalid(format!("duration is {secs} s, limit is {MAX_DURATION_SECS}")));
+    }
+    Ok(secs)
+}
+
+/// A reaction: a short run of characters with no spaces or controls.
+fn emoji_text(emoji: &str) -> Result<String, ChatError> {
+    let emoji: String = emoji.trim().nfc().collect();
+    let len = emoji.chars().count();
+    if len == 0 || len > MAX_EMOJI_CHARS || emoji.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(ChatError::Invalid(format!("reaction must be 1 to {MAX_EMOJI_CHARS} characters without spaces")));
+    }
+    Ok(emoji)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator() -> Validator {
+        Validator::from_config(&Config { max_message_bytes: 8, max_name_len: 5, ..Config::default() })
+    }
+
+    fn join(room: &str, name: &str) -> ClientRequest {
+        ClientRequest::Join { room: room.into(), name: name.into(), replay: None, password: None, invite: None }
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        // "e" + combining acute accent composes to "é"
+        let req = validator().request(join(" caf\u{65}\u{301} ", "zoë")).unwrap();
+        assert_eq!(req, join("caf\u{e9}", "zoë"));
+    }
+
+    #[test]
+    fn bad_names_are_refused() {
+        let v = validator();
+        for (room, name) in [("", "bob"), ("rust", "   "), ("a/b", "bob"), ("rust", "bob\u{0}"), ("rust", "robert")] {
+            let err = v.request(join(room, name)).unwrap_err();
+            assert!(matches!(err, ChatError::Invalid(_)), "{room:?} {name:?}: {err}");
+        }
+        assert!(v.request(join("rust-lang.zh_cn", "b_o-b")).is_ok());
+    }
+
+    #[test]
+    fn message_limits() {
+        let v = validator();
+        let msg = |text: &str| ClientRequest::Message { room: "rust".into(), text: text.into(), reply_to: None };
+        assert!(v.request(msg("hi\n\tyo")).is_ok());
+        assert!(v.request(msg(" \n")).is_err());
+        assert!(v.request(msg("123456789")).is_err());
+        assert!(v.request(msg("\u{1b}[2J")).is_err());
+        // four two-byte characters fit exactly
+        assert!(v.request(msg("éééé")).is_ok());
+    }
+
+    #[test]
+    fn durations_are_capped() {
+        let v = validator();
+        let mute = |duration| ClientRequest::Mute { room: "rust".into(), name: "bob".into(), duration };
+        assert!(v.request(mute(Some(MAX_DURATION_SECS))).is_ok());
+        assert!(v.request(mute(None)).is_ok());
+        assert!(matches!(v.request(mute(Some(u64::MAX))), Err(ChatError::Invalid(_))));
+        let invite = ClientRequest::CreateInvite { room: "rust".into(), ttl: Some(MAX_DURATION_SECS + 1) };
+        assert!(matches!(v.request(invite), Err(ChatError::Invalid(_))));
+    }
+
+    #[test]
+    fn history_pages_are_capped() {
+        let v = Validator::from_config(&Config { max_history_page: 50, ..Config::default() });
+        let page = |limit| ClientRequest::History { room: "rust".into(), before: None, limit, thread: None };
+        assert_eq!(v.request(page(20)).unwrap(), page(20));
+        assert_eq!(v.request(page(usize::MAX)).unwrap(), page(50));
+        let direct = ClientRequest::DirectHistory { with: "bob".into(), before: None, limit: 1000 };
+        assert!(matches!(v.request(direct), Ok(ClientRequest::DirectHistory { limit: 50, .. })));
+    }
+}
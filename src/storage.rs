@@ -0,0 +1,919 @@
+//! Room history storage.
+//!
+//! A [`Storage`] backend hands out one [`RoomLog`] per room. The room task
+//! appends every `NewMessage` frame to its log and reads the retained frames
+//! back for history replay. Two backends ship with the crate:
+//!
+//! * [`MemoryStorage`] — history lives only as long as the room task; a
+//!   room that expires and comes back keeps counting `seq` where it left
+//!   off, along with its ACL and read cursors.
+//! * [`SegmentStorage`] — append-only segment files on local disk, reloaded
+//!   when a room is reopened after a restart.
+//!
+//! Both apply the same [`Retention`] policy (count, age and bytes), and
+//! both keep each room's [`RoomAcl`] (roles and bans) and [`ReadCursors`]
+//! next to its history.
+//! Direct messages between two users get a log of their own, kept apart
+//! from every room.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::moderation::RoomAcl;
+use crate::receipts::ReadCursors;
+
+/// Which frames a room keeps; `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_count: usize,
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Retention {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max_count: cfg.history_limit,
+            max_age: (cfg.history_max_age_secs > 0)
+                .then(|| Duration::from_secs(cfg.history_max_age_secs)),
+            max_bytes: (cfg.history_max_bytes > 0).then_some(cfg.history_max_bytes),
+        }
+    }
+}
+
+/// Selects a slice of a room's retained history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryQuery {
+    /// only records with an index below this
+    pub before: Option<u64>,
+    /// only records stamped at or after this (ms since epoch)
+    pub since: Option<u64>,
+    /// at most this many, counted from the newest end
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    /// The newest `limit` records.
+    pub fn latest(limit: usize) -> Self {
+        Self { before: None, since: None, limit }
+    }
+}
+
+/// Per-room append-only history.
+///
+/// Every appended frame gets a monotonically increasing index which stays
+/// stable across restarts; it is the cursor used for paging.
+pub trait RoomLog: Send {
+    /// Append one encoded frame stamped with `ts` (ms since epoch).
+    fn append(&mut self, ts: u64, frame: Bytes) -> io::Result<()>;
+    /// Index the next appended frame will get; rooms use it as message `seq`.
+    fn next_index(&self) -> u64;
+    /// Retained `(index, frame)` pairs matching `q`, oldest first.
+    fn query(&mut self, q: &HistoryQuery) -> Vec<(u64, Bytes)>;
+    /// Overwrite the record at `index`, keeping its timestamp; used for
+    /// edited and deleted messages. `false` if it is no longer retained.
+    fn replace(&mut self, index: u64, frame: Bytes) -> io::Result<bool>;
+    /// Push buffered writes down to durable storage.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Factory for room logs, shared by the hub and every room task.
+pub trait Storage: Send + Sync {
+    fn open_room(&self, room: &str) -> io::Result<Box<dyn RoomLog>>;
+    /// Direct messages between users `a` and `b`, in either order.
+    fn open_direct(&self, a: &str, b: &str) -> io::Result<Box<dyn RoomLog>>;
+    /// Saved roles and bans of `room`; empty if none were saved.
+    fn load_acl(&self, room: &str) -> io::Result<RoomAcl>;
+    fn save_acl(&self, room: &str, acl: &RoomAcl) -> io::Result<()>;
+    /// Saved read cursors of `room`'s members; empty if none were saved.
+    fn load_reads(&self, room: &str) -> io::Result<ReadCursors>;
+    fn save_reads(&self, room: &str, reads: &ReadCursors) -> io::Result<()>;
+}
+
+/// Build the backend selected by `HISTORY_DIR` (empty → in memory).
+pub fn from_config(cfg: &Config) -> Arc<dyn Storage> {
+    let retention = Retention::from_config(cfg);
+    match &cfg.history_dir {
+        Some(dir) => Arc::new(SegmentStorage::new(dir, retention, cfg.segment_bytes)),
+        None => Arc::new(MemoryStorage::new(retention)),
+    }
+}
+
+fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+/// The two users of a conversation in a fixed order.
+fn pair<'a>(a: &'a str, b: &'a str) -> (&'a str, &'a str) {
+    if a <= b { (a, b) } else { (b, a) }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    index: u64,
+    ts: u64,
+    frame: Bytes,
+}
+
+/// In-memory sliding window enforcing a [`Retention`] policy.
+#[derive(Debug)]
+struct Window {
+    entries: VecDeque<Entry>,
+    bytes: u64,
+    retention: Retention,
+}
+
+impl Window {
+    fn new(retention: Retention) -> Self {
+        Self { entries: VecDeque::new(), bytes: 0, retention }
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.bytes += entry.frame.len() as u64;
+        self.entries.push_back(entry);
+        self.prune(now_ms());
+    }
+
+    fn prune(&mut self, now: u64) {
+        let r = self.retention;
+        while let Some(front) = self.entries.front() {
+            let too_many = self.entries.len() > r.max_count;
+            let too_big = r.max_bytes.is_some_and(|max| self.bytes > max);
+            let too_old = r
+                .max_age
+                .is_some_and(|age| now.saturating_sub(front.ts) > age.as_millis() as u64);
+            if !(too_many || too_big || too_old) {
+                break;
+            }
+            self.bytes -= front.frame.len() as u64;
+            self.entries.pop_front();
+        }
+    }
+
+    fn get(&self, index: u64) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.index == index)
+    }
+
+    /// Swap the frame of retained entry `index`; `false` if there is none.
+    fn replace(&mut self, index: u64, frame: Bytes) -> bool {
+        let Some(entry) = self.entries.iter_mut().find(|e| e.index == index) else {
+            return false;
+        };
+        self.bytes = self.bytes - entry.frame.len() as u64 + frame.len() as u64;
+        entry.frame = frame;
+        true
+    }
+
+    fn query(&mut self, q: &HistoryQuery) -> Vec<(u64, Bytes)> {
+        self.prune(now_ms());
+        let mut out: Vec<(u64, Bytes)> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|e| q.before.is_none_or(|b| e.index < b))
+            .take_while(|e| q.since.is_none_or(|s| e.ts >= s))
+            .take(q.limit)
+            .map(|e| (e.index, e.frame.clone()))
+            .collect();
+        out.reverse();
+        out
+    }
+}
+
+// ---------------------------------------------------------------------------
+// memory backend
+// ---------------------------------------------------------------------------
+
+/// Rooms whose state [`MemoryStorage`] keeps once their task is gone; the
+/// least recently saved are forgotten beyond this.
+const MAX_SAVED_ROOMS: usize = 10_000;
+
+/// Volatile backend; history is lost when the room task or the process
+/// ends. What a room saves — its `seq` counter, ACL and read cursors — is
+/// kept for up to [`MAX_SAVED_ROOMS`] rooms; opening or reading a room
+/// saves nothing. Conversations keep their history for the whole process.
+pub struct MemoryStorage {
+    retention: Retention,
+    rooms: Arc<Mutex<SavedRooms>>,
+    /// by the ordered pair of names
+    directs: Mutex<HashMap<(String, String), SharedHistory>>,
+}
+
+impl MemoryStorage {
+    pub fn new(retention: Retention) -> Self {
+        Self { retention, rooms: Arc::default(), directs: Mutex::new(HashMap::new()) }
+    }
+
+    fn saved(&self) -> std::sync::MutexGuard<'_, SavedRooms> {
+        self.rooms.lock().expect("saved rooms lock")
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open_room(&self, room: &str) -> io::Result<Box<dyn RoomLog>> {
+        let next = self.saved().rooms.get(room).map_or(0, |saved| saved.next);
+        let history = Arc::new(Mutex::new(MemoryHistory { window: Window::new(self.retention), next }));
+        Ok(Box::new(MemoryLog { history, room: Some((self.rooms.clone(), room.to_string())) }))
+    }
+
+    fn open_direct(&self, a: &str, b: &str) -> io::Result<Box<dyn RoomLog>> {
+        let (a, b) = pair(a, b);
+        let history = self
+            .directs
+            .lock()
+            .expect("memory log lock")
+            .entry((a.to_string(), b.to_string()))
+            .or_insert_with(|| Arc::new(Mutex::new(MemoryHistory { window: Window::new(self.retention), next: 0 })))
+            .clone();
+        Ok(Box::new(MemoryLog { history, room: None }))
+    }
+
+    fn load_acl(&self, room: &str) -> io::Result<RoomAcl> {
+        Ok(self.saved().rooms.get(room).map(|saved| saved.acl.clone()).unwrap_or_default())
+    }
+
+    fn save_acl(&self, room: &str, acl: &RoomAcl) -> io::Result<()> {
+        self.saved().update(room, |saved| saved.acl = acl.clone());
+        Ok(())
+    }
+
+    fn load_reads(&self, room: &str) -> io::Result<ReadCursors> {
+        Ok(self.saved().rooms.get(room).map(|saved| saved.reads.clone()).unwrap_or_default())
+    }
+
+    fn save_reads(&self, room: &str, reads: &ReadCursors) -> io::Result<()> {
+        self.saved().update(room, |saved| saved.reads = reads.clone());
+        Ok(())
+    }
+}
+
+/// What [`MemoryStorage`] keeps of a room between its tasks.
+#[derive(Debug, Default)]
+struct SavedRoom {
+    next: u64,
+    acl: RoomAcl,
+    reads: ReadCursors,
+    /// when this was last saved, for eviction
+    saved_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct SavedRooms {
+    rooms: HashMap<String, SavedRoom>,
+    /// counts saves
+    clock: u64,
+}
+
+impl SavedRooms {
+    /// Change what is kept of `room`, making room for it if needed.
+    fn update(&mut self, room: &str, change: impl FnOnce(&mut SavedRoom)) {
+        self.clock += 1;
+        if !self.rooms.contains_key(room) && self.rooms.len() >= MAX_SAVED_ROOMS {
+            let oldest = self.rooms.iter().min_by_key(|(_, saved)| saved.saved_at).map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.rooms.remove(&oldest);
+            }
+        }
+        let saved = self.rooms.entry(room.to_string()).or_default();
+        change(saved);
+        saved.saved_at = self.clock;
+    }
+}
+
+/// One room's or conversation's retained history.
+#[derive(Debug)]
+struct MemoryHistory {
+    window: Window,
+    next: u64,
+}
+
+type SharedHistory = Arc<Mutex<MemoryHistory>>;
+
+/// A handle on a [`MemoryHistory`]; every handle on a conversation sees
+/// the same one.
+struct MemoryLog {
+    history: SharedHistory,
+    /// where a room log records how far it has counted
+    room: Option<(Arc<Mutex<SavedRooms>>, String)>,
+}
+
+impl MemoryLog {
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryHistory> {
+        self.history.lock().expect("memory log lock")
+    }
+}
+
+impl RoomLog for MemoryLog {
+    fn append(&mut self, ts: u64, frame: Bytes) -> io::Result<()> {
+        let mut history = self.lock();
+        let index = history.next;
+        history.window.push(Entry { index, ts, frame });
+        history.next += 1;
+        if let Some((saved, room)) = &self.room {
+            saved.lock().expect("saved rooms lock").update(room, |saved| saved.next = index + 1);
+        }
+        Ok(())
+    }
+
+    fn next_index(&self) -> u64 {
+        self.lock().next
+    }
+
+    fn query(&mut self, q: &HistoryQuery) -> Vec<(u64, Bytes)> {
+        self.lock().window.query(q)
+    }
+
+    fn replace(&mut self, index: u64, frame: Bytes) -> io::Result<bool> {
+        Ok(self.lock().window.replace(index, frame))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// segment log backend
+// ---------------------------------------------------------------------------
+
+const SEGMENT_EXT: &str = "seg";
+/// Roles and bans, as JSON, in the room's directory.
+const ACL_FILE: &str = "acl.json";
+/// Read cursors, as JSON, in the room's directory.
+const READS_FILE: &str = "reads.json";
+/// Holds one directory per conversation; `@` never survives [`escape_room`],
+/// so no room can clash with it.
+const DIRECT_DIR: &str = "@direct";
+/// `ts: u64` + `len: u32`, both little endian.
+const RECORD_HEADER: usize = 12;
+/// Set in `len` to mark an amendment, whose header is followed by the
+/// `index: u64` (LE) of the record it replaces.
+const AMEND_FLAG: u32 = 1 << 31;
+/// The `index` of an amendment.
+const AMEND_HEADER: usize = 8;
+
+/// Durable backend: one directory per room holding numbered segment files.
+///
+/// Each record is `ts (u64 LE) | len (u32 LE) | frame`. An edit is appended
+/// as an amendment, `ts | len with AMEND_FLAG | index (u64 LE) | frame`,
+/// which replaces the frame of record `index` when the log is read back and
+/// takes no index of its own. A segment is named after the index of its
+/// first record and is rolled, by the next append only, once it exceeds
+/// `segment_bytes`; amendments go to the current segment, so no two
+/// segments share a name. Whole segments are deleted once every record in them
+/// has fallen out of the retention window. The room's ACL and read cursors
+/// live beside the segments in `acl.json` and `reads.json`. Conversations live under `@direct/`, named
+/// after both users.
+pub struct SegmentStorage {
+    root: PathBuf,
+    retention: Retention,
+    segment_bytes: u64,
+}
+
+impl SegmentStorage {
+    pub fn new(root: impl Into<PathBuf>, retention: Retention, segment_bytes: u64) -> Self {
+        Self { root: root.into(), retention, segment_bytes: segment_bytes.max(1) }
+    }
+}
+
+impl Storage for SegmentStorage {
+    fn open_room(&self, room: &str) -> io::Result<Box<dyn RoomLog>> {
+        let dir = self.root.join(escape_room(room));
+        let log = SegmentLog::open(dir, self.retention, self.segment_bytes)?;
+        Ok(Box::new(log))
+    }
+
+    fn open_direct(&self, a: &str, b: &str) -> io::Result<Box<dyn RoomLog>> {
+        let (a, b) = pair(a, b);
+        let dir = self.root.join(DIRECT_DIR).join(format!("{}+{}", escape_room(a), escape_room(b)));
+        let log = SegmentLog::open(dir, self.retention, self.segment_bytes)?;
+        Ok(Box::new(log))
+    }
+
+    fn load_acl(&self, room: &str) -> io::Result<RoomAcl> {
+        load_json(&self.root.join(escape_room(room)).join(ACL_FILE))
+    }
+
+    fn save_acl(&self, room: &str, acl: &RoomAcl) -> io::Result<()> {
+        save_json(&self.root.join(escape_room(room)), ACL_FILE, acl)
+    }
+
+    fn load_reads(&self, room: &str) -> io::Result<ReadCursors> {
+        load_json(&self.root.join(escape_room(room)).join(READS_FILE))
+    }
+
+    fn save_reads(&self, room: &str, reads: &ReadCursors) -> io::Result<()> {
+        save_json(&self.root.join(escape_room(room)), READS_FILE, reads)
+    }
+}
+
+/// `T::default()` if the file does not exist.
+fn load_json<T: DeserializeOwned + Default>(path: &Path) -> io::Result<T> {
+    match fs::read(path) {
+        Ok(buf) => serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Written to a temporary file and renamed, so a crash never leaves half a file.
+fn save_json<T: Serialize>(dir: &Path, name: &str, value: &T) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!("{name}.tmp"));
+    let mut f = File::create(&tmp)?;
+    f.write_all(&serde_json::to_vec(value)?)?;
+    f.sync_data()?;
+    fs::rename(tmp, dir.join(name))
+}
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    /// one past the last record index in this segment
+    end: u64,
+    size: u64,
+}
+
+struct SegmentLog {
+    dir: PathBuf,
+    window: Window,
+    segments: Vec<Segment>,
+    active: Option<File>,
+    segment_bytes: u64,
+    /// index the next appended record will get
+    next: u64,
+}
+
+impl SegmentLog {
+    fn open(dir: PathBuf, retention: Retention, segment_bytes: u64) -> io::Result<Self> {
+        let mut firsts: Vec<u64> = Vec::new();
+        // the directory is created lazily on first append
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+                continue;
+            }
+            if let Some(first) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| u64::from_str_radix(s, 16).ok())
+            {
+                firsts.push(first);
+            }
+        }
+        firsts.sort_unstable();
+
+        let mut window = Window::new(retention);
+        let mut segments = Vec::with_capacity(firsts.len());
+        let mut next = 0;
+        for first in firsts {
+            let path = segment_path(&dir, first);
+            let (records, size) = read_segment(&path)?;
+            next = next.max(first);
+            for record in records {
+                match record {
+                    Record::Append { ts, frame } => {
+                        window.push(Entry { index: next, ts, frame });
+                        next += 1;
+                    }
+                    Record::Amend { index, frame } => {
+                        window.replace(index, frame);
+                    }
+                }
+            }
+            segments.push(Segment { path, end: next, size });
+        }
+
+        let mut log = Self { dir, window, segments, active: None, segment_bytes, next };
+        log.compact()?;
+        Ok(log)
+    }
+
+    /// Delete segments whose records have all left the window.
+    fn compact(&mut self) -> io::Result<()> {
+        let keep_from = self.window.entries.front().map_or(self.next, |e| e.index);
+        // never remove the segment we are currently appending to
+        while self.segments.len() > 1 && self.segments[0].end <= keep_from {
+            let seg = self.segments.remove(0);
+            fs::remove_file(&seg.path)?;
+        }
+        Ok(())
+    }
+
+    /// Write `rec` to the end of the log; only an `append` may start a new
+    /// segment, named after the index it takes.
+    fn write(&mut self, rec: &[u8], append: bool) -> io::Result<()> {
+        self.active_file(append)?.write_all(rec)?;
+        let seg = self.segments.last_mut().expect("active segment");
+        seg.size += rec.len() as u64;
+        Ok(())
+    }
+
+    fn active_file(&mut self, append: bool) -> io::Result<&mut File> {
+        let roll = match self.segments.last() {
+            Some(seg) => append && seg.size >= self.segment_bytes,
+            None => true,
+        };
+        if roll {
+            fs::create_dir_all(&self.dir)?;
+            let path = segment_path(&self.dir, self.next);
+            self.segments.push(Segment { path, end: self.next, size: 0 });
+            self.active = None;
+        }
+        if self.active.is_none() {
+            let path = &self.segments.last().expect("segment exists").path;
+            self.active = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        Ok(self.active.as_mut().expect("active segment"))
+    }
+}
+
+impl RoomLog for SegmentLog {
+    fn append(&mut self, ts: u64, frame: Bytes) -> io::Result<()> {
+        let index = self.next;
+        let mut rec = Vec::with_capacity(RECORD_HEADER + frame.len());
+        encode_record(&mut rec, ts, &frame);
+        self.write(&rec, true)?;
+
+        self.next += 1;
+        self.segments.last_mut().expect("active segment").end = self.next;
+        self.window.push(Entry { index, ts, frame });
+        self.compact()
+    }
+
+    fn next_index(&self) -> u64 {
+        self.next
+    }
+
+    fn query(&mut self, q: &HistoryQuery) -> Vec<(u64, Bytes)> {
+        self.window.query(q)
+    }
+
+    /// Appends an amendment record, like any other write; the retained
+    /// frame only changes once it is written.
+    fn replace(&mut self, index: u64, frame: Bytes) -> io::Result<bool> {
+        let Some(ts) = self.window.get(index).map(|e| e.ts) else {
+            return Ok(false);
+        };
+        let mut rec = Vec::with_capacity(RECORD_HEADER + AMEND_HEADER + frame.len());
+        encode_amendment(&mut rec, ts, index, &frame);
+        self.write(&rec, false)?;
+        Ok(self.window.replace(index, frame))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.active.as_mut() {
+            Some(f) => f.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn segment_path(dir: &Path, first: u64) -> PathBuf {
+    dir.join(format!("{first:016x}.{SEGMENT_EXT}"))
+}
+
+fn encode_record(buf: &mut Vec<u8>, ts: u64, frame: &[u8]) {
+    buf.extend_from_slice(&ts.to_le_bytes());
+    buf.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    buf.extend_from_slice(frame);
+}
+
+fn encode_amendment(buf: &mut Vec<u8>, ts: u64, index: u64, frame: &[u8]) {
+    buf.extend_from_slice(&ts.to_le_bytes());
+    buf.extend_from_slice(&(frame.len() as u32 | AMEND_FLAG).to_le_bytes());
+    buf.extend_from_slice(&index.to_le_bytes());
+    buf.extend_from_slice(frame);
+}
+
+/// One record of a segment file.
+enum Record {
+    /// a new frame, taking the next index
+    Append { ts: u64, frame: Bytes },
+    /// a new version of the frame at `index`
+    Amend { index: u64, frame: Bytes },
+}
+
+/// Read all complete records; a torn tail from a crash is truncated away.
+fn read_segment(path: &Path) -> io::Result<(Vec<Record>, u64)> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+
+    let mut records = Vec::new();
+    let mut pos = 0usize;
+    while buf.len() - pos >= RECORD_HEADER {
+        let ts = u64::from_le_bytes(buf[pos..pos + 8].try_into().expect("8 bytes"));
+        let len = u32::from_le_bytes(buf[pos + 8..pos + 12].try_into().expect("4 bytes"));
+        let mut start = pos + RECORD_HEADER;
+        let amends = if len & AMEND_FLAG != 0 {
+            if buf.len() - start < AMEND_HEADER {
+                break;
+            }
+            start += AMEND_HEADER;
+            Some(u64::from_le_bytes(buf[start - AMEND_HEADER..start].try_into().expect("8 bytes")))
+        } else {
+            None
+        };
+        let len = (len & !AMEND_FLAG) as usize;
+        if buf.len() - start < len {
+            break;
+        }
+        let frame = Bytes::copy_from_slice(&buf[start..start + len]);
+        records.push(match amends {
+            Some(index) => Record::Amend { index, frame },
+            None => Record::Append { ts, frame },
+        });
+        pos = start + len;
+    }
+
+    if pos < buf.len() {
+        tracing::warn!(path=%path.display(), "truncating torn segment tail");
+        OpenOptions::new().write(true).open(path)?.set_len(pos as u64)?;
+    }
+    Ok((records, pos as u64))
+}
+
+/// Map a room name onto a safe directory name.
+fn escape_room(room: &str) -> String {
+    let mut out = String::with_capacity(room.len());
+    for b in room.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retention(max_count: usize) -> Retention {
+        Retention { max_count, max_age: None, max_bytes: None }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("webchathub-{name}-{}-{}", std::process::id(), now_ms()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn frame(s: &str) -> Bytes {
+        Bytes::copy_from_slice(s.as_bytes())
+    }
+
+    fn all(log: &mut Box<dyn RoomLog>) -> Vec<Bytes> {
+        log.query(&HistoryQuery::latest(usize::MAX))
+            .into_iter()
+            .map(|(_, f)| f)
+            .collect()
+    }
+
+    #[test]
+    fn memory_count_limit() {
+        let mut log = MemoryStorage::new(retention(2)).open_room("r").unwrap();
+        for s in ["a", "b", "c"] {
+            log.append(now_ms(), frame(s)).unwrap();
+        }
+        assert_eq!(all(&mut log), vec![frame("b"), frame("c")]);
+    }
+
+    #[test]
+    fn memory_rooms_keep_counting_when_reopened() {
+        let storage = MemoryStorage::new(retention(10));
+        let mut log = storage.open_room("r").unwrap();
+        log.append(now_ms(), frame("a")).unwrap();
+        drop(log);
+        let mut log = storage.open_room("r").unwrap();
+        assert_eq!(log.next_index(), 1);
+        assert!(all(&mut log).is_empty());
+        // rooms only opened or read leave nothing behind
+        assert_eq!(storage.open_room("s").unwrap().query(&HistoryQuery::latest(10)), Vec::new());
+        assert_eq!(storage.load_acl("t").unwrap(), RoomAcl::default());
+        assert_eq!(storage.saved().rooms.len(), 1);
+        // the least recently saved room makes way
+        for i in 0..MAX_SAVED_ROOMS {
+            storage.save_reads(&format!("room{i}"), &ReadCursors::default()).unwrap();
+        }
+        assert_eq!(storage.saved().rooms.len(), MAX_SAVED_ROOMS);
+        assert_eq!(storage.open_room("r").unwrap().next_index(), 0);
+        // either spelling of a conversation is the same one
+        storage.open_direct("bob", "alice").unwrap().append(now_ms(), frame("hi")).unwrap();
+        assert_eq!(storage.open_direct("alice", "bob").unwrap().next_index(), 1);
+    }
+
+    #[test]
+    fn memory_age_and_bytes_limit() {
+        let r = Retention {
+            max_count: 100,
+            max_age: Some(Duration::from_secs(60)),
+            max_bytes: Some(4),
+        };
+        let mut log = MemoryStorage::new(r).open_room("r").unwrap();
+        log.append(now_ms() - 120_000, frame("old")).unwrap();
+        log.append(now_ms(), frame("ab")).unwrap();
+        log.append(now_ms(), frame("cd")).unwrap();
+        log.append(now_ms(), frame("e")).unwrap();
+        assert_eq!(all(&mut log), vec![frame("cd"), frame("e")]);
+    }
+
+    #[test]
+    fn query_pages_backwards() {
+        let mut log = MemoryStorage::new(retention(10)).open_room("r").unwrap();
+        for s in ["a", "b", "c", "d", "e"] {
+            log.append(now_ms(), frame(s)).unwrap();
+        }
+        let page = log.query(&HistoryQuery::latest(2));
+        assert_eq!(page, vec![(3, frame("d")), (4, frame("e"))]);
+
+        let page = log.query(&HistoryQuery { before: Some(3), since: None, limit: 2 });
+        assert_eq!(page, vec![(1, frame("b")), (2, frame("c"))]);
+
+        let page = log.query(&HistoryQuery { before: Some(1), since: None, limit: 2 });
+        assert_eq!(page, vec![(0, frame("a"))]);
+    }
+
+    #[test]
+    fn query_since_timestamp() {
+        let mut log = MemoryStorage::new(retention(10)).open_room("r").unwrap();
+        log.append(100, frame("old")).unwrap();
+        log.append(200, frame("new")).unwrap();
+        let q = HistoryQuery { before: None, since: Some(150), limit: 10 };
+        assert_eq!(log.query(&q), vec![(1, frame("new"))]);
+    }
+
+    #[test]
+    fn segment_survives_reopen() {
+        let dir = temp_dir("reopen");
+        let store = SegmentStorage::new(&dir, retention(10), 1024);
+        {
+            let mut log = store.open_room("rust/dev").unwrap();
+            log.append(now_ms(), frame("one")).unwrap();
+            log.append(now_ms(), frame("two")).unwrap();
+            log.flush().unwrap();
+        }
+        let mut log = store.open_room("rust/dev").unwrap();
+        assert_eq!(all(&mut log), vec![frame("one"), frame("two")]);
+        log.append(now_ms(), frame("three")).unwrap();
+        drop(log);
+
+        let mut log = store.open_room("rust/dev").unwrap();
+        assert_eq!(all(&mut log), vec![frame("one"), frame("two"), frame("three")]);
+        // indexes are stable across restarts
+        assert_eq!(log.query(&HistoryQuery::latest(1)), vec![(2, frame("three"))]);
+        assert_eq!(log.next_index(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segment_rolls_and_compacts() {
+        let dir = temp_dir("compact");
+        // every record is its own segment
+        let store = SegmentStorage::new(&dir, retention(2), 1);
+        let mut log = store.open_room("r").unwrap();
+        for s in ["a", "b", "c", "d"] {
+            log.append(now_ms(), frame(s)).unwrap();
+        }
+        let files = fs::read_dir(dir.join("r")).unwrap().count();
+        assert_eq!(files, 2);
+        drop(log);
+
+        let mut log = store.open_room("r").unwrap();
+        assert_eq!(all(&mut log), vec![frame("c"), frame("d")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segment_truncates_torn_tail() {
+        let dir = temp_dir("torn");
+        let store = SegmentStorage::new(&dir, retention(10), 1024);
+        {
+            let mut log = store.open_room("r").unwrap();
+            log.append(now_ms(), frame("ok")).unwrap();
+        }
+        let seg = segment_path(&dir.join("r"), 0);
+        OpenOptions::new().append(true).open(&seg).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+        let mut log = store.open_room("r").unwrap();
+        assert_eq!(all(&mut log), vec![frame("ok")]);
+        log.append(now_ms(), frame("next")).unwrap();
+        drop(log);
+
+        let mut log = store.open_room("r").unwrap();
+        assert_eq!(all(&mut log), vec![frame("ok"), frame("next")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_survives_reopen() {
+        let dir = temp_dir("replace");
+        // two records per segment, so the edit lands in a closed one
+        let store = SegmentStorage::new(&dir, retention(10), 2 * (RECORD_HEADER as u64 + 3));
+        let mut log = store.open_room("r").unwrap();
+        for s in ["one", "two", "six"] {
+            log.append(now_ms(), frame(s)).unwrap();
+        }
+        assert!(log.replace(1, frame("2")).unwrap());
+        assert!(log.replace(2, frame("6")).unwrap());
+        assert!(!log.replace(7, frame("x")).unwrap());
+        log.append(now_ms(), frame("ten")).unwrap();
+        assert!(log.replace(1, frame("II")).unwrap());
+        let edited = vec![frame("one"), frame("II"), frame("6"), frame("ten")];
+        assert_eq!(all(&mut log), edited);
+        drop(log);
+
+        let mut log = store.open_room("r").unwrap();
+        assert_eq!(all(&mut log), edited);
+        assert_eq!(log.next_index(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repeated_edits_survive_reopen_and_compaction() {
+        let dir = temp_dir("reedit");
+        // every record fills a segment
+        let store = SegmentStorage::new(&dir, retention(2), 1);
+        let mut log = store.open_room("r").unwrap();
+        log.append(now_ms(), frame("one")).unwrap();
+        for s in ["a", "b", "c"] {
+            assert!(log.replace(0, frame(s)).unwrap());
+        }
+        log.append(now_ms(), frame("two")).unwrap();
+        for s in ["d", "e"] {
+            assert!(log.replace(0, frame(s)).unwrap());
+            assert!(log.replace(1, frame(s)).unwrap());
+        }
+        drop(log);
+
+        let mut log = store.open_room("r").unwrap();
+        assert_eq!(all(&mut log), vec![frame("e"), frame("e")]);
+        // pushing both out of the window deletes their segments cleanly
+        for s in ["three", "four", "five"] {
+            log.append(now_ms(), frame(s)).unwrap();
+        }
+        assert!(log.replace(4, frame("5")).unwrap());
+        drop(log);
+        let mut log = store.open_room("r").unwrap();
+        assert_eq!(all(&mut log), vec![frame("four"), frame("5")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn acl_and_reads_survive_reopen() {
+        let dir = temp_dir("acl");
+        let store = SegmentStorage::new(&dir, retention(10), 1024);
+        assert_eq!(store.load_acl("r").unwrap(), RoomAcl::default());
+
+        let mut acl = RoomAcl::default();
+        acl.claim("alice");
+        acl.bans.insert("troll".into(), None);
+        store.save_acl("r", &acl).unwrap();
+        let mut log = store.open_room("r").unwrap();
+        log.append(now_ms(), frame("hi")).unwrap();
+        drop(log);
+
+        let mut reads = ReadCursors::default();
+        reads.start("alice", 1);
+        store.save_reads("r", &reads).unwrap();
+
+        let store = SegmentStorage::new(&dir, retention(10), 1024);
+        assert_eq!(store.load_acl("r").unwrap(), acl);
+        assert_eq!(store.load_reads("r").unwrap(), reads);
+        assert_eq!(all(&mut store.open_room("r").unwrap()), vec![frame("hi")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn direct_log_is_shared_by_both_users() {
+        let dir = temp_dir("direct");
+        let store = SegmentStorage::new(&dir, retention(10), 1024);
+        store.open_direct("bob", "alice").unwrap().append(now_ms(), frame("hi")).unwrap();
+        assert_eq!(all(&mut store.open_direct("alice", "bob").unwrap()), vec![frame("hi")]);
+        // a room of the same name stays empty
+        assert!(all(&mut store.open_room("alice+bob").unwrap()).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn escapes_room_names() {
+        assert_eq!(escape_room("rust-cn_1"), "rust-cn_1");
+        assert_eq!(escape_room("../x"), "%2E%2E%2Fx");
+    }
+}
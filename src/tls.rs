@@ -0,0 +1,57 @@
+//! PEM loading and TLS configuration shared by the server and the client.
+//!
+//! Both sides use rustls; the server builds its acceptor in
+//! [`crate::server::tls`], the client its connector via [`client_config`].
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Every certificate in a PEM file, in file order.
+pub fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut rd = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut rd).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{path}: no certificates found")));
+    }
+    Ok(certs)
+}
+
+/// The first private key (PKCS#8, PKCS#1 or SEC1) in a PEM file.
+pub fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut rd = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut rd)?.ok_or_else(|| invalid(format!("{path}: no private key found")))
+}
+
+/// A trust store holding every certificate of a PEM bundle.
+pub fn load_roots(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|e| invalid(format!("{path}: {e}")))?;
+    }
+    Ok(roots)
+}
+
+/// Client-side config: trusts `ca_file` (or the bundled web PKI roots when
+/// `None`) and presents `cert`/`key` when the server asks for one.
+pub fn client_config(ca_file: Option<&str>, identity: Option<(&str, &str)>) -> io::Result<Arc<ClientConfig>> {
+    let roots = match ca_file {
+        Some(path) => load_roots(path)?,
+        None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() },
+    };
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid(e.to_string()))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}